@@ -0,0 +1,120 @@
+use std::net::SocketAddr;
+
+#[cfg(feature = "graphql")]
+use axum::Extension;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    routing::get,
+    Json, Router,
+};
+
+#[cfg(feature = "graphql")]
+use super::graphql;
+use super::{
+    db::Db,
+    health::{Health, SharedHealth},
+    metrics,
+    refiner::{row_at, Refined, SharedRows},
+};
+
+#[derive(Clone)]
+pub struct AppState {
+    pub rows: SharedRows,
+    /// Rows of tomorrow once the tomorrow pass has refined them
+    pub tomorrow: SharedRows,
+    pub tz: chrono_tz::Tz,
+    pub health: SharedHealth,
+    pub db: Db,
+}
+
+pub fn router(state: AppState) -> Router {
+    let router = Router::new()
+        .route("/today", get(today))
+        .route("/now", get(now))
+        .route("/hour/:hour", get(hour))
+        .route("/metrics", get(render_metrics))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz));
+    #[cfg(feature = "graphql")]
+    let router = {
+        let schema = graphql::schema(state.rows.clone(), state.tomorrow.clone(), state.tz);
+        router
+            .route("/graphql", get(graphql::playground).post(graphql::handler))
+            .layer(Extension(schema))
+    };
+    router.with_state(state)
+}
+
+pub async fn serve(addr: SocketAddr, state: AppState) -> Result<(), String> {
+    tracing::info!("Serving HTTP API on {}", addr);
+    axum::Server::bind(&addr)
+        .serve(router(state).into_make_service())
+        .await
+        .map_err(|e| e.to_string())
+}
+
+async fn today(State(state): State<AppState>) -> Json<Vec<Refined>> {
+    Json(state.rows.read().await.clone())
+}
+
+async fn now(State(state): State<AppState>) -> Result<Json<Refined>, StatusCode> {
+    row_at(&state.rows.read().await, chrono::Utc::now())
+        .cloned()
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn hour(
+    Path(hour): Path<u32>,
+    State(state): State<AppState>,
+) -> Result<Json<Refined>, StatusCode> {
+    find_hour(hour, &state.rows).await
+}
+
+async fn render_metrics(State(state): State<AppState>) -> Result<String, StatusCode> {
+    if let Some(refined) = row_at(&state.rows.read().await, chrono::Utc::now()) {
+        metrics::CURRENT_PRICE.set(refined.pris_time);
+        metrics::CURRENT_RATIO.set(refined.pris_forhold_24);
+    }
+    metrics::encode().map_err(|e| {
+        tracing::error!("Failed to encode metrics: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+async fn healthz(State(state): State<AppState>) -> (StatusCode, Json<Health>) {
+    let health = state.health.read().await.clone();
+    let status = if health.is_healthy() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(health))
+}
+
+async fn readyz(State(state): State<AppState>) -> (StatusCode, String) {
+    match state.db.ping().await {
+        Ok((build, version)) => (
+            StatusCode::OK,
+            format!("InfluxDB {} {} reachable", build, version),
+        ),
+        Err(e) => {
+            tracing::warn!("InfluxDB unreachable: {}", e);
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                format!("InfluxDB unreachable: {}", e),
+            )
+        }
+    }
+}
+
+async fn find_hour(hour: u32, rows: &SharedRows) -> Result<Json<Refined>, StatusCode> {
+    rows.read()
+        .await
+        .iter()
+        .find(|refined| refined.hour == hour)
+        .cloned()
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
@@ -0,0 +1,234 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate, Utc};
+use chrono_tz::Tz;
+#[cfg(feature = "influx")]
+use influxdb::WriteQuery;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    config::{Area, ConsumptionConfig, SourceConfig},
+    error::RefinerError,
+    refiner::{PricePoint, Refined},
+    store::PriceStore,
+};
+
+/// Days of prices kept for every area, enough for today with yesterday and tomorrow around it
+const CACHED_DAYS: usize = 3;
+
+/// Serializes the read, change and write of the cache file between concurrent ticks
+static FILE_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+/// The prices of a day of an area as they were last read
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct Cached {
+    area: Option<String>,
+    date: NaiveDate,
+    prices: Vec<(DateTime<Utc>, f64)>,
+}
+
+fn load(path: &Path) -> Vec<Cached> {
+    match fs::read_to_string(path) {
+        Ok(json) => serde_json::from_str(&json).unwrap_or_else(|e| {
+            tracing::warn!("Ignoring price cache {}: {}", path.display(), e);
+            Vec::new()
+        }),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// Replaces the file at once, so a crash midway leaves the old cache
+fn save(path: &Path, cached: &[Cached]) -> Result<(), String> {
+    let json = serde_json::to_string(cached).map_err(|e| e.to_string())?;
+    let temporary = path.with_extension("tmp");
+    fs::write(&temporary, json).map_err(|e| e.to_string())?;
+    fs::rename(&temporary, path).map_err(|e| e.to_string())
+}
+
+/// Reads through another store and keeps the last days of prices it read in a file, which are
+/// read instead when the other store fails. Reading history or consumption fails over to none,
+/// so the day is refined from its own prices
+pub struct CachedStore<'a> {
+    store: &'a dyn PriceStore,
+    path: PathBuf,
+    stale: AtomicBool,
+}
+
+impl<'a> CachedStore<'a> {
+    pub fn new(store: &'a dyn PriceStore, path: impl Into<PathBuf>) -> CachedStore<'a> {
+        CachedStore {
+            store,
+            path: path.into(),
+            stale: AtomicBool::new(false),
+        }
+    }
+
+    fn remember(&self, date: NaiveDate, area: Option<&Area>, prices: &[PricePoint]) {
+        let area = area.map(|area| area.name.clone());
+        let _lock = FILE_LOCK
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut cached = load(&self.path);
+        cached.retain(|day| !(day.area == area && day.date == date));
+        cached.push(Cached {
+            area: area.clone(),
+            date,
+            prices: prices
+                .iter()
+                .map(|price| (price.start.with_timezone(&Utc), price.value))
+                .collect(),
+        });
+        // The latest days of every area
+        cached.sort_by(|a, b| b.date.cmp(&a.date));
+        let mut kept: Vec<Cached> = Vec::new();
+        for day in cached {
+            if kept.iter().filter(|kept| kept.area == day.area).count() < CACHED_DAYS {
+                kept.push(day);
+            }
+        }
+        if let Err(e) = save(&self.path, &kept) {
+            tracing::warn!("Failed to cache prices in {}: {}", self.path.display(), e);
+        }
+    }
+
+    fn recall(&self, date: NaiveDate, tz: Tz, area: Option<&Area>) -> Option<Vec<PricePoint>> {
+        let area = area.map(|area| area.name.clone());
+        let _lock = FILE_LOCK
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let day = load(&self.path)
+            .into_iter()
+            .find(|day| day.area == area && day.date == date)?;
+        Some(
+            day.prices
+                .into_iter()
+                .map(|(start, value)| PricePoint {
+                    start: start.with_timezone(&tz),
+                    value,
+                })
+                .collect(),
+        )
+    }
+
+    fn fall_back(&self, what: &str, e: &RefinerError) {
+        tracing::warn!("Failed to read {}, falling back to the cache: {}", what, e);
+        self.stale.store(true, Ordering::Relaxed);
+    }
+
+    /// Unlike falling back, leaves the rows unmarked as their prices are read from the store
+    fn go_without(&self, what: &str, e: &RefinerError) {
+        tracing::warn!("Failed to read {}, refining without it: {}", what, e);
+    }
+}
+
+#[async_trait]
+impl<'a> PriceStore for CachedStore<'a> {
+    async fn read_prices(
+        &self,
+        date: NaiveDate,
+        tz: Tz,
+        source: &SourceConfig,
+        area: Option<&Area>,
+    ) -> Result<Vec<PricePoint>, RefinerError> {
+        match self.store.read_prices(date, tz, source, area).await {
+            Ok(prices) => {
+                self.remember(date, area, &prices);
+                Ok(prices)
+            }
+            Err(e @ RefinerError::MissingData(_)) => Err(e),
+            Err(e) => match self.recall(date, tz, area) {
+                Some(prices) => {
+                    self.fall_back(&format!("the prices of {}", date), &e);
+                    Ok(prices)
+                }
+                None => Err(e),
+            },
+        }
+    }
+
+    async fn read_prices_before(
+        &self,
+        date: NaiveDate,
+        days: u32,
+        tz: Tz,
+        source: &SourceConfig,
+        area: Option<&Area>,
+    ) -> Result<Vec<PricePoint>, RefinerError> {
+        let history = self
+            .store
+            .read_prices_before(date, days, tz, source, area)
+            .await;
+        history.or_else(|e| {
+            self.go_without(&format!("the {} days before {}", days, date), &e);
+            Ok(Vec::new())
+        })
+    }
+
+    async fn read_consumption(
+        &self,
+        date: NaiveDate,
+        tz: Tz,
+        consumption: &ConsumptionConfig,
+        area: Option<&Area>,
+    ) -> Result<Vec<PricePoint>, RefinerError> {
+        let used = self
+            .store
+            .read_consumption(date, tz, consumption, area)
+            .await;
+        used.or_else(|e| {
+            self.go_without(&format!("the consumption of {}", date), &e);
+            Ok(Vec::new())
+        })
+    }
+
+    async fn count_refined(
+        &self,
+        date: NaiveDate,
+        measurement: &str,
+        area: Option<&Area>,
+        forecast: bool,
+    ) -> Result<u64, RefinerError> {
+        self.store
+            .count_refined(date, measurement, area, forecast)
+            .await
+    }
+
+    async fn write_refined(
+        &self,
+        date: NaiveDate,
+        rows: &[Refined],
+        measurement: &str,
+        area: Option<&Area>,
+    ) -> Result<(), RefinerError> {
+        self.store
+            .write_refined(date, rows, measurement, area)
+            .await
+    }
+
+    async fn delete_forecast(
+        &self,
+        date: NaiveDate,
+        measurement: &str,
+        area: Option<&Area>,
+    ) -> Result<(), RefinerError> {
+        self.store.delete_forecast(date, measurement, area).await
+    }
+
+    #[cfg(feature = "influx")]
+    async fn write(&self, queries: Vec<WriteQuery>) -> Result<(), RefinerError> {
+        self.store.write(queries).await
+    }
+
+    fn stale(&self) -> bool {
+        self.stale.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,86 @@
+use chrono::{DateTime, Datelike, Duration, NaiveDate, Timelike, Weekday};
+use chrono_tz::Tz;
+
+use super::config::CalendarConfig;
+
+/// Easter Sunday of `year` in the Gregorian calendar, by the anonymous algorithm
+pub fn easter(year: i32) -> NaiveDate {
+    let a = year % 19;
+    let b = year / 100;
+    let c = year % 100;
+    let d = b / 4;
+    let e = b % 4;
+    let f = (b + 8) / 25;
+    let g = (b - f + 1) / 3;
+    let h = (19 * a + b - d - g + 15) % 30;
+    let i = c / 4;
+    let k = c % 4;
+    let l = (32 + 2 * e + 2 * i - h - k) % 7;
+    let m = (a + 11 * h + 22 * l) / 451;
+    let month = (h + l - 7 * m + 114) / 31;
+    let day = (h + l - 7 * m + 114) % 31 + 1;
+    NaiveDate::from_ymd(year, month as u32, day as u32)
+}
+
+/// The Norwegian public holidays (helligdager) of `year`, in date order
+pub fn holidays(year: i32) -> Vec<NaiveDate> {
+    let easter = easter(year);
+    let mut holidays = vec![
+        NaiveDate::from_ymd(year, 1, 1),
+        // Maundy Thursday, Good Friday, Easter Sunday and Monday
+        easter - Duration::days(3),
+        easter - Duration::days(2),
+        easter,
+        easter + Duration::days(1),
+        NaiveDate::from_ymd(year, 5, 1),
+        NaiveDate::from_ymd(year, 5, 17),
+        // Ascension Day, Whit Sunday and Monday
+        easter + Duration::days(39),
+        easter + Duration::days(49),
+        easter + Duration::days(50),
+        NaiveDate::from_ymd(year, 12, 25),
+        NaiveDate::from_ymd(year, 12, 26),
+    ];
+    // Ascension Day falls on May 1st or 17th some years
+    holidays.sort();
+    holidays.dedup();
+    holidays
+}
+
+pub fn is_holiday(date: NaiveDate) -> bool {
+    holidays(date.year()).contains(&date)
+}
+
+/// Whether `date` is off-peak all day, on weekends and, unless turned off, holidays
+pub fn is_off_day(date: NaiveDate, calendar: &CalendarConfig) -> bool {
+    matches!(date.weekday(), Weekday::Sat | Weekday::Sun) || (calendar.holidays && is_holiday(date))
+}
+
+/// Whether a price starting at `start` is in the peak hours of a working day
+pub fn is_peak(start: DateTime<Tz>, calendar: &CalendarConfig) -> bool {
+    !is_off_day(start.date().naive_local(), calendar)
+        && (calendar.peak_start..calendar.peak_end).contains(&start.hour())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn easter_sundays() {
+        assert_eq!(easter(2000), NaiveDate::from_ymd(2000, 4, 23));
+        assert_eq!(easter(2008), NaiveDate::from_ymd(2008, 3, 23));
+        assert_eq!(easter(2019), NaiveDate::from_ymd(2019, 4, 21));
+        assert_eq!(easter(2024), NaiveDate::from_ymd(2024, 3, 31));
+        assert_eq!(easter(2025), NaiveDate::from_ymd(2025, 4, 20));
+    }
+
+    #[test]
+    fn ascension_on_may_day_is_one_holiday() {
+        // Easter 2008 was early enough that Ascension Day fell on May 1st
+        let holidays = holidays(2008);
+        assert_eq!(holidays.len(), 11);
+        assert!(holidays.contains(&NaiveDate::from_ymd(2008, 5, 1)));
+        assert!(holidays.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+}
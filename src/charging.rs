@@ -0,0 +1,163 @@
+use chrono::{DateTime, Duration, NaiveDate, TimeZone};
+use chrono_tz::Tz;
+#[cfg(feature = "influx")]
+use influxdb::{InfluxDbWriteable, Query, Timestamp, WriteQuery};
+use tracing::instrument;
+
+use super::{
+    config::{Area, EvConfig, RefinerConfig},
+    error::RefinerError,
+    refiner::{get_prices_at, points_per_hour, with_production, Day, PricePoint},
+    store::PriceStore,
+};
+
+/// The cheapest prices to charge in to have the car charged by `deadline`, which need not be
+/// consecutive
+#[derive(Clone, Debug)]
+pub struct Plan {
+    pub deadline: DateTime<Tz>,
+    /// Name of the configured area, unset with a single area
+    pub area: Option<String>,
+    /// Start of each price charged in, in time order
+    pub starts: Vec<DateTime<Tz>>,
+    pub energy_kwh: f64,
+    /// Estimated cost of the charged energy
+    pub cost: f64,
+    /// Whether there were enough prices known before the deadline to charge the target energy
+    pub complete: bool,
+}
+
+impl Plan {
+    /// Whether the price starting at `start` is charged in
+    pub fn contains(&self, start: DateTime<Tz>) -> bool {
+        self.starts.contains(&start)
+    }
+
+    /// The deadline is the timestamp, `date` and `area` are tags, everything else is a field
+    #[cfg(feature = "influx")]
+    pub fn to_query(&self, measurement: &str) -> WriteQuery {
+        let starts: Vec<String> = self
+            .starts
+            .iter()
+            .map(|start| start.format("%H:%M").to_string())
+            .collect();
+        let mut query = Timestamp::from(self.deadline)
+            .into_query(measurement)
+            .add_tag("date", self.deadline.date().naive_local().to_string())
+            .add_field("starts", starts.join(","))
+            .add_field("slots", self.starts.len() as u64)
+            .add_field("energy_kwh", self.energy_kwh)
+            .add_field("cost", self.cost)
+            .add_field("complete", self.complete);
+        if let Some(area) = &self.area {
+            query = query.add_tag("area", area.clone());
+        }
+        query
+    }
+}
+
+/// Local time at `hour` of `date`
+fn ready_by(date: NaiveDate, hour: u32, tz: Tz) -> Result<DateTime<Tz>, RefinerError> {
+    tz.from_local_datetime(&date.and_hms(hour, 0, 0))
+        .earliest()
+        .ok_or_else(|| RefinerError::Timestamp(format!("{} has no {}:00", date, hour)))
+}
+
+/// Picks the cheapest prices among those starting in the day before `deadline`
+pub fn plan(prices: &[PricePoint], deadline: DateTime<Tz>, config: &EvConfig) -> Plan {
+    let energy_per_point = config.power_kw / points_per_hour(prices) as f64;
+    let needed = (config.energy_kwh / energy_per_point).ceil() as usize;
+
+    let mut candidates: Vec<&PricePoint> = prices
+        .iter()
+        .filter(|price| deadline - Duration::days(1) <= price.start && price.start < deadline)
+        .collect();
+    candidates.sort_by(|a, b| {
+        a.value
+            .partial_cmp(&b.value)
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+    candidates.truncate(needed);
+
+    let mut starts: Vec<DateTime<Tz>> = candidates.iter().map(|price| price.start).collect();
+    starts.sort();
+    let energy_kwh = (starts.len() as f64 * energy_per_point).min(config.energy_kwh);
+    Plan {
+        deadline,
+        area: None,
+        complete: starts.len() == needed,
+        cost: candidates
+            .iter()
+            .map(|price| price.value * energy_per_point)
+            .sum(),
+        starts,
+        energy_kwh,
+    }
+}
+
+/// Plans the sessions ready by the morning of `date` and of the day after, which between them
+/// cover every price of `date`. With solar the prices are less what the forecast `production`
+/// saves, so hours the panels cover are preferred
+#[instrument(skip_all, fields(date = %date))]
+#[allow(clippy::too_many_arguments)]
+pub async fn plans(
+    date: NaiveDate,
+    prices: &[PricePoint],
+    store: &dyn PriceStore,
+    tz: Tz,
+    config: &RefinerConfig,
+    ev: &EvConfig,
+    area: Option<&Area>,
+    production: &[PricePoint],
+) -> Result<Vec<Plan>, RefinerError> {
+    let mut known = get_prices_at(Day::Yesterday.of(date), store, tz, config, area).await?;
+    known.extend_from_slice(prices);
+    known.extend(get_prices_at(Day::Tomorrow.of(date), store, tz, config, area).await?);
+    if let Some(solar) = &config.solar {
+        known = with_production(&known, production, solar);
+    }
+
+    let mut plans = Vec::new();
+    for date in [date, date.succ()] {
+        let mut plan = plan(&known, ready_by(date, ev.ready_by, tz)?, ev);
+        plan.area = area.map(|area| area.name.clone());
+        if !plan.complete {
+            tracing::warn!(
+                "Only {} of {} kWh can be charged by {}, prices are missing",
+                plan.energy_kwh,
+                ev.energy_kwh,
+                plan.deadline
+            );
+        }
+        plans.push(plan);
+    }
+    Ok(plans)
+}
+
+/// Renders plans as Influx line protocol, one line per plan
+#[cfg(feature = "influx")]
+pub fn line_protocol(plans: &[Plan]) -> Result<String, RefinerError> {
+    let write_queries: Vec<WriteQuery> = plans
+        .iter()
+        .map(|plan| plan.to_query("charging_plan"))
+        .collect();
+    Ok(write_queries
+        .build()
+        .map_err(|e| RefinerError::Write(e.to_string()))?
+        .get())
+}
+
+/// Writes all plans in a single batched query, a later plan for the same deadline replaces
+/// the earlier one
+#[cfg(feature = "influx")]
+#[instrument(skip_all, fields(plans = plans.len()))]
+pub async fn write_plans(plans: &[Plan], store: &dyn PriceStore) -> Result<(), RefinerError> {
+    if plans.is_empty() {
+        return Ok(());
+    }
+    let write_queries: Vec<WriteQuery> = plans
+        .iter()
+        .map(|plan| plan.to_query("charging_plan"))
+        .collect();
+    store.write(write_queries).await
+}
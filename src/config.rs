@@ -0,0 +1,1509 @@
+use std::{env, fmt::Display, fs, net::SocketAddr, path::PathBuf, str::FromStr};
+
+use chrono_tz::Tz;
+use serde::{
+    de::{DeserializeOwned, IntoDeserializer},
+    Deserialize, Serialize,
+};
+use tracing::Level;
+
+#[cfg(feature = "live")]
+use super::live::LiveSettings;
+#[cfg(feature = "mqtt")]
+use super::mqtt::MqttSettings;
+#[cfg(feature = "sentry")]
+use super::reporting::ReportingSettings;
+#[cfg(feature = "daemon")]
+use super::{
+    events::EventSettings, grafana::GrafanaSettings, notify::NotifySettings,
+    weather::WeatherSettings,
+};
+use super::{
+    refiner::{total_field, Refined},
+    rules, sink,
+};
+
+const DEFAULT_ANOMALY_FACTOR: f64 = 5.0;
+const DEFAULT_BREAKER_COOLDOWN_SECS: u64 = 60;
+const DEFAULT_BREAKER_THRESHOLD: u32 = 5;
+const DEFAULT_CONSUMPTION_COLUMN: &str = "consumption";
+const DEFAULT_CONSUMPTION_MEASUREMENT: &str = "consumption";
+const DEFAULT_CURRENCY: &str = "NOK";
+const DEFAULT_DATE_TAG: &str = "date";
+const DEFAULT_EV_POWER_KW: f64 = 11.0;
+const DEFAULT_EV_READY_BY: u32 = 7;
+const DEFAULT_MAX_CONCURRENT_QUERIES: usize = 4;
+const DEFAULT_PEAK_END: u32 = 22;
+const DEFAULT_PEAK_START: u32 = 6;
+const DEFAULT_PRICE_COLUMN: &str = "price";
+const DEFAULT_QUERY_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_REFINED_MEASUREMENT: &str = "refined";
+const DEFAULT_RETRIES: u32 = 10;
+const DEFAULT_TICK_TIMEOUT_SECS: u64 = 300;
+const DEFAULT_TOMORROW_POLL_SECS: u64 = 600;
+const DEFAULT_UPDATE_TIME: u32 = 0;
+const DEFAULT_ROLLING_CHEAPEST: usize = 4;
+const DEFAULT_SOLAR_LOAD_KW: f64 = 1.0;
+const DEFAULT_SOLAR_URL: &str = "https://api.forecast.solar";
+const DEFAULT_SOURCE_MEASUREMENT: &str = "price_info";
+const DEFAULT_SOURCE_WAIT_SECS: u64 = 7200;
+const DEFAULT_SUBSIDY_PERCENT: f64 = 90.0;
+const DEFAULT_SUBSIDY_THRESHOLD: f64 = 0.73;
+const DEFAULT_TARIFF_DAY_END: u32 = 22;
+const DEFAULT_TARIFF_DAY_START: u32 = 6;
+const DEFAULT_TREND_FLAT: f64 = 2.0;
+const DEFAULT_TREND_HOURS: usize = 3;
+const DEFAULT_VAT_PERCENT: f64 = 25.0;
+const DEFAULT_TIMEZONE: Tz = chrono_tz::Europe::Oslo;
+const DEFAULT_LOG_DIR: &str = "./var/log";
+const DEFAULT_LOG_FILE_PREFIX: &str = "tibber-status-server";
+const REDACTED: &str = "<redacted>";
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Print the refined rows as line protocol instead of writing them
+    pub dry_run: bool,
+    /// IANA name of the timezone that days and hours are counted in
+    pub timezone: Tz,
+    pub refiner: RefinerConfig,
+    pub influxdb: InfluxDbConfig,
+    pub schedule: ScheduleConfig,
+    pub logging: LoggingConfig,
+    #[cfg(feature = "mqtt")]
+    pub mqtt: Option<MqttSettings>,
+    #[cfg(feature = "daemon")]
+    pub notify: Option<NotifySettings>,
+    #[cfg(feature = "daemon")]
+    pub events: Option<EventSettings>,
+    #[cfg(feature = "live")]
+    pub live: Option<LiveSettings>,
+    #[cfg(feature = "daemon")]
+    pub grafana: Option<GrafanaSettings>,
+    #[cfg(feature = "daemon")]
+    pub weather: Option<WeatherSettings>,
+    #[cfg(feature = "sentry")]
+    pub reporting: Option<ReportingSettings>,
+    pub http: HttpConfig,
+    pub otel: OtelConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            dry_run: false,
+            timezone: DEFAULT_TIMEZONE,
+            refiner: RefinerConfig::default(),
+            influxdb: InfluxDbConfig::default(),
+            schedule: ScheduleConfig::default(),
+            logging: LoggingConfig::default(),
+            #[cfg(feature = "mqtt")]
+            mqtt: None,
+            #[cfg(feature = "daemon")]
+            notify: None,
+            #[cfg(feature = "daemon")]
+            events: None,
+            #[cfg(feature = "live")]
+            live: None,
+            #[cfg(feature = "daemon")]
+            grafana: None,
+            #[cfg(feature = "daemon")]
+            weather: None,
+            #[cfg(feature = "sentry")]
+            reporting: None,
+            http: HttpConfig::default(),
+            otel: OtelConfig::default(),
+        }
+    }
+}
+
+/// Resolution of the refined rows
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Resolution {
+    /// One row per hour, quarter hour prices are averaged
+    Hourly,
+    /// One row per price, hourly or quarter hourly as the prices were stored
+    Native,
+}
+
+/// How tomorrow's prices are guessed while they are unpublished
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Forecast {
+    /// Nothing is refined until the prices are published
+    Off,
+    /// The prices of the same weekday the week before
+    LastWeek,
+    /// Today's prices scaled by how much today's average changed from yesterday's
+    Trend,
+}
+
+/// A boolean field that is set when the price is within `low` and `high` percent of the
+/// daily average
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Band {
+    pub name: String,
+    pub low: f64,
+    pub high: f64,
+}
+
+impl Band {
+    fn new(name: &str, low: f64, high: f64) -> Band {
+        Band {
+            name: name.to_string(),
+            low,
+            high,
+        }
+    }
+}
+
+/// A boolean field set by an expression, see `rules::DayRules::evaluate` for what it can use
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub name: String,
+    pub expr: String,
+}
+
+impl Rule {
+    fn new(name: &str, expr: &str) -> Rule {
+        Rule {
+            name: name.to_string(),
+            expr: expr.to_string(),
+        }
+    }
+}
+
+/// Unit the prices are stored in, they are refined per kWh
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PriceUnit {
+    /// Currency per kWh, as Tibber reports them
+    Kwh,
+    /// Øre or cents per kWh
+    SubunitKwh,
+    /// Currency per MWh, as the power exchanges report them
+    Mwh,
+}
+
+impl PriceUnit {
+    /// Converts a stored price to currency per kWh
+    pub fn per_kwh(&self, value: f64) -> f64 {
+        match self {
+            PriceUnit::Kwh => value,
+            PriceUnit::SubunitKwh => value / 100.0,
+            PriceUnit::Mwh => value / 1000.0,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SourceConfig {
+    /// Measurement the prices are read from
+    pub measurement: String,
+    /// Field holding the price
+    pub price_column: String,
+    /// Tag holding the date of the price in the configured timezone
+    pub date_tag: String,
+    pub unit: PriceUnit,
+    /// Written as the currency tag of the refined rows
+    pub currency: String,
+}
+
+impl Default for SourceConfig {
+    fn default() -> Self {
+        SourceConfig {
+            measurement: DEFAULT_SOURCE_MEASUREMENT.to_string(),
+            price_column: DEFAULT_PRICE_COLUMN.to_string(),
+            date_tag: DEFAULT_DATE_TAG.to_string(),
+            unit: PriceUnit::Kwh,
+            currency: DEFAULT_CURRENCY.to_string(),
+        }
+    }
+}
+
+/// A price area or home refined alongside the others, its rows tagged with its name
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Area {
+    /// Written as the area tag of the refined rows
+    pub name: String,
+    /// Measurement the area's prices are read from, the source measurement if unset
+    pub measurement: Option<String>,
+    /// Tag the area's prices are selected by, every price of the measurement if unset
+    pub tag: Option<String>,
+    /// Value of `tag` for the area, its name if unset
+    pub value: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RefinerConfig {
+    pub source: SourceConfig,
+    /// Measurement the refined rows are written to
+    pub measurement: String,
+    /// Areas to refine every tick, a single untagged one if empty
+    pub areas: Vec<Area>,
+    pub resolution: Resolution,
+    /// Refines a forecast of tomorrow, tagged `forecast=true`, while its prices are unpublished
+    pub forecast: Forecast,
+    /// Lengths in hours of the runs of consecutive cheapest hours to find
+    pub windows: Vec<usize>,
+    /// Number of cheapest hours of the next 24 that are flagged once tomorrow's prices are known
+    pub rolling_cheapest: usize,
+    /// Hours ahead the trend is fitted over
+    pub trend_hours: usize,
+    /// Percent of the daily average per hour the price must change by to not be flat
+    pub trend_flat: f64,
+    /// Times the median of the seven days before a price must exceed, up or down, to be flagged
+    /// as an anomaly
+    pub anomaly_factor: f64,
+    pub bands: Vec<Band>,
+    pub rules: Vec<Rule>,
+    /// Consumer price fields are only refined if set
+    pub costs: Option<CostConfig>,
+    /// Total price fields are only refined if set
+    pub tariff: Option<TariffConfig>,
+    /// Peak and off-peak flags are only refined if set
+    pub calendar: Option<CalendarConfig>,
+    /// Solar production and net price fields are only refined if set
+    pub solar: Option<SolarConfig>,
+    /// Charging plans are only made if set
+    pub ev: Option<EvConfig>,
+    pub appliances: Vec<Appliance>,
+    /// Consumption cost fields are only refined if set
+    pub consumption: Option<ConsumptionConfig>,
+    /// File the last days of prices read are kept in, refined from with `stale_source` set when
+    /// reading them fails. Not kept if unset
+    pub price_cache: Option<String>,
+    /// Refined rows are only written as JSON if set
+    pub json_sink: Option<JsonSinkConfig>,
+}
+
+impl Default for RefinerConfig {
+    fn default() -> Self {
+        RefinerConfig {
+            source: SourceConfig::default(),
+            measurement: DEFAULT_REFINED_MEASUREMENT.to_string(),
+            areas: Vec::new(),
+            resolution: Resolution::Hourly,
+            forecast: Forecast::Off,
+            windows: vec![3],
+            rolling_cheapest: DEFAULT_ROLLING_CHEAPEST,
+            trend_hours: DEFAULT_TREND_HOURS,
+            trend_flat: DEFAULT_TREND_FLAT,
+            anomaly_factor: DEFAULT_ANOMALY_FACTOR,
+            bands: vec![
+                Band::new("t0_60", 0.0, 60.0),
+                Band::new("t60_90", 60.0, 90.0),
+                Band::new("t90_115", 90.0, 115.0),
+                Band::new("t115_140", 115.0, 140.0),
+                Band::new("t140_999", 140.0, 999.0),
+            ],
+            // The fields refined before rules were configurable
+            rules: vec![
+                Rule::new("in_6_l_8", "!in_lowest(2, 0, 8) && in_lowest(8, 0, 8)"),
+                Rule::new("in_0_6_high", "in_lowest(3, 0, 6)"),
+                Rule::new("in_6_12_high", "in_lowest(3, 6, 12)"),
+                Rule::new("in_12_18_high", "in_lowest(3, 12, 18)"),
+                Rule::new("in_18_24_high", "in_lowest(3, 18, 24)"),
+                Rule::new("i8h_low", "in_highest(8, 0, 8)"),
+            ],
+            costs: None,
+            tariff: None,
+            calendar: None,
+            solar: None,
+            ev: None,
+            appliances: Vec::new(),
+            consumption: None,
+            price_cache: None,
+            json_sink: None,
+        }
+    }
+}
+
+/// What the household pays on top of the spot price, per kWh in the currency of the prices
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CostConfig {
+    pub vat_percent: f64,
+    /// The supplier's markup, before VAT
+    pub markup: f64,
+    /// Fixed fee per month, before VAT
+    pub monthly_fee: f64,
+    /// Spot price before VAT above which the electricity subsidy (strømstøtte) covers a share
+    pub subsidy_threshold: f64,
+    /// Percent of the spot price above the threshold the subsidy covers, 0 to disable it
+    pub subsidy_percent: f64,
+}
+
+impl Default for CostConfig {
+    fn default() -> Self {
+        CostConfig {
+            vat_percent: DEFAULT_VAT_PERCENT,
+            markup: 0.0,
+            monthly_fee: 0.0,
+            subsidy_threshold: DEFAULT_SUBSIDY_THRESHOLD,
+            subsidy_percent: DEFAULT_SUBSIDY_PERCENT,
+        }
+    }
+}
+
+/// The energy part of a time of use grid tariff, per kWh in the currency of the prices
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TariffConfig {
+    /// Rate on weekdays from `day_start` until `day_end`
+    pub day: f64,
+    /// Rate on weekday nights
+    pub night: f64,
+    /// Rate all day on Saturdays and Sundays
+    pub weekend: f64,
+    pub day_start: u32,
+    pub day_end: u32,
+}
+
+impl Default for TariffConfig {
+    fn default() -> Self {
+        TariffConfig {
+            day: 0.0,
+            night: 0.0,
+            weekend: 0.0,
+            day_start: DEFAULT_TARIFF_DAY_START,
+            day_end: DEFAULT_TARIFF_DAY_END,
+        }
+    }
+}
+
+/// Peak hours of working days, for grid tariffs that are off-peak on weekends and holidays
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CalendarConfig {
+    pub peak_start: u32,
+    pub peak_end: u32,
+    /// Whether Norwegian public holidays are off-peak all day, like weekends
+    pub holidays: bool,
+}
+
+impl Default for CalendarConfig {
+    fn default() -> Self {
+        CalendarConfig {
+            peak_start: DEFAULT_PEAK_START,
+            peak_end: DEFAULT_PEAK_END,
+            holidays: true,
+        }
+    }
+}
+
+/// A photovoltaic system whose production is forecast by forecast.solar, or an API like it
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SolarConfig {
+    pub latitude: f64,
+    pub longitude: f64,
+    /// Tilt of the panels in degrees, 0 lying flat and 90 upright
+    pub declination: f64,
+    /// Direction of the panels in degrees, 0 south, -90 east and 90 west
+    pub azimuth: f64,
+    /// Peak power of the panels
+    pub kwp: f64,
+    pub url: String,
+    /// Key of a paid forecast.solar plan, the public API is used if unset
+    pub api_key: Option<String>,
+    /// Load in kW the net price is planned for, like a charging car. Production covers the
+    /// share of it that it exceeds
+    pub load_kw: f64,
+    /// Share of the spot price paid for energy fed into the grid, what self-consumed energy
+    /// would have earned
+    pub export_ratio: f64,
+}
+
+impl Default for SolarConfig {
+    fn default() -> Self {
+        SolarConfig {
+            latitude: 0.0,
+            longitude: 0.0,
+            declination: 0.0,
+            azimuth: 0.0,
+            kwp: 0.0,
+            url: DEFAULT_SOLAR_URL.to_string(),
+            api_key: None,
+            load_kw: DEFAULT_SOLAR_LOAD_KW,
+            export_ratio: 0.0,
+        }
+    }
+}
+
+/// Where refined rows are written as newline delimited JSON, when they are written to InfluxDB
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JsonSinkConfig {
+    /// File the rows are appended to, or `-` for standard output
+    pub path: String,
+    /// Write the rows only as JSON, not to InfluxDB. The summary, charging plans and schedules
+    /// are still written to InfluxDB
+    pub replace: bool,
+}
+
+impl Default for JsonSinkConfig {
+    fn default() -> Self {
+        JsonSinkConfig {
+            path: sink::STDOUT.to_string(),
+            replace: false,
+        }
+    }
+}
+
+/// An electric car to plan charging for
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EvConfig {
+    /// Energy to charge every day
+    pub energy_kwh: f64,
+    pub power_kw: f64,
+    /// Hour of the day the car must be charged by
+    pub ready_by: u32,
+}
+
+impl Default for EvConfig {
+    fn default() -> Self {
+        EvConfig {
+            energy_kwh: 0.0,
+            power_kw: DEFAULT_EV_POWER_KW,
+            ready_by: DEFAULT_EV_READY_BY,
+        }
+    }
+}
+
+/// Where the energy used every hour is stored, in kWh
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConsumptionConfig {
+    pub measurement: String,
+    pub column: String,
+}
+
+impl Default for ConsumptionConfig {
+    fn default() -> Self {
+        ConsumptionConfig {
+            measurement: DEFAULT_CONSUMPTION_MEASUREMENT.to_string(),
+            column: DEFAULT_CONSUMPTION_COLUMN.to_string(),
+        }
+    }
+}
+
+/// An appliance to schedule in the cheapest hours of its window
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Appliance {
+    pub name: String,
+    /// How long it runs
+    pub hours: usize,
+    /// Power drawn while running, only used to simulate savings
+    pub power_kw: f64,
+    /// Hour since midnight it may start running
+    pub earliest: usize,
+    /// Hour since midnight it must be done by
+    pub latest: usize,
+    /// Whether it must run in one go, or may be paused between the cheapest hours
+    pub contiguous: bool,
+}
+
+impl Default for Appliance {
+    fn default() -> Self {
+        Appliance {
+            name: String::new(),
+            hours: 1,
+            power_kw: 1.0,
+            earliest: 0,
+            latest: 24,
+            contiguous: true,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InfluxDbConfig {
+    pub addr: String,
+    pub db_name: String,
+    /// Upper bound on queries in flight at once, shared by every tick
+    pub max_concurrent_queries: usize,
+    pub query_timeout_secs: u64,
+    /// Consecutive failures before queries fail fast
+    pub breaker_threshold: u32,
+    pub breaker_cooldown_secs: u64,
+    /// Databases the refined rows are written to, `addr` and `db_name` if empty. The first one
+    /// is read back to check what was written
+    pub write_targets: Vec<WriteTarget>,
+    /// Retention policy of every write target the refined rows, charging plans and schedules
+    /// are written to, the database's default policy if unset
+    pub retention_policy: Option<String>,
+    /// Creates `retention_policy` on every write target at startup with this duration, like
+    /// `52w` or `INF`, if it doesn't exist
+    pub retention_duration: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct WriteTarget {
+    pub addr: String,
+    pub db_name: String,
+}
+
+impl Default for InfluxDbConfig {
+    fn default() -> Self {
+        InfluxDbConfig {
+            addr: String::new(),
+            db_name: String::new(),
+            max_concurrent_queries: DEFAULT_MAX_CONCURRENT_QUERIES,
+            query_timeout_secs: DEFAULT_QUERY_TIMEOUT_SECS,
+            breaker_threshold: DEFAULT_BREAKER_THRESHOLD,
+            breaker_cooldown_secs: DEFAULT_BREAKER_COOLDOWN_SECS,
+            write_targets: Vec::new(),
+            retention_policy: None,
+            retention_duration: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScheduleConfig {
+    /// Hour of the day at which the next day is refined
+    pub update_time: u32,
+    pub retries: u32,
+    /// Deadline for refining a whole day, a timed out tick is retried
+    pub tick_timeout_secs: u64,
+    /// Hour of the day at which to start polling for tomorrow's prices, tomorrow is only
+    /// refined by the daily pass if unset
+    pub tomorrow_time: Option<u32>,
+    pub tomorrow_poll_secs: u64,
+    /// Hour of the day by which tomorrow's prices must be stored, an error is raised if they
+    /// aren't. Not checked if unset
+    pub tomorrow_deadline: Option<u32>,
+    /// Age in hours of the latest refined row after which an error is raised, catching a
+    /// scheduler that silently stopped. Not checked if unset
+    pub stale_after_hours: Option<u32>,
+    /// Refine today again every hour and write the current hour's row, keeping fields that look
+    /// ahead up to date as tomorrow's prices arrive
+    pub hourly: bool,
+    /// Directory the days that failed on every retry are kept in, to refine them again after
+    /// the next successful tick or on startup. Failed days are only logged if unset
+    pub spool_dir: Option<String>,
+    /// Interval at which the source is polled for today's prices before refining it, on startup
+    /// and in the daily pass. Refined at once, retrying with backoff, if unset
+    pub source_poll_secs: Option<u64>,
+    /// How long to poll the source for before refining without the prices
+    pub source_wait_secs: u64,
+}
+
+impl Default for ScheduleConfig {
+    fn default() -> Self {
+        ScheduleConfig {
+            update_time: DEFAULT_UPDATE_TIME,
+            retries: DEFAULT_RETRIES,
+            tick_timeout_secs: DEFAULT_TICK_TIMEOUT_SECS,
+            tomorrow_time: None,
+            tomorrow_poll_secs: DEFAULT_TOMORROW_POLL_SECS,
+            tomorrow_deadline: None,
+            stale_after_hours: None,
+            hourly: false,
+            spool_dir: None,
+            source_poll_secs: None,
+            source_wait_secs: DEFAULT_SOURCE_WAIT_SECS,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl From<LogLevel> for Level {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Trace => Level::TRACE,
+            LogLevel::Debug => Level::DEBUG,
+            LogLevel::Info => Level::INFO,
+            LogLevel::Warn => Level::WARN,
+            LogLevel::Error => Level::ERROR,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogTarget {
+    File,
+    Stdout,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    Minutely,
+    Hourly,
+    Daily,
+    Never,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    pub level: LogLevel,
+    pub format: LogFormat,
+    pub target: LogTarget,
+    pub dir: String,
+    pub file_prefix: String,
+    pub rotation: LogRotation,
+    /// Keeps every log file if unset
+    pub max_files: Option<usize>,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            level: LogLevel::Info,
+            format: LogFormat::Text,
+            target: LogTarget::File,
+            dir: DEFAULT_LOG_DIR.to_string(),
+            file_prefix: DEFAULT_LOG_FILE_PREFIX.to_string(),
+            rotation: LogRotation::Daily,
+            max_files: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConfig {
+    /// The HTTP API is disabled if unset
+    pub addr: Option<SocketAddr>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OtelConfig {
+    /// OTLP exporting is disabled if unset
+    pub endpoint: Option<String>,
+}
+
+impl Config {
+    /// Loads the config file if given, applies environment overrides and validates the result
+    pub fn load(path: Option<PathBuf>) -> Result<Config, String> {
+        let mut config = match path {
+            Some(path) => {
+                let contents = fs::read_to_string(&path).map_err(|e| {
+                    format!("Failed to read config file {}: {}", path.display(), e)
+                })?;
+                toml::from_str(&contents).map_err(|e| {
+                    format!("Failed to parse config file {}: {}", path.display(), e)
+                })?
+            }
+            None => Config::default(),
+        };
+        config.apply_env()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// The config with its passwords and tokens replaced, for printing it
+    pub fn redacted(&self) -> Config {
+        let mut config = self.clone();
+        if let Some(api_key) = config
+            .refiner
+            .solar
+            .as_mut()
+            .and_then(|solar| solar.api_key.as_mut())
+        {
+            *api_key = REDACTED.to_string();
+        }
+        #[cfg(feature = "mqtt")]
+        if let Some(password) = config.mqtt.as_mut().and_then(|mqtt| mqtt.password.as_mut()) {
+            *password = REDACTED.to_string();
+        }
+        #[cfg(feature = "daemon")]
+        if let Some(token) = config
+            .notify
+            .as_mut()
+            .and_then(|notify| notify.telegram_token.as_mut())
+        {
+            *token = REDACTED.to_string();
+        }
+        #[cfg(feature = "live")]
+        if let Some(live) = &mut config.live {
+            live.token = REDACTED.to_string();
+        }
+        #[cfg(feature = "daemon")]
+        if let Some(grafana) = &mut config.grafana {
+            grafana.token = REDACTED.to_string();
+        }
+        #[cfg(feature = "sentry")]
+        if let Some(reporting) = &mut config.reporting {
+            reporting.dsn = REDACTED.to_string();
+        }
+        config
+    }
+
+    /// The config as TOML, in the layout of the config file
+    pub fn to_toml(&self) -> Result<String, String> {
+        // Through a value, which puts every table after the plain keys TOML needs first
+        let value = toml::Value::try_from(self).map_err(|e| e.to_string())?;
+        toml::to_string_pretty(&value).map_err(|e| e.to_string())
+    }
+
+    fn apply_env(&mut self) -> Result<(), String> {
+        env_parse("DRY_RUN", &mut self.dry_run)?;
+        env_parse("TIMEZONE", &mut self.timezone)?;
+        env_parse("PRICE_MEASUREMENT", &mut self.refiner.source.measurement)?;
+        env_parse("PRICE_COLUMN", &mut self.refiner.source.price_column)?;
+        env_parse("PRICE_DATE_TAG", &mut self.refiner.source.date_tag)?;
+        env_enum("PRICE_UNIT", &mut self.refiner.source.unit)?;
+        env_parse("CURRENCY", &mut self.refiner.source.currency)?;
+        env_parse("REFINED_MEASUREMENT", &mut self.refiner.measurement)?;
+        env_enum("RESOLUTION", &mut self.refiner.resolution)?;
+        env_enum("FORECAST", &mut self.refiner.forecast)?;
+        env_parse("ROLLING_CHEAPEST", &mut self.refiner.rolling_cheapest)?;
+        env_parse("TREND_HOURS", &mut self.refiner.trend_hours)?;
+        env_parse("TREND_FLAT", &mut self.refiner.trend_flat)?;
+        env_parse("ANOMALY_FACTOR", &mut self.refiner.anomaly_factor)?;
+        env_parse_opt("PRICE_CACHE", &mut self.refiner.price_cache)?;
+        if env::var("SOLAR_KWP").is_ok() && self.refiner.solar.is_none() {
+            self.refiner.solar = Some(SolarConfig::default());
+        }
+        if let Some(solar) = &mut self.refiner.solar {
+            env_parse("SOLAR_KWP", &mut solar.kwp)?;
+            env_parse("SOLAR_LAT", &mut solar.latitude)?;
+            env_parse("SOLAR_LON", &mut solar.longitude)?;
+            env_parse("SOLAR_DECLINATION", &mut solar.declination)?;
+            env_parse("SOLAR_AZIMUTH", &mut solar.azimuth)?;
+            env_parse("SOLAR_URL", &mut solar.url)?;
+            env_parse_opt("SOLAR_API_KEY", &mut solar.api_key)?;
+            env_parse("SOLAR_LOAD", &mut solar.load_kw)?;
+            env_parse("SOLAR_EXPORT_RATIO", &mut solar.export_ratio)?;
+        }
+        if env::var("JSON_SINK").is_ok() && self.refiner.json_sink.is_none() {
+            self.refiner.json_sink = Some(JsonSinkConfig::default());
+        }
+        if let Some(json_sink) = &mut self.refiner.json_sink {
+            env_parse("JSON_SINK", &mut json_sink.path)?;
+            env_parse("JSON_SINK_REPLACE", &mut json_sink.replace)?;
+        }
+
+        if env::var("VAT_PERCENT").is_ok() && self.refiner.costs.is_none() {
+            self.refiner.costs = Some(CostConfig::default());
+        }
+        if let Some(costs) = &mut self.refiner.costs {
+            env_parse("VAT_PERCENT", &mut costs.vat_percent)?;
+            env_parse("PRICE_MARKUP", &mut costs.markup)?;
+            env_parse("MONTHLY_FEE", &mut costs.monthly_fee)?;
+            env_parse("SUBSIDY_THRESHOLD", &mut costs.subsidy_threshold)?;
+            env_parse("SUBSIDY_PERCENT", &mut costs.subsidy_percent)?;
+        }
+        if env::var("TARIFF_DAY").is_ok() && self.refiner.tariff.is_none() {
+            self.refiner.tariff = Some(TariffConfig::default());
+        }
+        if let Some(tariff) = &mut self.refiner.tariff {
+            env_parse("TARIFF_DAY", &mut tariff.day)?;
+            env_parse("TARIFF_NIGHT", &mut tariff.night)?;
+            env_parse("TARIFF_WEEKEND", &mut tariff.weekend)?;
+            env_parse("TARIFF_DAY_START", &mut tariff.day_start)?;
+            env_parse("TARIFF_DAY_END", &mut tariff.day_end)?;
+        }
+        if env::var("PEAK_START").is_ok() && self.refiner.calendar.is_none() {
+            self.refiner.calendar = Some(CalendarConfig::default());
+        }
+        if let Some(calendar) = &mut self.refiner.calendar {
+            env_parse("PEAK_START", &mut calendar.peak_start)?;
+            env_parse("PEAK_END", &mut calendar.peak_end)?;
+            env_parse("HOLIDAYS", &mut calendar.holidays)?;
+        }
+        if env::var("EV_ENERGY").is_ok() && self.refiner.ev.is_none() {
+            self.refiner.ev = Some(EvConfig::default());
+        }
+        if let Some(ev) = &mut self.refiner.ev {
+            env_parse("EV_ENERGY", &mut ev.energy_kwh)?;
+            env_parse("EV_POWER", &mut ev.power_kw)?;
+            env_parse("EV_READY_BY", &mut ev.ready_by)?;
+        }
+        if env::var("CONSUMPTION_MEASUREMENT").is_ok() && self.refiner.consumption.is_none() {
+            self.refiner.consumption = Some(ConsumptionConfig::default());
+        }
+        if let Some(consumption) = &mut self.refiner.consumption {
+            env_parse("CONSUMPTION_MEASUREMENT", &mut consumption.measurement)?;
+            env_parse("CONSUMPTION_COLUMN", &mut consumption.column)?;
+        }
+
+        env_parse("INFLUXDB_ADDR", &mut self.influxdb.addr)?;
+        env_parse("INFLUXDB_DB_NAME", &mut self.influxdb.db_name)?;
+        env_parse(
+            "MAX_CONCURRENT_QUERIES",
+            &mut self.influxdb.max_concurrent_queries,
+        )?;
+        env_parse("QUERY_TIMEOUT", &mut self.influxdb.query_timeout_secs)?;
+        env_parse("BREAKER_THRESHOLD", &mut self.influxdb.breaker_threshold)?;
+        env_parse("BREAKER_COOLDOWN", &mut self.influxdb.breaker_cooldown_secs)?;
+        if let Ok(addr) = env::var("INFLUXDB_WRITE_ADDR") {
+            // A single target replaces the configured ones
+            let db_name = env::var("INFLUXDB_WRITE_DB_NAME")
+                .unwrap_or_else(|_| self.influxdb.db_name.clone());
+            self.influxdb.write_targets = vec![WriteTarget { addr, db_name }];
+        }
+        env_parse_opt(
+            "INFLUXDB_RETENTION_POLICY",
+            &mut self.influxdb.retention_policy,
+        )?;
+        env_parse_opt(
+            "INFLUXDB_RETENTION_DURATION",
+            &mut self.influxdb.retention_duration,
+        )?;
+
+        env_parse("UPDATE_TIME", &mut self.schedule.update_time)?;
+        env_parse("RETRIES", &mut self.schedule.retries)?;
+        env_parse("TICK_TIMEOUT", &mut self.schedule.tick_timeout_secs)?;
+        env_parse_opt("TOMORROW_TIME", &mut self.schedule.tomorrow_time)?;
+        env_parse(
+            "TOMORROW_POLL_INTERVAL",
+            &mut self.schedule.tomorrow_poll_secs,
+        )?;
+        env_parse_opt("TOMORROW_DEADLINE", &mut self.schedule.tomorrow_deadline)?;
+        env_parse_opt("STALE_AFTER", &mut self.schedule.stale_after_hours)?;
+        env_parse("HOURLY", &mut self.schedule.hourly)?;
+        env_parse_opt("SPOOL_DIR", &mut self.schedule.spool_dir)?;
+        env_parse_opt("SOURCE_POLL_INTERVAL", &mut self.schedule.source_poll_secs)?;
+        env_parse("SOURCE_WAIT", &mut self.schedule.source_wait_secs)?;
+
+        env_enum("LOG_LEVEL", &mut self.logging.level)?;
+        env_enum("LOG_FORMAT", &mut self.logging.format)?;
+        env_enum("LOG_TARGET", &mut self.logging.target)?;
+        env_parse("LOG_DIR", &mut self.logging.dir)?;
+        env_parse("LOG_FILE_PREFIX", &mut self.logging.file_prefix)?;
+        env_enum("LOG_ROTATION", &mut self.logging.rotation)?;
+        env_parse_opt("LOG_MAX_FILES", &mut self.logging.max_files)?;
+
+        #[cfg(feature = "mqtt")]
+        if env::var("MQTT_HOST").is_ok() && self.mqtt.is_none() {
+            self.mqtt = Some(MqttSettings::default());
+        }
+        #[cfg(feature = "mqtt")]
+        if let Some(mqtt) = &mut self.mqtt {
+            env_parse("MQTT_HOST", &mut mqtt.host)?;
+            env_parse("MQTT_PORT", &mut mqtt.port)?;
+            env_parse_opt("MQTT_USERNAME", &mut mqtt.username)?;
+            env_parse_opt("MQTT_PASSWORD", &mut mqtt.password)?;
+            env_parse("MQTT_BASE_TOPIC", &mut mqtt.base_topic)?;
+            env_parse("MQTT_DISCOVERY_PREFIX", &mut mqtt.discovery_prefix)?;
+        }
+
+        #[cfg(feature = "daemon")]
+        let channels = ["NTFY_URL", "TELEGRAM_TOKEN", "NOTIFY_WEBHOOK_URL"];
+        #[cfg(feature = "daemon")]
+        if channels.iter().any(|var| env::var(var).is_ok()) && self.notify.is_none() {
+            self.notify = Some(NotifySettings::default());
+        }
+        #[cfg(feature = "daemon")]
+        if let Some(notify) = &mut self.notify {
+            env_parse("NOTIFY_TITLE", &mut notify.title)?;
+            env_parse_opt("NTFY_URL", &mut notify.ntfy_url)?;
+            env_parse_opt("TELEGRAM_TOKEN", &mut notify.telegram_token)?;
+            env_parse_opt("TELEGRAM_CHAT_ID", &mut notify.telegram_chat_id)?;
+            env_parse_opt("NOTIFY_WEBHOOK_URL", &mut notify.webhook_url)?;
+        }
+
+        #[cfg(feature = "daemon")]
+        if let Ok(urls) = env::var("EVENT_WEBHOOK_URLS") {
+            let events = self.events.get_or_insert_with(EventSettings::default);
+            events.urls = comma_separated(&urls);
+        }
+        #[cfg(feature = "daemon")]
+        if let (Some(events), Ok(fields)) = (&mut self.events, env::var("EVENT_FIELDS")) {
+            events.fields = comma_separated(&fields);
+        }
+
+        #[cfg(feature = "live")]
+        if env::var("TIBBER_TOKEN").is_ok() && self.live.is_none() {
+            self.live = Some(LiveSettings::default());
+        }
+        #[cfg(feature = "live")]
+        if let Some(live) = &mut self.live {
+            env_parse("TIBBER_TOKEN", &mut live.token)?;
+            env_parse("TIBBER_HOME_ID", &mut live.home_id)?;
+            env_parse("LIVE_MEASUREMENT", &mut live.measurement)?;
+            env_parse("LIVE_WRITE_INTERVAL", &mut live.write_interval_secs)?;
+        }
+
+        #[cfg(feature = "daemon")]
+        if env::var("GRAFANA_URL").is_ok() && self.grafana.is_none() {
+            self.grafana = Some(GrafanaSettings::default());
+        }
+        #[cfg(feature = "daemon")]
+        if let Some(grafana) = &mut self.grafana {
+            env_parse("GRAFANA_URL", &mut grafana.url)?;
+            env_parse("GRAFANA_TOKEN", &mut grafana.token)?;
+            env_parse_opt("GRAFANA_DASHBOARD_UID", &mut grafana.dashboard_uid)?;
+            if let Ok(tags) = env::var("GRAFANA_TAGS") {
+                grafana.tags = comma_separated(&tags);
+            }
+            env_parse("GRAFANA_WINDOW_HOURS", &mut grafana.window_hours)?;
+        }
+        #[cfg(feature = "daemon")]
+        if env::var("WEATHER_LAT").is_ok() && self.weather.is_none() {
+            self.weather = Some(WeatherSettings::default());
+        }
+        #[cfg(feature = "daemon")]
+        if let Some(weather) = &mut self.weather {
+            env_parse("WEATHER_LAT", &mut weather.latitude)?;
+            env_parse("WEATHER_LON", &mut weather.longitude)?;
+            env_parse_opt("WEATHER_ALTITUDE", &mut weather.altitude)?;
+            env_parse("WEATHER_URL", &mut weather.url)?;
+            env_parse("WEATHER_USER_AGENT", &mut weather.user_agent)?;
+            env_parse("COLD_BELOW", &mut weather.cold_below)?;
+            env_parse("EXPENSIVE_RATIO", &mut weather.expensive_ratio)?;
+        }
+
+        #[cfg(feature = "sentry")]
+        if env::var("SENTRY_DSN").is_ok() && self.reporting.is_none() {
+            self.reporting = Some(ReportingSettings::default());
+        }
+        #[cfg(feature = "sentry")]
+        if let Some(reporting) = &mut self.reporting {
+            env_parse("SENTRY_DSN", &mut reporting.dsn)?;
+            env_parse_opt("SENTRY_ENVIRONMENT", &mut reporting.environment)?;
+            env_parse("SENTRY_SAMPLE_RATE", &mut reporting.sample_rate)?;
+        }
+
+        env_parse_opt("HTTP_ADDR", &mut self.http.addr)?;
+        env_parse_opt("OTEL_EXPORTER_OTLP_ENDPOINT", &mut self.otel.endpoint)?;
+
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.influxdb.addr.is_empty() {
+            return Err(
+                "influxdb.addr is not set, set it in the config file or with INFLUXDB_ADDR"
+                    .to_string(),
+            );
+        }
+        if self.influxdb.db_name.is_empty() {
+            return Err(
+                "influxdb.db_name is not set, set it in the config file or with INFLUXDB_DB_NAME"
+                    .to_string(),
+            );
+        }
+        if self.refiner.source.currency.is_empty() {
+            return Err("refiner.source.currency must not be empty".to_string());
+        }
+        let source = &self.refiner.source;
+        let names = [
+            &source.measurement,
+            &source.price_column,
+            &source.date_tag,
+            &self.refiner.measurement,
+        ];
+        if !names.iter().all(|name| query_safe(name)) {
+            return Err(
+                "refiner.measurement and refiner.source measurement, price_column and date_tag \
+                 must be non-empty and not contain quotes or backslashes"
+                    .to_string(),
+            );
+        }
+        let areas = &self.refiner.areas;
+        for (i, area) in areas.iter().enumerate() {
+            if area.name.is_empty() || areas[..i].iter().any(|a| a.name == area.name) {
+                return Err(format!(
+                    "refiner.areas must have unique, non-empty names, got {:?}",
+                    area.name
+                ));
+            }
+            let names = [&area.measurement, &area.tag, &area.value];
+            let mut names = names.into_iter().flatten().chain([&area.name]);
+            if !names.all(|name| query_safe(name)) {
+                return Err(format!(
+                    "refiner.areas {} must have a non-empty measurement, tag and value without \
+                     quotes or backslashes",
+                    area.name
+                ));
+            }
+        }
+        let windows = &self.refiner.windows;
+        if let Some(hours) = windows.iter().find(|hours| !(1..=23).contains(*hours)) {
+            return Err(format!(
+                "refiner.windows must be between 1 and 23 hours, got {}",
+                hours
+            ));
+        }
+        if !(1..=24).contains(&self.refiner.rolling_cheapest) {
+            return Err(format!(
+                "refiner.rolling_cheapest must be between 1 and 24 hours, got {}",
+                self.refiner.rolling_cheapest
+            ));
+        }
+        if !(1..=23).contains(&self.refiner.trend_hours) || !(self.refiner.trend_flat >= 0.0) {
+            return Err(format!(
+                "refiner.trend_hours must be between 1 and 23 and refiner.trend_flat at least 0, \
+                 got {} and {}",
+                self.refiner.trend_hours, self.refiner.trend_flat
+            ));
+        }
+        if !(self.refiner.anomaly_factor > 1.0) {
+            return Err(format!(
+                "refiner.anomaly_factor must be above 1, got {}",
+                self.refiner.anomaly_factor
+            ));
+        }
+        // Bands and rules are flattened into rows, next to every other field
+        let fixed = RefinerConfig {
+            bands: Vec::new(),
+            rules: Vec::new(),
+            ..self.refiner.clone()
+        };
+        let mut reserved: Vec<String> = match serde_json::to_value(Refined::schema(&fixed)) {
+            Ok(serde_json::Value::Object(fields)) => fields.keys().cloned().collect(),
+            _ => return Err("refined rows did not serialize into an object".to_string()),
+        };
+        for band in &self.refiner.bands {
+            let total = total_field(&band.name);
+            if reserved.contains(&total) {
+                return Err(format!(
+                    "refiner band {} would write its total price flag to {}, which is taken by \
+                     another field of refined rows",
+                    band.name, total
+                ));
+            }
+        }
+        reserved.extend(
+            self.refiner
+                .bands
+                .iter()
+                .map(|band| total_field(&band.name)),
+        );
+        let bands = self.refiner.bands.iter().map(|band| band.name.as_str());
+        let rule_names = self.refiner.rules.iter().map(|rule| rule.name.as_str());
+        let names: Vec<&str> = bands.chain(rule_names).collect();
+        for (i, name) in names.iter().enumerate() {
+            let valid_name =
+                !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+            if !valid_name {
+                return Err(format!(
+                    "refiner band and rule name {:?} must be a non-empty field name of letters, \
+                     digits and underscores",
+                    name
+                ));
+            }
+            if reserved.iter().any(|field| field == name) {
+                return Err(format!(
+                    "refiner band or rule name {} is taken by another field of refined rows, like \
+                     pris_time, cheapest_3h_start or a band's <name>_total",
+                    name
+                ));
+            }
+            if names[..i].contains(name) {
+                return Err(format!(
+                    "refiner has more than one band or rule named {}",
+                    name
+                ));
+            }
+        }
+        for rule in &self.refiner.rules {
+            rules::check(&rule.expr)
+                .map_err(|e| format!("refiner.rules {} is invalid: {}", rule.name, e))?;
+        }
+        for band in &self.refiner.bands {
+            if band.low >= band.high {
+                return Err(format!(
+                    "refiner.bands {} must have low below high, got {} and {}",
+                    band.name, band.low, band.high
+                ));
+            }
+        }
+        let appliances = &self.refiner.appliances;
+        for (i, appliance) in appliances.iter().enumerate() {
+            if appliance.name.is_empty() || appliances[..i].iter().any(|a| a.name == appliance.name)
+            {
+                return Err(format!(
+                    "refiner.appliances must have unique, non-empty names, got {:?}",
+                    appliance.name
+                ));
+            }
+            let window = appliance.latest.saturating_sub(appliance.earliest);
+            if appliance.latest > 24 || appliance.hours == 0 || appliance.hours > window {
+                return Err(format!(
+                    "refiner.appliances {} must run at least an hour within its window, \
+                     which ends at hour 24 at the latest",
+                    appliance.name
+                ));
+            }
+            if !(appliance.power_kw > 0.0 && appliance.power_kw.is_finite()) {
+                return Err(format!(
+                    "refiner.appliances {} must draw more than 0 kW, got {}",
+                    appliance.name, appliance.power_kw
+                ));
+            }
+        }
+        if let Some(costs) = &self.refiner.costs {
+            if !(0.0..=100.0).contains(&costs.vat_percent)
+                || !(0.0..=100.0).contains(&costs.subsidy_percent)
+            {
+                return Err(format!(
+                    "refiner.costs.vat_percent and subsidy_percent must be between 0 and 100, \
+                     got {} and {}",
+                    costs.vat_percent, costs.subsidy_percent
+                ));
+            }
+        }
+        if let Some(tariff) = &self.refiner.tariff {
+            if tariff.day_start >= tariff.day_end || tariff.day_end > 24 {
+                return Err(format!(
+                    "refiner.tariff.day_start must be before day_end, which is 24 at the latest, \
+                     got {} and {}",
+                    tariff.day_start, tariff.day_end
+                ));
+            }
+        }
+        if let Some(solar) = &self.refiner.solar {
+            if !(-90.0..=90.0).contains(&solar.latitude)
+                || !(-180.0..=180.0).contains(&solar.longitude)
+            {
+                return Err(format!(
+                    "refiner.solar.latitude must be between -90 and 90 and longitude between -180 \
+                     and 180, got {} and {}",
+                    solar.latitude, solar.longitude
+                ));
+            }
+            if !(0.0..=90.0).contains(&solar.declination)
+                || !(-180.0..=180.0).contains(&solar.azimuth)
+            {
+                return Err(format!(
+                    "refiner.solar.declination must be between 0 and 90 and azimuth between -180 \
+                     and 180, got {} and {}",
+                    solar.declination, solar.azimuth
+                ));
+            }
+            if !(solar.kwp > 0.0) || !(solar.load_kw > 0.0) {
+                return Err(format!(
+                    "refiner.solar.kwp and load_kw must be above 0, got {} and {}",
+                    solar.kwp, solar.load_kw
+                ));
+            }
+            if !(0.0..=1.0).contains(&solar.export_ratio) {
+                return Err(format!(
+                    "refiner.solar.export_ratio must be between 0 and 1, got {}",
+                    solar.export_ratio
+                ));
+            }
+        }
+        if let Some(calendar) = &self.refiner.calendar {
+            if calendar.peak_start >= calendar.peak_end || calendar.peak_end > 24 {
+                return Err(format!(
+                    "refiner.calendar.peak_start must be before peak_end, which is 24 at the \
+                     latest, got {} and {}",
+                    calendar.peak_start, calendar.peak_end
+                ));
+            }
+        }
+        if let Some(consumption) = &self.refiner.consumption {
+            let names = [&consumption.measurement, &consumption.column];
+            if !names.iter().all(|name| query_safe(name)) {
+                return Err(
+                    "refiner.consumption measurement and column must be non-empty and not \
+                     contain quotes or backslashes"
+                        .to_string(),
+                );
+            }
+        }
+        if let Some(ev) = &self.refiner.ev {
+            if !(ev.energy_kwh > 0.0 && ev.power_kw > 0.0) {
+                return Err(format!(
+                    "refiner.ev.energy_kwh and refiner.ev.power_kw must be above 0, got {} and {}",
+                    ev.energy_kwh, ev.power_kw
+                ));
+            }
+            if ev.ready_by > 23 {
+                return Err(format!(
+                    "refiner.ev.ready_by must be an hour between 0 and 23, got {}",
+                    ev.ready_by
+                ));
+            }
+        }
+        if let Some(target) = self
+            .influxdb
+            .write_targets
+            .iter()
+            .find(|target| target.addr.is_empty() || target.db_name.is_empty())
+        {
+            return Err(format!(
+                "influxdb.write_targets must all have addr and db_name set, got {:?} and {:?}",
+                target.addr, target.db_name
+            ));
+        }
+        if let Some(policy) = &self.influxdb.retention_policy {
+            if !query_safe(policy) {
+                return Err(format!(
+                    "influxdb.retention_policy must be non-empty and not contain quotes or \
+                     backslashes, got {:?}",
+                    policy
+                ));
+            }
+        }
+        if let Some(duration) = &self.influxdb.retention_duration {
+            if self.influxdb.retention_policy.is_none() {
+                return Err(
+                    "influxdb.retention_duration is set without influxdb.retention_policy"
+                        .to_string(),
+                );
+            }
+            if !is_influx_duration(duration) {
+                return Err(format!(
+                    "influxdb.retention_duration must be an InfluxDB duration like 52w or INF, \
+                     got {:?}",
+                    duration
+                ));
+            }
+        }
+        if self.influxdb.max_concurrent_queries == 0 {
+            return Err("influxdb.max_concurrent_queries must be at least 1".to_string());
+        }
+        if self.influxdb.breaker_threshold == 0 {
+            return Err("influxdb.breaker_threshold must be at least 1".to_string());
+        }
+        if self.influxdb.query_timeout_secs == 0 || self.schedule.tick_timeout_secs == 0 {
+            return Err(
+                "influxdb.query_timeout_secs and schedule.tick_timeout_secs must be at least 1"
+                    .to_string(),
+            );
+        }
+        if self.schedule.update_time > 23 {
+            return Err(format!(
+                "schedule.update_time must be an hour between 0 and 23, got {}",
+                self.schedule.update_time
+            ));
+        }
+        if let Some(tomorrow_time) = self.schedule.tomorrow_time {
+            if tomorrow_time > 23 {
+                return Err(format!(
+                    "schedule.tomorrow_time must be an hour between 0 and 23, got {}",
+                    tomorrow_time
+                ));
+            }
+        }
+        if let Some(tomorrow_deadline) = self.schedule.tomorrow_deadline {
+            if tomorrow_deadline > 23 {
+                return Err(format!(
+                    "schedule.tomorrow_deadline must be an hour between 0 and 23, got {}",
+                    tomorrow_deadline
+                ));
+            }
+        }
+        if self.schedule.stale_after_hours == Some(0) {
+            return Err("schedule.stale_after_hours must be at least 1".to_string());
+        }
+        if self.schedule.tomorrow_poll_secs == 0 {
+            return Err("schedule.tomorrow_poll_secs must be at least 1".to_string());
+        }
+        if self.schedule.source_poll_secs == Some(0) {
+            return Err("schedule.source_poll_secs must be at least 1".to_string());
+        }
+        if self.schedule.retries == 0 {
+            return Err("schedule.retries must be at least 1".to_string());
+        }
+        #[cfg(feature = "mqtt")]
+        if let Some(mqtt) = &self.mqtt {
+            if mqtt.host.is_empty() {
+                return Err(
+                    "mqtt.host is not set, set it in the config file or with MQTT_HOST"
+                        .to_string(),
+                );
+            }
+        }
+        #[cfg(feature = "daemon")]
+        if let Some(notify) = &self.notify {
+            if notify.telegram_token.is_some() != notify.telegram_chat_id.is_some() {
+                return Err(
+                    "notify.telegram_token and notify.telegram_chat_id must be set together"
+                        .to_string(),
+                );
+            }
+            let channels = [
+                &notify.ntfy_url,
+                &notify.telegram_token,
+                &notify.webhook_url,
+            ];
+            if channels.iter().all(|channel| channel.is_none()) {
+                return Err(
+                    "notify needs at least one of ntfy_url, telegram_token and webhook_url"
+                        .to_string(),
+                );
+            }
+        }
+        #[cfg(feature = "live")]
+        if let Some(live) = &self.live {
+            if live.token.is_empty() || live.home_id.is_empty() || live.measurement.is_empty() {
+                return Err(
+                    "live.token, live.home_id and live.measurement must be set, set them in the \
+                     config file or with TIBBER_TOKEN, TIBBER_HOME_ID and LIVE_MEASUREMENT"
+                        .to_string(),
+                );
+            }
+            // Quoted in the subscription
+            if live.home_id.contains(['"', '\\']) {
+                return Err(format!(
+                    "live.home_id must not contain quotes or backslashes, got {:?}",
+                    live.home_id
+                ));
+            }
+        }
+        #[cfg(feature = "daemon")]
+        if let Some(grafana) = &self.grafana {
+            if grafana.url.is_empty() || grafana.token.is_empty() {
+                return Err(
+                    "grafana.url and grafana.token must be set, set them in the config file or \
+                     with GRAFANA_URL and GRAFANA_TOKEN"
+                        .to_string(),
+                );
+            }
+            if grafana.window_hours == 0 || grafana.window_hours > 24 {
+                return Err(format!(
+                    "grafana.window_hours must be from 1 through 24, got {}",
+                    grafana.window_hours
+                ));
+            }
+        }
+        #[cfg(feature = "daemon")]
+        if let Some(weather) = &self.weather {
+            if !(-90.0..=90.0).contains(&weather.latitude)
+                || !(-180.0..=180.0).contains(&weather.longitude)
+            {
+                return Err(format!(
+                    "weather.latitude must be between -90 and 90 and weather.longitude between \
+                     -180 and 180, got {} and {}",
+                    weather.latitude, weather.longitude
+                ));
+            }
+            if weather.user_agent.is_empty() {
+                return Err(
+                    "weather.user_agent must identify the refiner to MET Norway".to_string()
+                );
+            }
+        }
+        #[cfg(feature = "sentry")]
+        if let Some(reporting) = &self.reporting {
+            if reporting.dsn.is_empty() {
+                return Err(
+                    "reporting.dsn is not set, set it in the config file or with SENTRY_DSN"
+                        .to_string(),
+                );
+            }
+            if !(0.0..=1.0).contains(&reporting.sample_rate) {
+                return Err(format!(
+                    "reporting.sample_rate must be from 0 through 1, got {}",
+                    reporting.sample_rate
+                ));
+            }
+        }
+        #[cfg(feature = "daemon")]
+        if let Some(events) = &self.events {
+            if events.urls.is_empty() || events.urls.iter().any(|url| url.is_empty()) {
+                return Err(
+                    "events.urls must not be empty, set it in the config file or with \
+                     EVENT_WEBHOOK_URLS"
+                        .to_string(),
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether `name` can be used as a measurement, field, tag or retention policy. They end up
+/// quoted in queries
+fn query_safe(name: &str) -> bool {
+    !name.is_empty() && !name.contains(['\'', '"', '\\'])
+}
+
+/// Whether `duration` is an InfluxQL duration literal, like `30d` or `1w2d`, or `INF`
+fn is_influx_duration(duration: &str) -> bool {
+    if duration == "INF" {
+        return true;
+    }
+    let mut rest = duration;
+    while !rest.is_empty() {
+        let digits = rest.len() - rest.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        if digits == 0 {
+            return false;
+        }
+        rest = &rest[digits..];
+        let unit = ["ns", "ms", "u", "µ", "s", "m", "h", "d", "w"]
+            .iter()
+            .find(|unit| rest.starts_with(*unit));
+        match unit {
+            Some(unit) => rest = &rest[unit.len()..],
+            None => return false,
+        }
+    }
+    !duration.is_empty()
+}
+
+/// The trimmed, non-empty items of a comma separated list
+fn comma_separated(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Overwrites `target` with the parsed value of `var` if it is set
+fn env_parse<T>(var: &str, target: &mut T) -> Result<(), String>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    if let Ok(value) = env::var(var) {
+        *target = value
+            .parse()
+            .map_err(|e| format!("Failed to parse {} {}: {}", var, value, e))?;
+    }
+    Ok(())
+}
+
+fn env_parse_opt<T>(var: &str, target: &mut Option<T>) -> Result<(), String>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    if let Ok(value) = env::var(var) {
+        *target = Some(
+            value
+                .parse()
+                .map_err(|e| format!("Failed to parse {} {}: {}", var, value, e))?,
+        );
+    }
+    Ok(())
+}
+
+/// Like `env_parse`, but for the lowercase enums used in the config file
+fn env_enum<T: DeserializeOwned>(var: &str, target: &mut T) -> Result<(), String> {
+    if let Ok(value) = env::var(var) {
+        *target = T::deserialize(value.as_str().into_deserializer())
+            .map_err(|e: serde::de::value::Error| format!("Invalid {} {}: {}", var, value, e))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,322 @@
+use std::{
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use influxdb::{Client, Query, ReadQuery, WriteQuery};
+use tokio::{sync::Semaphore, time};
+
+use super::{config::InfluxDbConfig, error::RefinerError, metrics};
+
+/// How long an unused connection is kept open for the next query
+const POOL_IDLE_SECS: u64 = 90;
+const TCP_KEEPALIVE_SECS: u64 = 60;
+
+/// Fails fast for `cooldown` once `threshold` queries in a row have failed, so a down
+/// database isn't hammered by every retry
+struct Breaker {
+    threshold: u32,
+    cooldown: Duration,
+    state: Mutex<BreakerState>,
+}
+
+#[derive(Default)]
+struct BreakerState {
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+}
+
+impl Breaker {
+    fn check(&self) -> Result<(), RefinerError> {
+        let state = self
+            .state
+            .lock()
+            .map_err(|e| RefinerError::Query(e.to_string()))?;
+        match state.open_until {
+            Some(until) if Instant::now() < until => {
+                Err(RefinerError::CircuitOpen(until - Instant::now()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn record<T>(&self, result: &Result<T, RefinerError>) {
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(e) => {
+                tracing::error!("Circuit breaker state poisoned: {}", e);
+                return;
+            }
+        };
+        if result.is_ok() {
+            if state.open_until.take().is_some() {
+                tracing::info!("InfluxDB is answering again, closing circuit breaker");
+            }
+            state.consecutive_failures = 0;
+            return;
+        }
+
+        state.consecutive_failures += 1;
+        if state.consecutive_failures >= self.threshold {
+            tracing::warn!(
+                "Opening circuit breaker for {:?} after {} consecutive InfluxDB failures",
+                self.cooldown,
+                state.consecutive_failures
+            );
+            metrics::CIRCUIT_BREAKER_TRIPS.inc();
+            state.open_until = Some(Instant::now() + self.cooldown);
+        }
+    }
+}
+
+/// A database written to
+struct Writer {
+    client: Client,
+    addr: String,
+    db_name: String,
+}
+
+/// The InfluxDB clients shared by everything that reads or writes, with the number of
+/// concurrent queries bounded by a semaphore, every query bounded by a timeout and a
+/// circuit breaker in front
+#[derive(Clone)]
+pub struct Db {
+    /// Where the prices are read from
+    client: Client,
+    /// Where writes go, the first is also where written rows are read back from
+    writers: Arc<Vec<Writer>>,
+    /// Retention policy writes go to, the client can't choose one so they're posted directly
+    retention_policy: Option<String>,
+    retention_duration: Option<String>,
+    http: reqwest::Client,
+    permits: Arc<Semaphore>,
+    query_timeout: Duration,
+    breaker: Arc<Breaker>,
+}
+
+impl Db {
+    /// Every client shares one pool of kept alive connections, build it once and clone it
+    pub fn new(config: &InfluxDbConfig) -> Db {
+        let http = reqwest::Client::builder()
+            .pool_max_idle_per_host(config.max_concurrent_queries)
+            .pool_idle_timeout(Duration::from_secs(POOL_IDLE_SECS))
+            .tcp_keepalive(Duration::from_secs(TCP_KEEPALIVE_SECS))
+            .gzip(true)
+            .build()
+            .unwrap_or_default();
+        let client = Client::new(config.addr.as_str(), config.db_name.as_str())
+            .with_http_client(http.clone());
+        let mut writers: Vec<Writer> = config
+            .write_targets
+            .iter()
+            .map(|target| Writer {
+                client: Client::new(target.addr.as_str(), target.db_name.as_str())
+                    .with_http_client(http.clone()),
+                addr: target.addr.clone(),
+                db_name: target.db_name.clone(),
+            })
+            .collect();
+        if writers.is_empty() {
+            writers.push(Writer {
+                client: client.clone(),
+                addr: config.addr.clone(),
+                db_name: config.db_name.clone(),
+            });
+        }
+        Db {
+            client,
+            writers: Arc::new(writers),
+            retention_policy: config.retention_policy.clone(),
+            retention_duration: config.retention_duration.clone(),
+            http,
+            permits: Arc::new(Semaphore::new(config.max_concurrent_queries)),
+            query_timeout: Duration::from_secs(config.query_timeout_secs),
+            breaker: Arc::new(Breaker {
+                threshold: config.breaker_threshold,
+                cooldown: Duration::from_secs(config.breaker_cooldown_secs),
+                state: Mutex::new(BreakerState::default()),
+            }),
+        }
+    }
+
+    pub async fn read(&self, query: &ReadQuery) -> Result<String, RefinerError> {
+        self.query(&self.client, "read", query, RefinerError::Query)
+            .await
+    }
+
+    /// Reads from the first write target, where the written rows are
+    pub async fn read_written(&self, query: &ReadQuery) -> Result<String, RefinerError> {
+        self.query(&self.writers[0].client, "read", query, RefinerError::Query)
+            .await
+    }
+
+    /// `measurement` quoted for reading it back from the retention policy it is written to
+    pub fn written_measurement(&self, measurement: &str) -> String {
+        match &self.retention_policy {
+            Some(policy) => format!("\"{}\".\"{}\"", policy, measurement),
+            None => format!("\"{}\"", measurement),
+        }
+    }
+
+    /// Writes to every write target, all are tried and the first failure is returned
+    pub async fn write(&self, query: Vec<WriteQuery>) -> Result<String, RefinerError> {
+        let mut result = Ok(String::new());
+        for writer in self.writers.iter() {
+            let written = match &self.retention_policy {
+                Some(policy) => self.write_to_policy(writer, policy, &query).await,
+                None => {
+                    self.query(&writer.client, "write", query.clone(), RefinerError::Write)
+                        .await
+                }
+            };
+            result = result.and(written);
+        }
+        result
+    }
+
+    /// Runs a DELETE statement against every write target, all are tried and the first failure
+    /// is returned. Deletes from every retention policy
+    pub async fn delete(&self, statement: &str) -> Result<(), RefinerError> {
+        let mut result = Ok(());
+        for writer in self.writers.iter() {
+            let query = ReadQuery::new(statement);
+            let deleted = self
+                .query(&writer.client, "delete", query, RefinerError::Write)
+                .await;
+            result = result.and(deleted.map(|_| ()));
+        }
+        result
+    }
+
+    /// Creates the retention policy on every write target if a duration is configured, an
+    /// existing policy with the same duration is left alone
+    pub async fn create_retention_policy(&self) -> Result<(), RefinerError> {
+        let (policy, duration) = match (&self.retention_policy, &self.retention_duration) {
+            (Some(policy), Some(duration)) => (policy, duration),
+            _ => return Ok(()),
+        };
+        for writer in self.writers.iter() {
+            let query = ReadQuery::new(format!(
+                "CREATE RETENTION POLICY \"{}\" ON \"{}\" DURATION {} REPLICATION 1",
+                policy, writer.db_name, duration
+            ));
+            self.query(&writer.client, "admin", query, RefinerError::Query)
+                .await?;
+            tracing::info!(
+                "Retention policy {} on {} keeps {}",
+                policy,
+                writer.db_name,
+                duration
+            );
+        }
+        Ok(())
+    }
+
+    /// Returns the build and version of the database
+    pub async fn ping(&self) -> Result<(String, String), RefinerError> {
+        self.breaker.check()?;
+        let _permit = self
+            .permits
+            .acquire()
+            .await
+            .map_err(|e| RefinerError::Query(e.to_string()))?;
+        let result = match time::timeout(self.query_timeout, self.client.ping()).await {
+            Ok(result) => result.map_err(|e| RefinerError::Query(e.to_string())),
+            Err(_) => Err(RefinerError::Timeout(self.query_timeout)),
+        };
+        self.breaker.record(&result);
+        result
+    }
+
+    /// Runs `query` against `client`, wrapping the client's errors with `error`
+    async fn query<Q: Query>(
+        &self,
+        client: &Client,
+        kind: &str,
+        query: Q,
+        error: fn(String) -> RefinerError,
+    ) -> Result<String, RefinerError> {
+        let request = async { client.query(query).await.map_err(|e| error(e.to_string())) };
+        self.guarded(kind, request, error).await
+    }
+
+    /// Posts the line protocol of `query` to the write endpoint of `writer` with the retention
+    /// policy set
+    async fn write_to_policy(
+        &self,
+        writer: &Writer,
+        policy: &str,
+        query: &[WriteQuery],
+    ) -> Result<String, RefinerError> {
+        let body = query
+            .to_vec()
+            .build()
+            .map_err(|e| RefinerError::Write(e.to_string()))?
+            .get();
+        let request = async {
+            let response = self
+                .http
+                .post(format!("{}/write", writer.addr.trim_end_matches('/')))
+                // Every point is timestamped from a `DateTime`, in nanoseconds
+                .query(&[
+                    ("db", writer.db_name.as_str()),
+                    ("rp", policy),
+                    ("precision", "ns"),
+                ])
+                .body(body)
+                .send()
+                .await
+                .map_err(|e| RefinerError::Write(e.to_string()))?;
+            let status = response.status();
+            let text = response
+                .text()
+                .await
+                .map_err(|e| RefinerError::Write(e.to_string()))?;
+            if status.is_success() {
+                Ok(text)
+            } else {
+                Err(RefinerError::Write(format!("{}: {}", status, text)))
+            }
+        };
+        self.guarded("write", request, RefinerError::Write).await
+    }
+
+    /// Runs `request` behind the circuit breaker, the semaphore and the timeout
+    async fn guarded<F>(
+        &self,
+        kind: &str,
+        request: F,
+        error: fn(String) -> RefinerError,
+    ) -> Result<String, RefinerError>
+    where
+        F: Future<Output = Result<String, RefinerError>>,
+    {
+        self.breaker.check()?;
+        let _permit = self
+            .permits
+            .acquire()
+            .await
+            .map_err(|e| error(e.to_string()))?;
+
+        let timer = metrics::INFLUX_QUERY_DURATION
+            .with_label_values(&[kind])
+            .start_timer();
+        let result = match time::timeout(self.query_timeout, request).await {
+            Ok(result) => result,
+            Err(_) => {
+                tracing::warn!("InfluxDB {} timed out after {:?}", kind, self.query_timeout);
+                Err(RefinerError::Timeout(self.query_timeout))
+            }
+        };
+        timer.observe_duration();
+        self.breaker.record(&result);
+
+        if result.is_err() {
+            metrics::INFLUX_QUERY_FAILURES
+                .with_label_values(&[kind])
+                .inc();
+        }
+        result
+    }
+}
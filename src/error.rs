@@ -0,0 +1,88 @@
+use std::time::Duration;
+
+use chrono::NaiveDate;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum RefinerError {
+    #[error("InfluxDB query failed: {0}")]
+    Query(String),
+    #[error("InfluxDB query timed out after {0:?}")]
+    Timeout(Duration),
+    #[error("Circuit breaker open, not querying InfluxDB for another {0:?}")]
+    CircuitOpen(Duration),
+    #[error("Error parsing result from {query}: {source}")]
+    Parse {
+        query: String,
+        #[source]
+        source: serde_json::Error,
+    },
+    /// The prices have not been published yet, or a row is missing after writing
+    #[error("Missing data: {0}")]
+    MissingData(String),
+    #[error("Failed to write refined rows: {0}")]
+    Write(String),
+    #[error("Invalid timestamp: {0}")]
+    Timestamp(String),
+    #[error("Rule failed: {0}")]
+    Rule(String),
+    #[error("Refine task failed: {0}")]
+    Task(String),
+    #[error("Tick for {date} timed out after {deadline:?}")]
+    Deadline { date: NaiveDate, deadline: Duration },
+    #[error("{}", summarize_hours(.date, .failed_hours))]
+    Hours {
+        date: NaiveDate,
+        failed_hours: Vec<(usize, RefinerError)>,
+    },
+}
+
+/// Why the binary failed to start, each with its own exit code so a supervisor can tell a broken
+/// config apart from a database that isn't up yet
+#[derive(Debug, Error)]
+pub enum StartupError {
+    #[error("Invalid configuration: {0}")]
+    Config(String),
+    #[error("Failed to set up logging: {0}")]
+    Logging(String),
+    #[error("InfluxDB at {addr} is not reachable: {source}")]
+    Unreachable {
+        addr: String,
+        #[source]
+        source: RefinerError,
+    },
+}
+
+impl StartupError {
+    /// Exit codes from sysexits.h: EX_CONFIG, EX_SOFTWARE and EX_UNAVAILABLE
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            StartupError::Config(_) => 78,
+            StartupError::Logging(_) => 70,
+            StartupError::Unreachable { .. } => 69,
+        }
+    }
+}
+
+impl RefinerError {
+    /// Whether trying again later may succeed, as opposed to failing the same way every time
+    pub fn is_transient(&self) -> bool {
+        match self {
+            RefinerError::Parse { .. } | RefinerError::Timestamp(_) | RefinerError::Rule(_) => {
+                false
+            }
+            RefinerError::Hours { failed_hours, .. } => {
+                failed_hours.iter().any(|(_, e)| e.is_transient())
+            }
+            _ => true,
+        }
+    }
+}
+
+fn summarize_hours(date: &NaiveDate, failed_hours: &[(usize, RefinerError)]) -> String {
+    let mut summary = format!("Failed to refine {} hours of {}", failed_hours.len(), date);
+    for (hour, e) in failed_hours {
+        summary.push_str(&format!("; hour {}: {}", hour, e));
+    }
+    summary
+}
@@ -0,0 +1,117 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    time::Duration,
+};
+
+use chrono::Timelike;
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::time;
+
+use super::refiner::{row_at, Refined, SharedRows};
+
+const SEND_TIMEOUT_SECS: u64 = 10;
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EventSettings {
+    /// Every URL is sent every change
+    pub urls: Vec<String>,
+    /// Boolean fields to watch, like `i8h_low` or a band, every boolean field if empty
+    pub fields: Vec<String>,
+}
+
+/// The watched boolean fields of `refined` by name
+fn flags(refined: &Refined, fields: &[String]) -> Result<BTreeMap<String, bool>, String> {
+    let values = match serde_json::to_value(refined).map_err(|e| e.to_string())? {
+        Value::Object(values) => values,
+        _ => return Err("Refined did not serialize into an object".to_string()),
+    };
+    Ok(values
+        .into_iter()
+        .filter(|(field, _)| fields.is_empty() || fields.contains(field))
+        .filter_map(|(field, value)| value.as_bool().map(|value| (field, value)))
+        .collect())
+}
+
+/// The fields whose value differs from `previous`. A field missing from either, like an unset
+/// `charge_now`, counts as false
+fn changes(
+    previous: &BTreeMap<String, bool>,
+    current: &BTreeMap<String, bool>,
+) -> BTreeMap<String, Value> {
+    let fields: BTreeSet<&String> = previous.keys().chain(current.keys()).collect();
+    fields
+        .into_iter()
+        .filter_map(|field| {
+            let from = previous.get(field).copied().unwrap_or(false);
+            let to = current.get(field).copied().unwrap_or(false);
+            (from != to).then(|| (field.clone(), json!({ "from": from, "to": to })))
+        })
+        .collect()
+}
+
+/// Compares the current hour's flags with the previous hour's at the start of every hour and
+/// posts the changes to every URL
+pub async fn run(settings: EventSettings, rows: SharedRows, tz: Tz) {
+    let http = reqwest::Client::builder()
+        .timeout(Duration::from_secs(SEND_TIMEOUT_SECS))
+        .build()
+        .unwrap_or_default();
+    let mut previous: Option<BTreeMap<String, bool>> = None;
+    loop {
+        let now = chrono::Utc::now().with_timezone(&tz);
+        let payload = {
+            let rows = rows.read().await;
+            match row_at(&rows, now) {
+                Some(refined) => match flags(refined, &settings.fields) {
+                    Ok(current) => {
+                        let changed = previous
+                            .as_ref()
+                            .map(|previous| changes(previous, &current))
+                            .unwrap_or_default();
+                        let payload = (!changed.is_empty()).then(|| {
+                            json!({
+                                "time": refined.time,
+                                "date": refined.date,
+                                "hour": refined.hour,
+                                "area": refined.area,
+                                "price": refined.pris_time,
+                                "changed": changed,
+                                "flags": &current,
+                            })
+                        });
+                        previous = Some(current);
+                        payload
+                    }
+                    Err(e) => {
+                        tracing::error!("Failed to read the current flags: {}", e);
+                        None
+                    }
+                },
+                None => {
+                    tracing::debug!("No refined row for hour {} yet", now.hour());
+                    None
+                }
+            }
+        };
+
+        if let Some(payload) = payload {
+            for url in &settings.urls {
+                let result = http
+                    .post(url)
+                    .json(&payload)
+                    .send()
+                    .await
+                    .and_then(|response| response.error_for_status());
+                if let Err(e) = result {
+                    tracing::warn!("Failed to post flag changes to {}: {}", url, e);
+                }
+            }
+        }
+
+        let seconds_into_hour = (now.minute() * 60 + now.second()) as u64;
+        time::sleep(Duration::from_secs(3600 - seconds_into_hour)).await;
+    }
+}
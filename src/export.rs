@@ -0,0 +1,95 @@
+use std::{collections::BTreeSet, fmt::Display, str::FromStr};
+
+use serde_json::{Map, Value};
+
+use super::{
+    error::RefinerError,
+    refiner::{Refined, ROW_KEYS},
+};
+
+/// File format refined rows are exported in
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// One line per row with a header of every field, fields a row doesn't have are empty
+    Csv,
+    /// An array of rows, with the fields they are serialized with everywhere else
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "csv" => Ok(Format::Csv),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("Unknown format {}, expected csv or json", format)),
+        }
+    }
+}
+
+impl Display for Format {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Format::Csv => write!(f, "csv"),
+            Format::Json => write!(f, "json"),
+        }
+    }
+}
+
+/// A field as a CSV cell, quoted if it has a comma, quote or line break
+fn cell(value: &Value) -> String {
+    let text = match value {
+        Value::Null => String::new(),
+        Value::String(text) => text.clone(),
+        value => value.to_string(),
+    };
+    if text.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text
+    }
+}
+
+/// Renders rows as CSV. The row keys come first, then every field any row has in alphabetical
+/// order, so exports of rows refined with other bands or rules line up
+fn csv(rows: &[Refined]) -> Result<String, RefinerError> {
+    let rows = rows
+        .iter()
+        .map(|row| match serde_json::to_value(row) {
+            Ok(Value::Object(fields)) => Ok(fields),
+            Ok(_) => Ok(Map::new()),
+            Err(e) => Err(RefinerError::Write(e.to_string())),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let fields: BTreeSet<&String> = rows
+        .iter()
+        .flat_map(|row| row.keys())
+        .filter(|name| !ROW_KEYS.contains(&name.as_str()))
+        .collect();
+    let mut columns: Vec<&str> = ROW_KEYS.to_vec();
+    columns.extend(fields.iter().map(|name| name.as_str()));
+
+    let mut csv = columns.join(",");
+    csv.push('\n');
+    for row in &rows {
+        let cells: Vec<String> = columns
+            .iter()
+            .map(|name| row.get(*name).map(cell).unwrap_or_default())
+            .collect();
+        csv.push_str(&cells.join(","));
+        csv.push('\n');
+    }
+    Ok(csv)
+}
+
+/// Renders rows in `format`
+pub fn render(rows: &[Refined], format: Format) -> Result<String, RefinerError> {
+    match format {
+        Format::Csv => csv(rows),
+        Format::Json => {
+            serde_json::to_string_pretty(rows).map_err(|e| RefinerError::Write(e.to_string()))
+        }
+    }
+}
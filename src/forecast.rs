@@ -0,0 +1,86 @@
+use chrono::{NaiveDate, TimeZone};
+use chrono_tz::Tz;
+use tracing::instrument;
+
+use super::{
+    config::{Area, Forecast, SourceConfig},
+    error::RefinerError,
+    refiner::{average, PricePoint},
+    store::PriceStore,
+};
+
+/// Largest change between two days' averages a trend forecast carries on, either way
+const MAX_TREND: f64 = 2.0;
+
+/// `prices` of another day at the same local times of `date`, times `date` doesn't have are
+/// left out
+fn moved(prices: &[PricePoint], date: NaiveDate, tz: Tz) -> Vec<PricePoint> {
+    let mut moved: Vec<PricePoint> = prices
+        .iter()
+        .filter_map(|price| {
+            let start = tz
+                .from_local_datetime(&date.and_time(price.start.time()))
+                .earliest()?;
+            Some(PricePoint {
+                start,
+                value: price.value,
+            })
+        })
+        .collect();
+    // The hour repeated when daylight saving time ends is moved onto the same time twice
+    moved.dedup_by_key(|price| price.start);
+    moved
+}
+
+/// How much the average changed from `before` to `after`, 1 unless both averages are positive
+fn trend_factor(before: &[PricePoint], after: &[PricePoint]) -> Result<f64, RefinerError> {
+    let (before, after) = (average(before)?, average(after)?);
+    if before <= 0.0 || after <= 0.0 {
+        return Ok(1.0);
+    }
+    Ok((after / before).clamp(1.0 / MAX_TREND, MAX_TREND))
+}
+
+/// A forecast of the prices of `date` from the days before it. Missing data if the days it is
+/// made from have no prices either
+#[instrument(skip(store, source))]
+pub async fn forecast(
+    date: NaiveDate,
+    method: Forecast,
+    store: &dyn PriceStore,
+    tz: Tz,
+    source: &SourceConfig,
+    area: Option<&Area>,
+) -> Result<Vec<PricePoint>, RefinerError> {
+    let prices = match method {
+        Forecast::Off => Vec::new(),
+        Forecast::LastWeek => {
+            let week_before = date - chrono::Duration::days(7);
+            let prices = store.read_prices(week_before, tz, source, area).await?;
+            moved(&prices, date, tz)
+        }
+        Forecast::Trend => {
+            let day_before = date.pred();
+            let prices = store.read_prices(day_before, tz, source, area).await?;
+            let factor = match store.read_prices(day_before.pred(), tz, source, area).await {
+                Ok(before) => trend_factor(&before, &prices)?,
+                Err(RefinerError::MissingData(_)) => 1.0,
+                Err(e) => return Err(e),
+            };
+            moved(&prices, date, tz)
+                .into_iter()
+                .map(|price| PricePoint {
+                    start: price.start,
+                    value: price.value * factor,
+                })
+                .collect()
+        }
+    };
+    if prices.is_empty() {
+        return Err(RefinerError::MissingData(format!(
+            "No prices to forecast {} from",
+            date
+        )));
+    }
+    Ok(prices)
+}
@@ -0,0 +1,192 @@
+use std::time::Duration;
+
+use chrono::{DateTime, NaiveDate};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use super::refiner::{cheapest_window, points_per_hour, PricePoint, Refined};
+
+const DEFAULT_TAG: &str = "tibber_refiner";
+const DEFAULT_WINDOW_HOURS: usize = 3;
+const SEND_TIMEOUT_SECS: u64 = 10;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GrafanaSettings {
+    /// Base URL of Grafana, like http://grafana:3000
+    pub url: String,
+    /// Service account token allowed to write annotations
+    pub token: String,
+    /// Dashboard the annotations belong to, organization wide if unset so any dashboard can
+    /// show them by tag
+    pub dashboard_uid: Option<String>,
+    /// Tags of every annotation, for dashboards to select them by
+    pub tags: Vec<String>,
+    /// Length of the cheapest run of hours that is annotated
+    pub window_hours: usize,
+}
+
+impl Default for GrafanaSettings {
+    fn default() -> Self {
+        GrafanaSettings {
+            url: String::new(),
+            token: String::new(),
+            dashboard_uid: None,
+            tags: vec![DEFAULT_TAG.to_string()],
+            window_hours: DEFAULT_WINDOW_HOURS,
+        }
+    }
+}
+
+/// A point or, with an end, a region on the dashboards' time axis
+#[derive(Clone, Debug)]
+pub struct Annotation {
+    pub time: DateTime<Tz>,
+    pub time_end: Option<DateTime<Tz>>,
+    pub text: String,
+    /// Tags besides the configured ones
+    pub tags: Vec<String>,
+}
+
+/// When the price starting at `index` ends, a price's length after it starts
+fn end_of(prices: &[PricePoint], index: usize) -> DateTime<Tz> {
+    let length = chrono::Duration::minutes(60 / points_per_hour(prices).max(1) as i64);
+    prices[index].start + length
+}
+
+/// The notable events of a day: its priciest and cheapest prices, the cheapest run of
+/// `window_hours` and every price flagged as an anomaly
+pub fn annotations(
+    prices: &[PricePoint],
+    rows: &[Refined],
+    currency: &str,
+    window_hours: usize,
+) -> Vec<Annotation> {
+    let by_value = |a: &(usize, &PricePoint), b: &(usize, &PricePoint)| {
+        a.1.value
+            .partial_cmp(&b.1.value)
+            .unwrap_or(std::cmp::Ordering::Equal)
+    };
+    let mut annotations = Vec::new();
+    if let Some((index, price)) = prices.iter().enumerate().max_by(by_value) {
+        annotations.push(Annotation {
+            time: price.start,
+            time_end: Some(end_of(prices, index)),
+            text: format!("Priciest hour: {:.4} {}/kWh", price.value, currency),
+            tags: vec!["max".to_string()],
+        });
+    }
+    if let Some((index, price)) = prices.iter().enumerate().min_by(by_value) {
+        annotations.push(Annotation {
+            time: price.start,
+            time_end: Some(end_of(prices, index)),
+            text: format!("Cheapest hour: {:.4} {}/kWh", price.value, currency),
+            tags: vec!["min".to_string()],
+        });
+    }
+    if let Some((start, average)) = cheapest_window(prices, window_hours) {
+        let last = start + window_hours * points_per_hour(prices) - 1;
+        annotations.push(Annotation {
+            time: prices[start].start,
+            time_end: Some(end_of(prices, last)),
+            text: format!(
+                "Cheapest {} hours: {:.4} {}/kWh on average",
+                window_hours, average, currency
+            ),
+            tags: vec!["window".to_string()],
+        });
+    }
+    for row in rows.iter().filter(|row| row.anomaly) {
+        annotations.push(Annotation {
+            time: row.time,
+            time_end: None,
+            text: format!(
+                "Anomalous price: {:.4} {}/kWh, check the source",
+                row.pris_time, currency
+            ),
+            tags: vec!["anomaly".to_string()],
+        });
+    }
+    annotations
+}
+
+/// Replaces the annotations of `date` and `area` with `annotations`, logging a failure
+pub async fn post(
+    settings: &GrafanaSettings,
+    date: NaiveDate,
+    area: Option<&str>,
+    annotations: &[Annotation],
+) {
+    let http = reqwest::Client::builder()
+        .timeout(Duration::from_secs(SEND_TIMEOUT_SECS))
+        .build()
+        .unwrap_or_default();
+    let url = format!("{}/api/annotations", settings.url.trim_end_matches('/'));
+
+    // Tags that tell the annotations of this day apart, so a day refined again replaces its own
+    let mut day_tags = settings.tags.clone();
+    day_tags.push(format!("date:{}", date));
+    if let Some(area) = area {
+        day_tags.push(format!("area:{}", area));
+    }
+
+    let mut query: Vec<(&str, String)> = day_tags.iter().map(|tag| ("tags", tag.clone())).collect();
+    query.push(("type", "annotation".to_string()));
+    query.push(("matchAny", "false".to_string()));
+    let existing = http
+        .get(&url)
+        .bearer_auth(&settings.token)
+        .query(&query)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status());
+    let existing: Vec<Value> = match existing {
+        Ok(response) => response.json().await.unwrap_or_default(),
+        Err(e) => {
+            tracing::warn!("Failed to read Grafana annotations of {}: {}", date, e);
+            return;
+        }
+    };
+    for id in existing
+        .iter()
+        .filter_map(|annotation| annotation["id"].as_u64())
+    {
+        let deleted = http
+            .delete(format!("{}/{}", url, id))
+            .bearer_auth(&settings.token)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        if let Err(e) = deleted {
+            tracing::warn!("Failed to delete Grafana annotation {}: {}", id, e);
+        }
+    }
+
+    for annotation in annotations {
+        let mut tags = day_tags.clone();
+        tags.extend(annotation.tags.iter().cloned());
+        let mut body = json!({
+            "time": annotation.time.timestamp_millis(),
+            "tags": tags,
+            "text": annotation.text,
+        });
+        if let Some(end) = annotation.time_end {
+            body["timeEnd"] = json!(end.timestamp_millis());
+        }
+        if let Some(uid) = &settings.dashboard_uid {
+            body["dashboardUID"] = json!(uid);
+        }
+        let posted = http
+            .post(&url)
+            .bearer_auth(&settings.token)
+            .json(&body)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        if let Err(e) = posted {
+            tracing::warn!("Failed to post Grafana annotation: {}", e);
+        }
+    }
+    tracing::debug!("Annotated {} events of {}", annotations.len(), date);
+}
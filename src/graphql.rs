@@ -0,0 +1,223 @@
+use async_graphql::{
+    http::GraphiQLSource, EmptyMutation, EmptySubscription, Object, Schema, SimpleObject,
+};
+use async_graphql_axum::{GraphQLRequest, GraphQLResponse};
+use axum::{response::Html, Extension};
+use chrono::{Duration, NaiveDate, Utc};
+use chrono_tz::Tz;
+
+use super::refiner::{
+    cheapest_window, points_per_hour, row_at, Day, PricePoint, Refined, SharedRows,
+};
+
+pub type PriceSchema = Schema<Query, EmptyMutation, EmptySubscription>;
+
+/// A price like Tibber's API has it, with what the refiner adds
+#[derive(Clone, Debug, SimpleObject)]
+pub struct Price {
+    /// Energy with costs and grid tariff when they are configured
+    pub total: f64,
+    /// The spot price
+    pub energy: f64,
+    /// Everything on top of the spot price, `total` less `energy`
+    pub tax: f64,
+    pub starts_at: String,
+    /// VERY_CHEAP through VERY_EXPENSIVE
+    pub level: String,
+    pub currency: String,
+    pub hour: u32,
+    /// Name of the configured area, unset with a single area
+    pub area: Option<String>,
+    /// The price relative to the day's average
+    pub ratio: f64,
+    /// Percent of the day's other prices that are cheaper
+    pub percentile: f64,
+    /// 1 for the cheapest price of the day
+    pub rank: u32,
+    pub negative: bool,
+    pub anomaly: bool,
+    /// Whether the day's prices are forecast rather than published
+    pub forecast: bool,
+    /// Bands the price is within
+    pub bands: Vec<String>,
+    /// Rules that are true
+    pub rules: Vec<String>,
+}
+
+impl From<&Refined> for Price {
+    fn from(row: &Refined) -> Self {
+        let total = row
+            .pris_total
+            .or(row.pris_effektiv)
+            .unwrap_or(row.pris_time);
+        let set = |flags: &std::collections::BTreeMap<String, bool>| {
+            flags
+                .iter()
+                .filter(|(_, set)| **set)
+                .map(|(name, _)| name.clone())
+                .collect()
+        };
+        Price {
+            total,
+            energy: row.pris_time,
+            tax: total - row.pris_time,
+            starts_at: row.time.to_rfc3339(),
+            level: row.price_level.clone(),
+            currency: row.currency.clone(),
+            hour: row.hour,
+            area: row.area.clone(),
+            ratio: row.pris_forhold_24,
+            percentile: row.pris_persentil,
+            rank: row.pris_rang,
+            negative: row.negativ_pris,
+            anomaly: row.anomaly,
+            forecast: row.forecast,
+            bands: set(&row.bands),
+            rules: set(&row.rules),
+        }
+    }
+}
+
+/// The cheapest consecutive prices of a length
+#[derive(Clone, Debug, SimpleObject)]
+pub struct Window {
+    pub starts_at: String,
+    pub ends_at: String,
+    /// Average spot price of the window
+    pub average: f64,
+    pub prices: Vec<Price>,
+}
+
+/// The shape of Tibber's `priceInfo`
+#[derive(Clone, Debug, SimpleObject)]
+pub struct PriceInfo {
+    pub current: Option<Price>,
+    pub today: Vec<Price>,
+    pub tomorrow: Vec<Price>,
+}
+
+pub struct Query {
+    today: SharedRows,
+    tomorrow: SharedRows,
+    tz: Tz,
+}
+
+impl Query {
+    /// The rows of `date`, from today's rows or once midnight has passed from the rows refined
+    /// ahead of time until the daily pass has run
+    async fn rows_of(&self, date: NaiveDate) -> Vec<Refined> {
+        let date = date.to_string();
+        for rows in [&self.today, &self.tomorrow] {
+            let rows: Vec<Refined> = rows
+                .read()
+                .await
+                .iter()
+                .filter(|row| row.date == date)
+                .cloned()
+                .collect();
+            if !rows.is_empty() {
+                return rows;
+            }
+        }
+        Vec::new()
+    }
+
+    async fn today_rows(&self) -> Vec<Refined> {
+        self.rows_of(Day::Today.date(self.tz)).await
+    }
+
+    async fn tomorrow_rows(&self) -> Vec<Refined> {
+        self.rows_of(Day::Tomorrow.date(self.tz)).await
+    }
+}
+
+#[Object]
+impl Query {
+    /// Today's prices in time order
+    async fn today(&self) -> Vec<Price> {
+        self.today_rows().await.iter().map(Price::from).collect()
+    }
+
+    /// Tomorrow's prices in time order, empty until they are refined
+    async fn tomorrow(&self) -> Vec<Price> {
+        self.tomorrow_rows().await.iter().map(Price::from).collect()
+    }
+
+    /// The price now
+    async fn current(&self) -> Option<Price> {
+        row_at(&self.today_rows().await, Utc::now()).map(Price::from)
+    }
+
+    /// Today's price of hour `n` on the clock
+    async fn hour(&self, n: u32) -> Option<Price> {
+        let rows = self.today_rows().await;
+        rows.iter().find(|row| row.hour == n).map(Price::from)
+    }
+
+    /// The cheapest `hours` consecutive hours from now on, through tomorrow once it is refined
+    async fn cheapest_window(&self, hours: u32) -> Option<Window> {
+        let now = Utc::now();
+        let mut rows: Vec<Refined> = self.today_rows().await;
+        rows.extend(self.tomorrow_rows().await);
+        // From the price now on, of the first area like the rest of the API
+        let area = rows.first().and_then(|row| row.area.clone());
+        let current = row_at(&rows, now).map(|row| row.time);
+        rows.retain(|row| {
+            row.area == area && current.map_or(row.time >= now, |current| row.time >= current)
+        });
+
+        let prices: Vec<PricePoint> = rows
+            .iter()
+            .map(|row| PricePoint {
+                start: row.time,
+                value: row.pris_time,
+            })
+            .collect();
+        let per_hour = points_per_hour(&prices);
+        let (start, average) = cheapest_window(&prices, hours as usize)?;
+        let window = &rows[start..start + hours as usize * per_hour];
+        let last = window.last()?;
+        Some(Window {
+            starts_at: window.first()?.time.to_rfc3339(),
+            ends_at: (last.time + Duration::minutes(60 / per_hour as i64)).to_rfc3339(),
+            average,
+            prices: window.iter().map(Price::from).collect(),
+        })
+    }
+
+    /// Today's and tomorrow's prices and the price now, shaped like Tibber's `priceInfo`
+    async fn price_info(&self) -> PriceInfo {
+        let today = self.today_rows().await;
+        PriceInfo {
+            current: row_at(&today, Utc::now()).map(Price::from),
+            today: today.iter().map(Price::from).collect(),
+            tomorrow: self.tomorrow_rows().await.iter().map(Price::from).collect(),
+        }
+    }
+}
+
+/// The schema served on /graphql, resolving against the shared rows
+pub fn schema(today: SharedRows, tomorrow: SharedRows, tz: Tz) -> PriceSchema {
+    Schema::build(
+        Query {
+            today,
+            tomorrow,
+            tz,
+        },
+        EmptyMutation,
+        EmptySubscription,
+    )
+    .finish()
+}
+
+pub async fn handler(
+    Extension(schema): Extension<PriceSchema>,
+    request: GraphQLRequest,
+) -> GraphQLResponse {
+    schema.execute(request.into_inner()).await.into()
+}
+
+/// GraphiQL, for trying queries in a browser
+pub async fn playground() -> Html<String> {
+    Html(GraphiQLSource::build().endpoint("/graphql").finish())
+}
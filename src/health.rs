@@ -0,0 +1,49 @@
+use std::{fmt::Display, sync::Arc};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::sync::RwLock;
+
+/// How long the refiner may go without attempting a tick before it is considered wedged
+const MAX_SILENCE_HOURS: i64 = 26;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Health {
+    pub started: DateTime<Utc>,
+    pub last_tick: Option<DateTime<Utc>>,
+    pub last_tick_ok: Option<bool>,
+    pub last_error: Option<String>,
+}
+
+pub type SharedHealth = Arc<RwLock<Health>>;
+
+impl Health {
+    pub fn new() -> Health {
+        Health {
+            started: Utc::now(),
+            last_tick: None,
+            last_tick_ok: None,
+            last_error: None,
+        }
+    }
+
+    /// Records the outcome of a tick attempt
+    pub fn record<T, E: Display>(&mut self, result: &Result<T, E>) {
+        self.last_tick = Some(Utc::now());
+        self.last_tick_ok = Some(result.is_ok());
+        self.last_error = result.as_ref().err().map(|e| e.to_string());
+    }
+
+    /// Healthy unless the last tick failed or nothing has happened for too long
+    pub fn is_healthy(&self) -> bool {
+        let last_activity = self.last_tick.unwrap_or(self.started);
+        let silent_for = Utc::now().signed_duration_since(last_activity);
+        self.last_tick_ok != Some(false) && silent_for < chrono::Duration::hours(MAX_SILENCE_HOURS)
+    }
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Health::new()
+    }
+}
@@ -0,0 +1,88 @@
+use std::time::Duration;
+
+use chrono::{DateTime, NaiveDate, Utc};
+use influxdb::{InfluxDbWriteable, Timestamp, WriteQuery};
+use tracing::instrument;
+
+use super::{db::Db, error::RefinerError, refiner::Refined};
+
+const MEASUREMENT: &str = "refiner_runs";
+
+/// One run of a pass, every attempt of a tick until it succeeded or was given up on, so the
+/// refiner's own health can be graphed from the database it writes to
+#[derive(Clone, Debug)]
+pub struct Run {
+    /// When the run finished
+    pub time: DateTime<Utc>,
+    /// The pass that ran, like `daily` or `tomorrow`
+    pub pass: &'static str,
+    pub date: NaiveDate,
+    /// Measurement the prices were read from
+    pub source: String,
+    pub duration: Duration,
+    /// Rows written, of every area
+    pub hours: usize,
+    /// Attempts after the first
+    pub retries: u32,
+    /// Whether the rows are of a forecast
+    pub forecast: bool,
+    /// Why the run failed, unset if it succeeded
+    pub error: Option<String>,
+}
+
+impl Run {
+    /// A run that wrote `rows` after `attempts` attempts, failed with `error` if it was given up on
+    pub fn new(
+        pass: &'static str,
+        date: NaiveDate,
+        source: &str,
+        duration: Duration,
+        attempts: u32,
+        rows: &[Refined],
+        error: Option<&RefinerError>,
+    ) -> Run {
+        Run {
+            time: Utc::now(),
+            pass,
+            date,
+            source: source.to_string(),
+            duration,
+            hours: rows.len(),
+            retries: attempts.saturating_sub(1),
+            forecast: rows.iter().any(|row| row.forecast),
+            error: error.map(|e| e.to_string()),
+        }
+    }
+
+    /// `pass`, `date`, `source` and `version` are tags, everything else is a field
+    pub fn to_query(&self) -> WriteQuery {
+        let mut query = Timestamp::from(self.time)
+            .into_query(MEASUREMENT)
+            .add_tag("pass", self.pass)
+            .add_tag("date", self.date.to_string())
+            .add_tag("source", self.source.clone())
+            .add_tag("version", env!("CARGO_PKG_VERSION"))
+            .add_field("duration_secs", self.duration.as_secs_f64())
+            .add_field("hours", self.hours as u64)
+            .add_field("retries", self.retries)
+            .add_field("forecast", self.forecast)
+            .add_field("ok", self.error.is_none());
+        if let Some(error) = &self.error {
+            query = query.add_field("error", error.clone());
+        }
+        query
+    }
+}
+
+/// Writes the run, failures are logged rather than returned since the run itself is done
+#[instrument(skip_all, fields(pass = run.pass, date = %run.date))]
+pub async fn write_run(run: &Run, db: &Db) {
+    if let Err(e) = db.write(vec![run.to_query()]).await {
+        tracing::warn!(
+            "Failed to write the {} run of {}: {}",
+            run.pass,
+            run.date,
+            e
+        );
+    }
+}
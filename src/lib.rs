@@ -1,2 +1,72 @@
+//! Refines day-ahead electricity prices into hourly rows of ratios, levels and flags.
+//!
+//! The analysis builds without any optional feature, for embedding it in another program.
+//! Everything that talks to the outside world is behind a feature, all enabled by default:
+//!
+//! - `influx`: InfluxDB as the price store and the sink of the refined rows and other outputs
+//! - `mqtt`: publishing the current row and Home Assistant discovery over MQTT
+//! - `http-api`: the HTTP API serving rows, health and metrics
+//! - `graphql`: a GraphQL endpoint on the HTTP API, shaped like Tibber's API
+//! - `live`: writing Tibber Pulse live measurements
+//! - `daemon`: the scheduler, notifications and webhooks the binary runs
+//! - `systemd`: readiness, status and watchdog pings when the daemon runs under systemd
+//!
+//! `sentry`, reporting passes that give up and panics, is not enabled by default.
+//!
+//! Without `influx` prices are read through a `PriceStore`, like the `MemoryStore`.
+
+#[cfg(feature = "http-api")]
+pub mod api;
+pub mod cache;
+pub mod calendar;
+pub mod charging;
+pub mod config;
+#[cfg(feature = "influx")]
+pub mod db;
+pub mod error;
+#[cfg(feature = "daemon")]
+pub mod events;
+pub mod export;
+pub mod forecast;
+#[cfg(feature = "daemon")]
+pub mod grafana;
+#[cfg(feature = "graphql")]
+pub mod graphql;
+pub mod health;
+#[cfg(feature = "influx")]
+pub mod heartbeat;
+#[cfg(feature = "live")]
+pub mod live;
+pub mod metrics;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+#[cfg(feature = "daemon")]
+pub mod notify;
+pub mod optimizer;
+pub mod refiner;
+#[cfg(feature = "sentry")]
+pub mod reporting;
+#[cfg(feature = "influx")]
+pub mod rollup;
+pub mod rules;
+#[cfg(feature = "daemon")]
 pub mod run;
-pub mod refiner;
\ No newline at end of file
+pub mod show;
+pub mod simulate;
+pub mod sink;
+#[cfg(feature = "daemon")]
+pub mod solar;
+#[cfg(feature = "daemon")]
+pub mod spool;
+pub mod store;
+pub mod summary;
+#[cfg(feature = "systemd")]
+pub mod systemd;
+#[cfg(feature = "daemon")]
+pub mod weather;
+
+pub use config::{Config, RefinerConfig};
+pub use error::{RefinerError, StartupError};
+pub use refiner::{refine, Context, Day, PriceDay, PricePoint, Refined};
+pub use store::{MemoryStore, PriceStore};
+pub use summary::{summarize, Summary};
@@ -0,0 +1,202 @@
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use futures::{SinkExt, StreamExt};
+use influxdb::{InfluxDbWriteable, Timestamp};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::time;
+use tokio_tungstenite::tungstenite::{
+    client::IntoClientRequest, http::HeaderValue, protocol::Message,
+};
+
+use super::{
+    db::Db,
+    refiner::{row_at, SharedRows},
+};
+
+const DEFAULT_LIVE_MEASUREMENT: &str = "live";
+const DEFAULT_WRITE_INTERVAL_SECS: u64 = 10;
+const TIBBER_API: &str = "https://api.tibber.com/v1-beta/gql";
+const MAX_BACKOFF_SECS: u64 = 300;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LiveSettings {
+    /// Tibber API token
+    pub token: String,
+    /// Id of the home with the Pulse
+    pub home_id: String,
+    pub measurement: String,
+    /// Least time between two written points, Pulse sends a measurement every few seconds
+    pub write_interval_secs: u64,
+}
+
+impl Default for LiveSettings {
+    fn default() -> Self {
+        LiveSettings {
+            token: String::new(),
+            home_id: String::new(),
+            measurement: DEFAULT_LIVE_MEASUREMENT.to_string(),
+            write_interval_secs: DEFAULT_WRITE_INTERVAL_SECS,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LiveMeasurement {
+    timestamp: DateTime<Utc>,
+    /// Watts drawn
+    power: f64,
+    /// kWh used since midnight
+    accumulated_consumption: Option<f64>,
+    /// Cost since midnight
+    accumulated_cost: Option<f64>,
+    /// Watts produced
+    power_production: Option<f64>,
+}
+
+/// Asks the API where live measurements are subscribed to
+async fn subscription_url(settings: &LiveSettings) -> Result<String, String> {
+    let response: Value = reqwest::Client::new()
+        .post(TIBBER_API)
+        .bearer_auth(&settings.token)
+        .json(&json!({ "query": "{ viewer { websocketSubscriptionUrl } }" }))
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| e.to_string())?
+        .json()
+        .await
+        .map_err(|e| e.to_string())?;
+    response["data"]["viewer"]["websocketSubscriptionUrl"]
+        .as_str()
+        .map(str::to_string)
+        .ok_or_else(|| format!("No websocket subscription URL in {}", response))
+}
+
+/// Subscribes to the live measurements of the home and writes them until the connection drops
+async fn subscribe(settings: &LiveSettings, db: &Db, rows: &SharedRows) -> Result<(), String> {
+    let url = subscription_url(settings).await?;
+    let mut request = url.into_client_request().map_err(|e| e.to_string())?;
+    let headers = request.headers_mut();
+    headers.insert(
+        "Sec-WebSocket-Protocol",
+        HeaderValue::from_static("graphql-transport-ws"),
+    );
+    let user_agent = format!("tibber_refiner/{}", env!("CARGO_PKG_VERSION"));
+    headers.insert(
+        "User-Agent",
+        HeaderValue::from_str(&user_agent).map_err(|e| e.to_string())?,
+    );
+
+    let (stream, _) = tokio_tungstenite::connect_async(request)
+        .await
+        .map_err(|e| e.to_string())?;
+    let (mut sink, mut stream) = stream.split();
+
+    let init = json!({ "type": "connection_init", "payload": { "token": settings.token } });
+    sink.send(Message::Text(init.to_string()))
+        .await
+        .map_err(|e| e.to_string())?;
+    let query = format!(
+        "subscription {{ liveMeasurement(homeId: \"{}\") {{ timestamp power \
+         accumulatedConsumption accumulatedCost powerProduction }} }}",
+        settings.home_id
+    );
+    let subscribe = json!({ "id": "1", "type": "subscribe", "payload": { "query": query } });
+
+    let interval = Duration::from_secs(settings.write_interval_secs);
+    let mut last_write: Option<Instant> = None;
+    while let Some(message) = stream.next().await {
+        let text = match message.map_err(|e| e.to_string())? {
+            Message::Text(text) => text,
+            Message::Close(frame) => return Err(format!("Closed by Tibber: {:?}", frame)),
+            _ => continue,
+        };
+        let message: Value = serde_json::from_str(&text).map_err(|e| e.to_string())?;
+        match message["type"].as_str() {
+            Some("connection_ack") => {
+                tracing::info!("Subscribed to live measurements of {}", settings.home_id);
+                sink.send(Message::Text(subscribe.to_string()))
+                    .await
+                    .map_err(|e| e.to_string())?;
+            }
+            Some("ping") => {
+                let pong = json!({ "type": "pong" });
+                sink.send(Message::Text(pong.to_string()))
+                    .await
+                    .map_err(|e| e.to_string())?;
+            }
+            Some("next") => {
+                if last_write.map_or(false, |last| last.elapsed() < interval) {
+                    continue;
+                }
+                let measurement: LiveMeasurement =
+                    serde_json::from_value(message["payload"]["data"]["liveMeasurement"].clone())
+                        .map_err(|e| e.to_string())?;
+                write(&measurement, settings, db, rows).await;
+                last_write = Some(Instant::now());
+            }
+            Some("error") | Some("complete") => {
+                return Err(format!("Subscription ended: {}", message["payload"]))
+            }
+            _ => tracing::debug!("Ignoring live message {}", text),
+        }
+    }
+    Err("Connection closed".to_string())
+}
+
+/// Writes the measurement with the current price, `kostnad_naa` is the cost per hour at the
+/// current power and `forbruk_dyr_time` whether power is drawn in an expensive hour
+async fn write(measurement: &LiveMeasurement, settings: &LiveSettings, db: &Db, rows: &SharedRows) {
+    let mut query = Timestamp::from(measurement.timestamp)
+        .into_query(settings.measurement.as_str())
+        .add_tag("home_id", settings.home_id.clone())
+        .add_field("power", measurement.power);
+    let optional = [
+        (
+            "accumulated_consumption",
+            measurement.accumulated_consumption,
+        ),
+        ("accumulated_cost", measurement.accumulated_cost),
+        ("power_production", measurement.power_production),
+    ];
+    for (name, value) in optional {
+        if let Some(value) = value {
+            query = query.add_field(name, value);
+        }
+    }
+    if let Some(refined) = row_at(&rows.read().await, measurement.timestamp) {
+        let expensive = matches!(refined.price_level.as_str(), "EXPENSIVE" | "VERY_EXPENSIVE");
+        query = query
+            .add_field("pris_time", refined.pris_time)
+            .add_field(
+                "kostnad_naa",
+                measurement.power / 1000.0 * refined.pris_time,
+            )
+            .add_field("forbruk_dyr_time", expensive && measurement.power > 0.0);
+    }
+    if let Err(e) = db.write(vec![query]).await {
+        tracing::warn!("Failed to write live measurement: {}", e);
+    }
+}
+
+/// Keeps a subscription to the live measurements of the home, reconnecting with backoff
+pub async fn run(settings: LiveSettings, db: Db, rows: SharedRows) {
+    let mut backoff = 1;
+    loop {
+        let started = Instant::now();
+        if let Err(e) = subscribe(&settings, &db, &rows).await {
+            tracing::warn!("Live measurements stopped: {}", e);
+        }
+        // A subscription that lasted a while was healthy, start over with a short backoff
+        if started.elapsed() > Duration::from_secs(MAX_BACKOFF_SECS) {
+            backoff = 1;
+        }
+        tracing::debug!("Reconnecting to live measurements in {} seconds", backoff);
+        time::sleep(Duration::from_secs(backoff)).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF_SECS);
+    }
+}
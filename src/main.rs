@@ -1,33 +1,523 @@
-use tibber_refiner::run::{get_db_info, get_instant, get_logger, get_retries, tick};
-use tokio::time;
+use std::{path::PathBuf, sync::Arc, time::Duration};
 
-#[tokio::main]
-async fn main() {
-    let (subscriber, _guard) = get_logger();
+use chrono::{NaiveDate, Utc};
+use clap::{Parser, Subcommand};
+use futures::StreamExt;
+#[cfg(feature = "sentry")]
+use tibber_refiner::reporting;
+use tibber_refiner::{
+    config::Config,
+    db::Db,
+    export::{self, Format},
+    refiner::{measurement_exists, Day},
+    run::{backfill, daemon, get_logger, rollup_pass, tick, LevelHandle, Reload, TickOptions},
+    show::table,
+    simulate::simulate,
+    StartupError,
+};
+use tracing_appender::non_blocking::WorkerGuard;
+
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    /// Path to a TOML config file
+    #[arg(long, global = true, env = "CONFIG_PATH")]
+    config: Option<PathBuf>,
+
+    /// Print the refined rows as line protocol instead of writing them [env: DRY_RUN]
+    #[arg(long, global = true)]
+    dry_run: bool,
+
+    /// Write dates that have already been refined instead of skipping them
+    #[arg(long, global = true)]
+    force: bool,
+
+    /// Print the default configuration as TOML and exit, a starting point for a config file
+    #[arg(long)]
+    print_config: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Refine today's prices every day at the configured update time (default)
+    Run,
+    /// Refine a single day once and exit
+    Refine {
+        /// Date to refine (YYYY-MM-DD), defaults to today
+        #[arg(long)]
+        date: Option<NaiveDate>,
+    },
+    /// Refine every date in a range and exit
+    Backfill {
+        /// First date to refine (YYYY-MM-DD)
+        #[arg(long)]
+        from: NaiveDate,
+        /// Last date to refine, inclusive (YYYY-MM-DD)
+        #[arg(long)]
+        to: NaiveDate,
+        /// Number of dates refined at the same time
+        #[arg(long, default_value_t = 4)]
+        concurrency: usize,
+    },
+    /// Roll up the week and month of a day into refined_weekly and refined_monthly and exit
+    Rollup {
+        /// Last date to include (YYYY-MM-DD), defaults to today
+        #[arg(long)]
+        date: Option<NaiveDate>,
+    },
+    /// Report what running the configured appliances in their cheapest hours would have saved
+    /// over a range of days, without writing anything
+    Simulate {
+        /// First date to simulate (YYYY-MM-DD)
+        #[arg(long)]
+        from: NaiveDate,
+        /// Last date to simulate, inclusive (YYYY-MM-DD)
+        #[arg(long)]
+        to: NaiveDate,
+        /// Energy the household uses a day, evenly through the day, for days without stored
+        /// consumption
+        #[arg(long)]
+        kwh_per_day: Option<f64>,
+    },
+    /// Refine a day without writing anything and print a table of its rows, with the current
+    /// hour marked and the flags that are set highlighted. Set NO_COLOR for a plain table
+    Show {
+        /// Date to show (YYYY-MM-DD), defaults to today
+        #[arg(long)]
+        date: Option<NaiveDate>,
+        /// Name of the configured area to show, defaults to the first
+        #[arg(long)]
+        area: Option<String>,
+    },
+    /// Refine a day or a range of days without writing anything and save the rows to a file
+    Export {
+        /// Date to export (YYYY-MM-DD), defaults to today unless a range is given
+        #[arg(long, conflicts_with_all = ["from", "to"])]
+        date: Option<NaiveDate>,
+        /// First date of a range to export (YYYY-MM-DD)
+        #[arg(long, requires = "to")]
+        from: Option<NaiveDate>,
+        /// Last date of a range to export, inclusive (YYYY-MM-DD)
+        #[arg(long, requires = "from")]
+        to: Option<NaiveDate>,
+        /// csv or json
+        #[arg(long, default_value_t = Format::Csv)]
+        format: Format,
+        /// File to write, standard output if unset
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+    /// Validate the configuration, check that InfluxDB is reachable and has the source
+    /// measurements, print the effective configuration with secrets redacted and exit
+    CheckConfig,
+}
+
+/// What the binary runs with once it has started
+struct App {
+    config: Config,
+    /// Shared by the command, so every query goes through one pool of connections
+    db: Db,
+    options: TickOptions,
+    command: Command,
+    /// The config file, read again on SIGHUP
+    config_path: Option<PathBuf>,
+    /// Set by `--dry-run`, which a reloaded config can't turn off
+    dry_run: bool,
+    level: LevelHandle,
+    /// Flushes the log file when dropped
+    _guard: WorkerGuard,
+    /// Sends the queued error reports when dropped
+    #[cfg(feature = "sentry")]
+    _reporting: Option<sentry::ClientInitGuard>,
+}
+
+/// Loads and validates the config, sets up logging and, before running the daemon, waits for
+/// InfluxDB with the configured retries
+async fn init(cli: Cli) -> Result<App, StartupError> {
+    let mut config = Config::load(cli.config.clone()).map_err(StartupError::Config)?;
+
+    config.dry_run |= cli.dry_run;
+    let options = TickOptions {
+        dry_run: config.dry_run,
+        force: cli.force,
+        discard: false,
+        grafana: config.grafana.clone(),
+        weather: config.weather.clone(),
+        deadline: Some(Duration::from_secs(config.schedule.tick_timeout_secs)),
+        tz: config.timezone,
+        refiner: Arc::new(config.refiner.clone()),
+    };
+
+    let (subscriber, guard, level) = get_logger(&config.logging, config.otel.endpoint.as_deref());
     tracing::subscriber::set_global_default(subscriber)
-        .expect("Failed to set global default subscriber");
+        .map_err(|e| StartupError::Logging(e.to_string()))?;
     tracing::trace!("Log setup complete");
+    #[cfg(feature = "sentry")]
+    let reporting = config.reporting.as_ref().map(reporting::init);
+
+    tracing::info!("TIMEZONE: {}", config.timezone);
+    tracing::info!("INFLUXDB_ADDR: {}", config.influxdb.addr);
+    tracing::info!("INFLUXDB_DB_NAME: {}", config.influxdb.db_name);
+    tracing::info!("UPDATE_TIME: {}", config.schedule.update_time);
+    tracing::info!("RETRIES: {}", config.schedule.retries);
+
+    let command = cli.command.unwrap_or(Command::Run);
+    let db = Db::new(&config.influxdb);
+    if matches!(command, Command::Run) {
+        wait_for_db(&db, &config).await?;
+    }
+    let writes = matches!(
+        command,
+        Command::Run | Command::Refine { .. } | Command::Backfill { .. } | Command::Rollup { .. }
+    );
+    if writes && !config.dry_run {
+        if let Err(e) = db.create_retention_policy().await {
+            tracing::error!("Failed to create retention policy: {}", e);
+        }
+    }
 
-    let (db_addr, db_name) = get_db_info();
-    let retries = get_retries();
-
-    loop {
-        let instant = get_instant();
-        time::sleep_until(instant).await;
-        for i in 0..retries {
-            let res = tick(db_addr.clone(), db_name.clone()).await;
-            if res.is_ok() {
-                break;
-            } else {
-                tracing::warn!("Failed attempt {} to tick: {}", i, res.err().unwrap());
+    Ok(App {
+        config,
+        db,
+        options,
+        command,
+        config_path: cli.config,
+        dry_run: cli.dry_run,
+        level,
+        _guard: guard,
+        #[cfg(feature = "sentry")]
+        _reporting: reporting,
+    })
+}
+
+/// Pings InfluxDB until it answers, backing off exponentially between the configured retries
+async fn wait_for_db(db: &Db, config: &Config) -> Result<(), StartupError> {
+    let retries = config.schedule.retries;
+    for i in 0..retries {
+        match db.ping().await {
+            Ok((build, version)) => {
+                tracing::info!("Connected to InfluxDB {} {}", build, version);
+                return Ok(());
+            }
+            Err(e) if i + 1 < retries => {
                 let backoff = 2_u64.pow(i);
-                tracing::debug!("Exponential backoff: {} seconds", backoff);
-                time::sleep(time::Duration::from_secs(backoff)).await;
+                tracing::warn!("InfluxDB is not reachable, retrying in {}s: {}", backoff, e);
+                tokio::time::sleep(Duration::from_secs(backoff)).await;
+            }
+            Err(source) => {
+                return Err(StartupError::Unreachable {
+                    addr: config.influxdb.addr.clone(),
+                    source,
+                })
+            }
+        }
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    let cli = Cli::parse();
+
+    if cli.print_config {
+        match Config::default().to_toml() {
+            Ok(toml) => print!("{}", toml),
+            Err(e) => {
+                eprintln!("Failed to print the default configuration: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    let App {
+        config,
+        db,
+        options,
+        command,
+        config_path,
+        dry_run: cli_dry_run,
+        level,
+        _guard,
+        #[cfg(feature = "sentry")]
+        _reporting,
+    } = match init(cli).await {
+        Ok(app) => app,
+        Err(e) => {
+            tracing::error!("{}", e);
+            eprintln!("{}", e);
+            std::process::exit(e.exit_code());
+        }
+    };
+
+    match command {
+        Command::Run => {
+            let reload = Reload {
+                path: config_path,
+                level,
+                dry_run: cli_dry_run,
+            };
+            daemon(config, db, reload).await
+        }
+        Command::Refine { date } => {
+            let date = date.unwrap_or_else(|| Day::Today.date(config.timezone));
+            match tick(db, date, options).await {
+                Ok(refined) => println!("Refined {} hours of {}", refined.len(), date),
+                Err(e) => {
+                    eprintln!("Failed to refine {}: {}", date, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Command::Backfill {
+            from,
+            to,
+            concurrency,
+        } => {
+            if from > to || concurrency == 0 {
+                eprintln!("--from must not be after --to, and --concurrency must be at least 1");
+                std::process::exit(2);
             }
-            tracing::error!(
-                "Unable to refine values after {} retires. Giving up",
-                retries
+            let total = (to - from).num_days() + 1;
+            let mut results = Box::pin(backfill(db, from, to, concurrency, options));
+
+            let mut done = 0;
+            let mut failed = Vec::new();
+            while let Some((date, result)) = results.next().await {
+                done += 1;
+                match result {
+                    Ok(refined) => {
+                        let hours = refined.len();
+                        println!("[{}/{}] {}: refined {} hours", done, total, date, hours)
+                    }
+                    Err(e) => {
+                        println!("[{}/{}] {}: failed: {}", done, total, date, e);
+                        failed.push(date);
+                    }
+                }
+            }
+
+            failed.sort();
+            println!(
+                "Backfilled {} of {} days from {} to {}",
+                total - failed.len() as i64,
+                total,
+                from,
+                to
             );
+            if !failed.is_empty() {
+                let failed: Vec<String> = failed.iter().map(|date| date.to_string()).collect();
+                println!("Incomplete days: {}", failed.join(", "));
+                std::process::exit(1);
+            }
+        }
+        Command::Rollup { date } => {
+            let date = date.unwrap_or_else(|| Day::Today.date(config.timezone));
+            match rollup_pass(&db, date, &options).await {
+                Ok(rollups) => println!("Rolled up {} periods through {}", rollups.len(), date),
+                Err(e) => {
+                    eprintln!("Failed to roll up {}: {}", date, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Command::Simulate {
+            from,
+            to,
+            kwh_per_day,
+        } => {
+            if from > to || config.refiner.appliances.is_empty() {
+                eprintln!("--from must not be after --to, and refiner.appliances must be set");
+                std::process::exit(2);
+            }
+            let tz = config.timezone;
+            let simulation = match simulate(from, to, kwh_per_day, &db, tz, &config.refiner).await {
+                Ok(simulation) => simulation,
+                Err(e) => {
+                    eprintln!("Failed to simulate {} to {}: {}", from, to, e);
+                    std::process::exit(1);
+                }
+            };
+
+            println!("Simulated {} days from {} to {}", simulation.days, from, to);
+            if !simulation.skipped.is_empty() {
+                let skipped: Vec<String> = simulation
+                    .skipped
+                    .iter()
+                    .map(|date| date.to_string())
+                    .collect();
+                println!("Days without prices: {}", skipped.join(", "));
+            }
+            if simulation.kwh > 0.0 {
+                println!(
+                    "Household: {:.1} kWh for {:.2} {}",
+                    simulation.kwh, simulation.cost, config.refiner.source.currency
+                );
+            }
+            println!("appliance              kWh   baseline    shifted      saved");
+            for saving in &simulation.savings {
+                println!(
+                    "{:<16} {:>9.1} {:>10.2} {:>10.2} {:>10.2} ({:.1}%)",
+                    saving.appliance,
+                    saving.kwh,
+                    saving.baseline,
+                    saving.shifted,
+                    saving.saved(),
+                    saving.percent()
+                );
+            }
+        }
+        Command::Show { date, area } => {
+            let date = date.unwrap_or_else(|| Day::Today.date(config.timezone));
+            // The first area by default, like the HTTP API and MQTT
+            let areas = &config.refiner.areas;
+            let area = match &area {
+                Some(name) => match areas.iter().find(|area| &area.name == name) {
+                    Some(area) => Some(area.name.clone()),
+                    None => {
+                        eprintln!("No area named {} in refiner.areas", name);
+                        std::process::exit(2);
+                    }
+                },
+                None => areas.first().map(|area| area.name.clone()),
+            };
+            let options = TickOptions {
+                discard: true,
+                ..options
+            };
+            let mut rows = match tick(db, date, options).await {
+                Ok(rows) => rows,
+                Err(e) => {
+                    eprintln!("Failed to refine {}: {}", date, e);
+                    std::process::exit(1);
+                }
+            };
+            rows.retain(|row| row.area == area);
+
+            let color = std::env::var_os("NO_COLOR").is_none();
+            let forecast = rows.iter().any(|row| row.forecast);
+            match (&area, forecast) {
+                (Some(area), true) => println!("{} in {}, forecast", date, area),
+                (Some(area), false) => println!("{} in {}", date, area),
+                (None, true) => println!("{}, forecast", date),
+                (None, false) => println!("{}", date),
+            }
+            print!("{}", table(&rows, Utc::now(), color));
+        }
+        Command::Export {
+            date,
+            from,
+            to,
+            format,
+            out,
+        } => {
+            let today = Day::Today.date(config.timezone);
+            let (from, to) = match (from, to) {
+                (Some(from), Some(to)) => (from, to),
+                _ => (date.unwrap_or(today), date.unwrap_or(today)),
+            };
+            if from > to {
+                eprintln!("--from must not be after --to");
+                std::process::exit(2);
+            }
+            let options = TickOptions {
+                discard: true,
+                ..options
+            };
+            let mut results = Box::pin(backfill(db, from, to, 4, options));
+
+            let mut rows = Vec::new();
+            let mut failed = Vec::new();
+            while let Some((date, result)) = results.next().await {
+                match result {
+                    Ok(refined) => rows.extend(refined),
+                    Err(e) => {
+                        eprintln!("{}: failed: {}", date, e);
+                        failed.push(date);
+                    }
+                }
+            }
+            rows.sort_by(|a, b| (a.time, &a.area).cmp(&(b.time, &b.area)));
+
+            let rendered = match export::render(&rows, format) {
+                Ok(rendered) => rendered,
+                Err(e) => {
+                    eprintln!("Failed to render rows as {}: {}", format, e);
+                    std::process::exit(1);
+                }
+            };
+            match &out {
+                Some(path) => {
+                    if let Err(e) = std::fs::write(path, rendered) {
+                        eprintln!("Failed to write {}: {}", path.display(), e);
+                        std::process::exit(1);
+                    }
+                    eprintln!("Exported {} rows to {}", rows.len(), path.display());
+                }
+                None => print!("{}", rendered),
+            }
+            if !failed.is_empty() {
+                std::process::exit(1);
+            }
         }
+        Command::CheckConfig => {
+            if let Err(e) = check_config(&config, &db).await {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+            println!("Configuration OK");
+        }
+    }
+}
+
+/// Prints the effective config and checks InfluxDB against it, the config itself is valid once
+/// it has loaded
+async fn check_config(config: &Config, db: &Db) -> Result<(), String> {
+    let toml = config
+        .redacted()
+        .to_toml()
+        .map_err(|e| format!("Failed to print the configuration: {}", e))?;
+    println!("{}", toml);
+
+    let (build, version) = db.ping().await.map_err(|e| {
+        format!(
+            "InfluxDB at {} is not reachable: {}",
+            config.influxdb.addr, e
+        )
+    })?;
+    println!(
+        "InfluxDB at {} is reachable, {} {}",
+        config.influxdb.addr, build, version
+    );
+
+    let refiner = &config.refiner;
+    let areas = refiner
+        .areas
+        .iter()
+        .filter_map(|area| area.measurement.clone());
+    let mut measurements: Vec<String> = std::iter::once(refiner.source.measurement.clone())
+        .chain(areas)
+        .chain(refiner.consumption.iter().map(|c| c.measurement.clone()))
+        .collect();
+    measurements.sort();
+    measurements.dedup();
+    let mut missing = Vec::new();
+    for measurement in measurements {
+        match measurement_exists(db, &measurement).await {
+            Ok(true) => println!("Measurement {} exists", measurement),
+            Ok(false) => missing.push(measurement),
+            Err(e) => return Err(format!("Failed to look up {}: {}", measurement, e)),
+        }
+    }
+    if !missing.is_empty() {
+        return Err(format!(
+            "No such measurement in {}: {}",
+            config.influxdb.db_name,
+            missing.join(", ")
+        ));
     }
+    Ok(())
 }
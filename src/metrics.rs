@@ -0,0 +1,112 @@
+use once_cell::sync::Lazy;
+use prometheus::{
+    register_gauge, register_histogram, register_histogram_vec, register_int_counter,
+    register_int_counter_vec, register_int_gauge, Encoder, Gauge, Histogram, HistogramVec,
+    IntCounter, IntCounterVec, IntGauge, TextEncoder,
+};
+
+pub static TICK_DURATION: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram!(
+        "tibber_refiner_tick_duration_seconds",
+        "Time spent refining a full day"
+    )
+    .expect("Failed to register tick duration histogram")
+});
+
+pub static TICK_RETRIES: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "tibber_refiner_tick_retries_total",
+        "Number of failed tick attempts that were retried"
+    )
+    .expect("Failed to register tick retries counter")
+});
+
+pub static HOURS_REFINED: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "tibber_refiner_hours_refined_total",
+        "Number of hours successfully refined and written"
+    )
+    .expect("Failed to register hours refined counter")
+});
+
+/// Labelled by `kind`, either `read` or `write`
+pub static INFLUX_QUERY_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "tibber_refiner_influx_query_duration_seconds",
+        "Latency of queries against InfluxDB",
+        &["kind"]
+    )
+    .expect("Failed to register influx query duration histogram")
+});
+
+/// Labelled by `kind`, either `read` or `write`
+pub static INFLUX_QUERY_FAILURES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "tibber_refiner_influx_query_failures_total",
+        "Number of failed queries against InfluxDB",
+        &["kind"]
+    )
+    .expect("Failed to register influx query failures counter")
+});
+
+pub static CIRCUIT_BREAKER_TRIPS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "tibber_refiner_circuit_breaker_trips_total",
+        "Number of times the InfluxDB circuit breaker opened"
+    )
+    .expect("Failed to register circuit breaker trips counter")
+});
+
+pub static CURRENT_PRICE: Lazy<Gauge> = Lazy::new(|| {
+    register_gauge!("tibber_refiner_current_price", "Price of the current hour")
+        .expect("Failed to register current price gauge")
+});
+
+pub static CURRENT_RATIO: Lazy<Gauge> = Lazy::new(|| {
+    register_gauge!(
+        "tibber_refiner_current_ratio",
+        "Price of the current hour relative to the daily average"
+    )
+    .expect("Failed to register current ratio gauge")
+});
+
+/// 1 while tomorrow's prices were missing at the last deadline check, 0 once they were found
+pub static TOMORROW_PRICES_MISSING: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "tibber_refiner_tomorrow_prices_missing",
+        "Whether tomorrow's prices were missing at the deadline"
+    )
+    .expect("Failed to register tomorrow prices missing gauge")
+});
+
+/// Age of the latest refined row at the last staleness check
+pub static REFINED_AGE: Lazy<Gauge> = Lazy::new(|| {
+    register_gauge!(
+        "tibber_refiner_refined_age_seconds",
+        "Age of the latest row written to the refined measurement"
+    )
+    .expect("Failed to register refined age gauge")
+});
+
+/// Registers every metric up front so they are exported before their first observation
+pub fn register() {
+    Lazy::force(&TICK_DURATION);
+    Lazy::force(&TICK_RETRIES);
+    Lazy::force(&HOURS_REFINED);
+    Lazy::force(&INFLUX_QUERY_DURATION);
+    Lazy::force(&INFLUX_QUERY_FAILURES);
+    Lazy::force(&CIRCUIT_BREAKER_TRIPS);
+    Lazy::force(&CURRENT_PRICE);
+    Lazy::force(&CURRENT_RATIO);
+    Lazy::force(&TOMORROW_PRICES_MISSING);
+    Lazy::force(&REFINED_AGE);
+}
+
+/// Renders all registered metrics in the Prometheus text format
+pub fn encode() -> Result<String, String> {
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buffer)
+        .map_err(|e| e.to_string())?;
+    String::from_utf8(buffer).map_err(|e| e.to_string())
+}
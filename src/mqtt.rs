@@ -0,0 +1,206 @@
+use std::time::Duration;
+
+use chrono::Timelike;
+use chrono_tz::Tz;
+use rumqttc::{AsyncClient, Event, EventLoop, LastWill, MqttOptions, Packet, QoS};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::{sync::watch, time};
+use tracing::instrument;
+
+use super::{
+    config::{Config, RefinerConfig},
+    refiner::{row_at, Refined, SharedRows, ROW_KEYS},
+};
+
+const DEFAULT_MQTT_PORT: u16 = 1883;
+const DEFAULT_BASE_TOPIC: &str = "tibber_refiner";
+const DEFAULT_DISCOVERY_PREFIX: &str = "homeassistant";
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MqttSettings {
+    pub host: String,
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub base_topic: String,
+    pub discovery_prefix: String,
+}
+
+impl Default for MqttSettings {
+    fn default() -> Self {
+        MqttSettings {
+            host: String::new(),
+            port: DEFAULT_MQTT_PORT,
+            username: None,
+            password: None,
+            base_topic: DEFAULT_BASE_TOPIC.to_string(),
+            discovery_prefix: DEFAULT_DISCOVERY_PREFIX.to_string(),
+        }
+    }
+}
+
+pub struct Publisher {
+    client: AsyncClient,
+    settings: MqttSettings,
+}
+
+impl Publisher {
+    fn availability_topic(&self) -> String {
+        format!("{}/status", self.settings.base_topic)
+    }
+
+    fn state_topic(&self) -> String {
+        format!("{}/state", self.settings.base_topic)
+    }
+
+    /// Connects to the broker and drives the event loop on a background task
+    pub fn connect(settings: MqttSettings) -> Publisher {
+        let mut options = MqttOptions::new("tibber_refiner", &settings.host, settings.port);
+        options.set_keep_alive(Duration::from_secs(30));
+        if let (Some(username), Some(password)) = (&settings.username, &settings.password) {
+            options.set_credentials(username, password);
+        }
+        let availability_topic = format!("{}/status", settings.base_topic);
+        options.set_last_will(LastWill::new(
+            availability_topic.clone(),
+            "offline",
+            QoS::AtLeastOnce,
+            true,
+        ));
+
+        let (client, eventloop) = AsyncClient::new(options, 64);
+        tokio::spawn(drive(eventloop, client.clone(), availability_topic));
+
+        Publisher { client, settings }
+    }
+
+    /// Publishes Home Assistant discovery configs for every field rows refined with `config` can
+    /// have, including those the current row leaves out
+    #[instrument(skip_all)]
+    pub async fn publish_discovery(&self, config: &RefinerConfig) -> Result<(), String> {
+        let schema = Refined::schema(config);
+        let fields = match serde_json::to_value(&schema).map_err(|e| e.to_string())? {
+            Value::Object(fields) => fields,
+            _ => return Err("Refined did not serialize into an object".to_string()),
+        };
+
+        let device = json!({
+            "identifiers": [self.settings.base_topic],
+            "name": "Tibber Refiner",
+            "manufacturer": "CasaMack",
+            "model": "tibber_refiner",
+            "sw_version": env!("CARGO_PKG_VERSION"),
+        });
+
+        for (field, value) in fields
+            .iter()
+            .filter(|(field, _)| !ROW_KEYS.contains(&field.as_str()))
+        {
+            let unique_id = format!("{}_{}", self.settings.base_topic, field);
+            let (component, mut config) = match value {
+                Value::Bool(_) => (
+                    "binary_sensor",
+                    json!({
+                        "value_template": format!("{{{{ 'ON' if value_json.{} else 'OFF' }}}}", field),
+                    }),
+                ),
+                _ => (
+                    "sensor",
+                    json!({
+                        "value_template": format!("{{{{ value_json.{} }}}}", field),
+                    }),
+                ),
+            };
+            config["name"] = json!(field);
+            config["unique_id"] = json!(unique_id);
+            config["state_topic"] = json!(self.state_topic());
+            config["availability_topic"] = json!(self.availability_topic());
+            config["device"] = device.clone();
+
+            let topic = format!(
+                "{}/{}/{}/config",
+                self.settings.discovery_prefix, component, unique_id
+            );
+            tracing::debug!("Publishing discovery config to {}", topic);
+            self.client
+                .publish(topic, QoS::AtLeastOnce, true, config.to_string())
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+
+        self.client
+            .publish(self.availability_topic(), QoS::AtLeastOnce, true, "online")
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    #[instrument(skip_all, fields(hour = refined.hour))]
+    pub async fn publish_state(&self, refined: &Refined) -> Result<(), String> {
+        let payload = serde_json::to_string(refined).map_err(|e| e.to_string())?;
+        self.client
+            .publish(self.state_topic(), QoS::AtLeastOnce, true, payload)
+            .await
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// Polls the connection, reconnecting after errors. Every connection is announced as online, as
+/// the broker sets the last will of the one before to offline
+async fn drive(mut eventloop: EventLoop, client: AsyncClient, availability_topic: String) {
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                // Queued rather than awaited, the request is only sent by polling
+                let online =
+                    client.try_publish(&availability_topic, QoS::AtLeastOnce, true, "online");
+                if let Err(e) = online {
+                    tracing::warn!("Failed to announce the MQTT connection as online: {}", e);
+                }
+            }
+            Ok(_) => {}
+            Err(e) => {
+                tracing::warn!("MQTT connection error: {}", e);
+                time::sleep(Duration::from_secs(5)).await;
+            }
+        }
+    }
+}
+
+/// Publishes the current hour's refined row at the start of every hour, and discovery configs
+/// again when the config is reloaded
+pub async fn run(
+    publisher: Publisher,
+    rows: SharedRows,
+    tz: Tz,
+    mut config_rx: watch::Receiver<Config>,
+) {
+    let mut discovered = false;
+    loop {
+        let now = chrono::Utc::now().with_timezone(&tz);
+        if !discovered {
+            let refiner = config_rx.borrow_and_update().refiner.clone();
+            match publisher.publish_discovery(&refiner).await {
+                Ok(_) => discovered = true,
+                Err(e) => tracing::error!("Failed to publish discovery configs: {}", e),
+            }
+        }
+        {
+            let rows = rows.read().await;
+            if let Some(refined) = row_at(&rows, now) {
+                if let Err(e) = publisher.publish_state(refined).await {
+                    tracing::error!("Failed to publish state: {}", e);
+                }
+            } else {
+                tracing::debug!("No refined row for hour {} yet", now.hour());
+            }
+        }
+
+        let seconds_into_hour = (now.minute() * 60 + now.second()) as u64;
+        tokio::select! {
+            _ = time::sleep(Duration::from_secs(3600 - seconds_into_hour)) => {}
+            Ok(()) = config_rx.changed() => discovered = false,
+        }
+    }
+}
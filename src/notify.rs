@@ -0,0 +1,122 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+const DEFAULT_TITLE: &str = "tibber_refiner";
+const SEND_TIMEOUT_SECS: u64 = 10;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotifySettings {
+    /// Title of every message, to tell several refiners apart
+    pub title: String,
+    /// Topic URL to publish to, like https://ntfy.sh/my-topic
+    pub ntfy_url: Option<String>,
+    pub telegram_token: Option<String>,
+    pub telegram_chat_id: Option<String>,
+    /// Receives a JSON object with `title`, `event` and `message`
+    pub webhook_url: Option<String>,
+}
+
+impl Default for NotifySettings {
+    fn default() -> Self {
+        NotifySettings {
+            title: DEFAULT_TITLE.to_string(),
+            ntfy_url: None,
+            telegram_token: None,
+            telegram_chat_id: None,
+            webhook_url: None,
+        }
+    }
+}
+
+/// What a message is about
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    Started,
+    Stopped,
+    /// A tick failed on every retry
+    TickFailed,
+    /// Prices are not stored when they should be
+    MissingData,
+    /// Nothing was refined for longer than the configured threshold
+    Stale,
+}
+
+impl Event {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::Started => "started",
+            Event::Stopped => "stopped",
+            Event::TickFailed => "tick_failed",
+            Event::MissingData => "missing_data",
+            Event::Stale => "stale",
+        }
+    }
+}
+
+/// Pushes messages to every configured channel, does nothing without settings
+#[derive(Clone)]
+pub struct Notifier {
+    settings: Option<NotifySettings>,
+    http: reqwest::Client,
+}
+
+impl Notifier {
+    pub fn new(settings: Option<NotifySettings>) -> Notifier {
+        let http = reqwest::Client::builder()
+            .timeout(Duration::from_secs(SEND_TIMEOUT_SECS))
+            .build()
+            .unwrap_or_default();
+        Notifier { settings, http }
+    }
+
+    /// Sends `message` to every channel, logging the channels it fails on
+    pub async fn notify(&self, event: Event, message: &str) {
+        let settings = match &self.settings {
+            Some(settings) => settings,
+            None => return,
+        };
+        tracing::debug!("Notifying {}: {}", event.name(), message);
+
+        if let Some(url) = &settings.ntfy_url {
+            let request = self
+                .http
+                .post(url)
+                .header("Title", settings.title.as_str())
+                .header("Tags", event.name())
+                .body(message.to_string());
+            self.send("ntfy", request).await;
+        }
+        if let (Some(token), Some(chat_id)) = (&settings.telegram_token, &settings.telegram_chat_id)
+        {
+            let request = self
+                .http
+                .post(format!("https://api.telegram.org/bot{}/sendMessage", token))
+                .json(&json!({
+                    "chat_id": chat_id,
+                    "text": format!("{}: {}", settings.title, message),
+                }));
+            self.send("Telegram", request).await;
+        }
+        if let Some(url) = &settings.webhook_url {
+            let request = self.http.post(url).json(&json!({
+                "title": settings.title,
+                "event": event.name(),
+                "message": message,
+            }));
+            self.send("webhook", request).await;
+        }
+    }
+
+    async fn send(&self, channel: &str, request: reqwest::RequestBuilder) {
+        let result = request
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        if let Err(e) = result {
+            tracing::warn!("Failed to notify {}: {}", channel, e);
+        }
+    }
+}
@@ -0,0 +1,142 @@
+#[cfg(feature = "influx")]
+use chrono::NaiveDate;
+use chrono::Timelike;
+#[cfg(feature = "influx")]
+use influxdb::{InfluxDbWriteable, Query, Timestamp, WriteQuery};
+#[cfg(feature = "influx")]
+use tracing::instrument;
+
+use super::{
+    config::Appliance,
+    refiner::{cheapest_window, hour_of_day, points_per_hour, PricePoint},
+};
+#[cfg(feature = "influx")]
+use super::{error::RefinerError, store::PriceStore};
+
+/// When an appliance runs during the day
+#[derive(Clone, Debug)]
+pub struct Schedule {
+    pub appliance: String,
+    /// Name of the configured area, unset with a single area
+    pub area: Option<String>,
+    /// Indices of the prices it runs in, in time order, empty if it doesn't fit its window
+    pub indices: Vec<usize>,
+}
+
+impl Schedule {
+    pub fn runs_at(&self, index: usize) -> bool {
+        self.indices.contains(&index)
+    }
+
+    /// Hour on the clock the appliance is first started
+    pub fn planned_start(&self, prices: &[PricePoint]) -> Option<u32> {
+        let first = self.indices.first()?;
+        prices.get(*first).map(|price| price.start.hour())
+    }
+}
+
+/// Runs the appliance in the cheapest prices of its window, in one go if it is contiguous
+pub fn schedule(prices: &[PricePoint], appliance: &Appliance) -> Schedule {
+    let allowed: Vec<usize> = (0..prices.len())
+        .filter(|index| {
+            let hour = hour_of_day(prices[*index].start);
+            appliance.earliest <= hour && hour < appliance.latest
+        })
+        .collect();
+    let length = appliance.hours * points_per_hour(prices);
+
+    let indices = match (allowed.first(), allowed.last()) {
+        (Some(first), Some(last)) if allowed.len() >= length => {
+            if appliance.contiguous {
+                cheapest_window(&prices[*first..=*last], appliance.hours)
+                    .map(|(start, _)| (first + start..first + start + length).collect())
+                    .unwrap_or_default()
+            } else {
+                let mut cheapest = allowed.clone();
+                cheapest.sort_by(|a, b| {
+                    prices[*a]
+                        .value
+                        .partial_cmp(&prices[*b].value)
+                        .unwrap_or(std::cmp::Ordering::Equal)
+                });
+                cheapest.truncate(length);
+                cheapest.sort_unstable();
+                cheapest
+            }
+        }
+        _ => Vec::new(),
+    };
+    if indices.is_empty() {
+        tracing::warn!(
+            "{} does not fit in its window of {} prices",
+            appliance.name,
+            allowed.len()
+        );
+    }
+
+    Schedule {
+        appliance: appliance.name.clone(),
+        area: None,
+        indices,
+    }
+}
+
+pub fn schedules(prices: &[PricePoint], appliances: &[Appliance]) -> Vec<Schedule> {
+    appliances
+        .iter()
+        .map(|appliance| schedule(prices, appliance))
+        .collect()
+}
+
+/// One point per price and appliance, `appliance`, `hour`, `date` and `area` are tags
+#[cfg(feature = "influx")]
+fn to_queries(date: NaiveDate, prices: &[PricePoint], schedules: &[Schedule]) -> Vec<WriteQuery> {
+    let mut queries = Vec::new();
+    for schedule in schedules {
+        let planned_start = schedule.planned_start(prices);
+        for (index, price) in prices.iter().enumerate() {
+            let mut query = Timestamp::from(price.start)
+                .into_query("appliances")
+                .add_tag("appliance", schedule.appliance.clone())
+                .add_tag("hour", price.start.hour())
+                .add_tag("date", date.to_string())
+                .add_field("run_now", schedule.runs_at(index));
+            if let Some(start) = planned_start {
+                query = query.add_field("planned_start", start);
+            }
+            if let Some(area) = &schedule.area {
+                query = query.add_tag("area", area.clone());
+            }
+            queries.push(query);
+        }
+    }
+    queries
+}
+
+/// Renders the schedules as Influx line protocol, one line per price and appliance
+#[cfg(feature = "influx")]
+pub fn line_protocol(
+    date: NaiveDate,
+    prices: &[PricePoint],
+    schedules: &[Schedule],
+) -> Result<String, RefinerError> {
+    Ok(to_queries(date, prices, schedules)
+        .build()
+        .map_err(|e| RefinerError::Write(e.to_string()))?
+        .get())
+}
+
+/// Writes every schedule in a single batched query
+#[cfg(feature = "influx")]
+#[instrument(skip_all, fields(date = %date, appliances = schedules.len()))]
+pub async fn write_schedules(
+    date: NaiveDate,
+    prices: &[PricePoint],
+    schedules: &[Schedule],
+    store: &dyn PriceStore,
+) -> Result<(), RefinerError> {
+    if schedules.is_empty() {
+        return Ok(());
+    }
+    store.write(to_queries(date, prices, schedules)).await
+}
@@ -1,27 +1,197 @@
-use influxdb::{Client, InfluxDbWriteable, ReadQuery};
+#[cfg(feature = "influx")]
+use influxdb::{InfluxDbWriteable, Query, ReadQuery, Timestamp, WriteQuery};
+#[cfg(feature = "influx")]
 use serde::Deserialize;
+use serde::Serialize;
 use tracing::instrument;
-use chrono_tz::Europe::Oslo;
+#[cfg(feature = "influx")]
+use chrono::Utc;
+use chrono::{DateTime, Datelike, NaiveDate, TimeZone, Timelike, Weekday};
+use chrono_tz::Tz;
+use std::{collections::BTreeMap, sync::Arc};
+use tokio::sync::RwLock;
 
-type HourPrice = (usize, f64);
+use super::{
+    calendar,
+    config::{
+        Area, Band, CalendarConfig, CostConfig, RefinerConfig, Resolution, SolarConfig,
+        TariffConfig,
+    },
+    error::RefinerError,
+    rules::DayRules,
+    store::PriceStore,
+};
+#[cfg(feature = "influx")]
+use super::{
+    config::{ConsumptionConfig, SourceConfig},
+    db::Db,
+    metrics,
+};
+
+/// Smallest magnitude a price is divided by, a hundredth of the currency per kWh, so ratios
+/// against an average near zero stay finite
+pub const RATIO_FLOOR: f64 = 0.01;
+
+/// A price starting at `start`, lasting an hour or a quarter of an hour
+#[derive(Copy, Clone, Debug)]
+pub struct PricePoint {
+    pub start: DateTime<Tz>,
+    pub value: f64,
+}
+
+/// Index of a price point within its day and its value
+pub type IndexedPrice = (usize, f64);
+
+/// Number of price points per hour, 4 with quarter hour prices. Taken from the closest prices so
+/// missing prices don't count as a longer resolution
+pub fn points_per_hour(prices: &[PricePoint]) -> usize {
+    prices
+        .windows(2)
+        .map(|pair| (pair[1].start - pair[0].start).num_minutes().max(1))
+        .min()
+        .map(|minutes| usize::try_from(60 / minutes).unwrap_or(1).max(1))
+        .unwrap_or(1)
+}
+
+/// Start of the hour on the clock `start` is in
+fn hour_start(start: DateTime<Tz>) -> DateTime<Tz> {
+    start - chrono::Duration::minutes(start.minute().into())
+}
+
+/// Averages quarter hour prices into hourly prices, hourly prices are returned as they are. An
+/// hour missing some of its prices is averaged over the others
+pub fn hourly(prices: &[PricePoint]) -> Vec<PricePoint> {
+    let mut hours: Vec<(DateTime<Tz>, Vec<f64>)> = Vec::new();
+    for price in prices {
+        let start = hour_start(price.start);
+        match hours.last_mut() {
+            Some((hour, values)) if *hour == start => values.push(price.value),
+            _ => hours.push((start, vec![price.value])),
+        }
+    }
+    hours
+        .into_iter()
+        .map(|(start, values)| PricePoint {
+            start,
+            value: values.iter().sum::<f64>() / values.len() as f64,
+        })
+        .collect()
+}
 
 #[derive(Copy, Clone, Debug)]
 pub enum Day {
+    Yesterday,
     Today,
     Tomorrow,
 }
 
+impl Day {
+    /// Resolves the day to a date in `tz`, resolve once and pass the date on so work
+    /// spanning midnight stays on the same date
+    pub fn date(&self, tz: Tz) -> NaiveDate {
+        self.of(chrono::Utc::now().with_timezone(&tz).date().naive_local())
+    }
+
+    /// Resolves the day as seen from `today`, which need not be the current date
+    pub fn of(&self, today: NaiveDate) -> NaiveDate {
+        match self {
+            Day::Yesterday => today.pred(),
+            Day::Today => today,
+            Day::Tomorrow => today.succ(),
+        }
+    }
+}
+
+/// Local midnight at the start of `date`
+pub fn start_of_day(date: NaiveDate, tz: Tz) -> Option<DateTime<Tz>> {
+    tz.from_local_datetime(&date.and_hms(0, 0, 0)).earliest()
+}
+
+/// Number of hours in `date`, 23 or 25 on the days daylight saving time starts or ends
+pub fn day_length(date: NaiveDate, tz: Tz) -> Option<usize> {
+    let length = start_of_day(date.succ_opt()?, tz)? - start_of_day(date, tz)?;
+    usize::try_from(length.num_hours()).ok()
+}
+
+/// Hours since local midnight of the day `start` is in, which differs from the hour on the clock
+/// after daylight saving time starts or ends
+pub fn hour_of_day(start: DateTime<Tz>) -> usize {
+    start_of_day(start.date().naive_local(), start.timezone())
+        .and_then(|midnight| usize::try_from((start - midnight).num_hours()).ok())
+        .unwrap_or(start.hour() as usize)
+}
+
+/// Number of hours of `date` without a single price
+pub fn missing_hours(date: NaiveDate, tz: Tz, prices: &[PricePoint]) -> Option<usize> {
+    let mut hours: Vec<usize> = prices
+        .iter()
+        .map(|price| hour_of_day(price.start))
+        .collect();
+    hours.dedup();
+    Some(day_length(date, tz)?.saturating_sub(hours.len()))
+}
+
+/// Drops the prices that would skew the analysis of `date`: prices tagged with it that start on
+/// another day, and all but the last returned of prices starting at the same time, like rows
+/// imported twice. The rest are returned in time order and what was dropped is logged
+pub fn sanitize(date: NaiveDate, prices: Vec<PricePoint>) -> Vec<PricePoint> {
+    let total = prices.len();
+    let mut kept: Vec<PricePoint> = Vec::with_capacity(total);
+    let mut outside = 0;
+    let mut duplicates = 0;
+    for price in prices {
+        if price.start.date().naive_local() != date {
+            outside += 1;
+            continue;
+        }
+        match kept.iter_mut().find(|kept| kept.start == price.start) {
+            Some(kept) => {
+                *kept = price;
+                duplicates += 1;
+            }
+            None => kept.push(price),
+        }
+    }
+    kept.sort_by_key(|price| price.start);
+    if outside + duplicates > 0 {
+        tracing::warn!(
+            "Dropped {} of {} prices of {}: {} duplicates and {} outside the day",
+            outside + duplicates,
+            total,
+            date,
+            duplicates,
+            outside
+        );
+    }
+    kept
+}
+
+/// What a tick does with the computed rows
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Output {
+    Write,
+    /// Print the row as line protocol instead of writing it
+    Print,
+    /// Only compute the row, used when the date has already been written and to show it
+    Discard,
+}
+
+#[cfg(feature = "influx")]
 #[derive(Deserialize)]
 struct QueryResults {
     pub results: Vec<Statement>,
 }
 
+#[cfg(feature = "influx")]
 #[derive(Deserialize)]
 struct Statement {
     pub statement_id: usize,
+    /// Missing when nothing matched
+    #[serde(default)]
     pub series: Vec<Serie>,
 }
 
+#[cfg(feature = "influx")]
 #[derive(Deserialize)]
 struct Serie {
     pub name: String,
@@ -29,319 +199,1802 @@ struct Serie {
     pub values: Vec<Value>,
 }
 
+#[cfg(feature = "influx")]
 #[derive(Deserialize)]
 struct Value {
     datetime: String,
     pub value: f64,
-    pub hour: u32,
 }
 
-#[instrument(skip(client))]
-pub async fn get_prices(day: Day, client: &Client) -> Result<Vec<HourPrice>, String> {
-    let date = match day {
-        Day::Today => chrono::Utc::now().with_timezone(&Oslo)
-            .date()
-            .to_string()
-            .split('+')
-            .into_iter()
-            .next()
-            .ok_or("Error splitting date")?
-            .to_owned(),
-        Day::Tomorrow => chrono::Utc::now().with_timezone(&Oslo)
-            .date()
-            .succ()
-            .to_string()
-            .split('+')
-            .into_iter()
-            .next()
-            .ok_or("Error splitting date")?
-            .to_owned(),
+#[cfg(feature = "influx")]
+#[derive(Deserialize)]
+struct RowsResults {
+    pub results: Vec<RowsStatement>,
+}
+
+#[cfg(feature = "influx")]
+#[derive(Deserialize)]
+struct RowsStatement {
+    /// Missing when nothing matched
+    #[serde(default)]
+    pub series: Vec<RowsSerie>,
+}
+
+#[cfg(feature = "influx")]
+#[derive(Deserialize)]
+struct RowsSerie {
+    pub columns: Vec<String>,
+    pub values: Vec<Vec<serde_json::Value>>,
+}
+
+#[cfg(feature = "influx")]
+#[derive(Deserialize)]
+struct CountResults {
+    pub results: Vec<CountStatement>,
+}
+
+#[cfg(feature = "influx")]
+#[derive(Deserialize)]
+struct CountStatement {
+    /// Missing when nothing matched
+    #[serde(default)]
+    pub series: Vec<CountSerie>,
+}
+
+#[cfg(feature = "influx")]
+#[derive(Deserialize)]
+struct CountSerie {
+    pub values: Vec<(String, u64)>,
+}
+
+/// The prices of `date` in time order, hourly or quarter hourly as they were stored, sanitized
+#[cfg(feature = "influx")]
+#[instrument(skip(db, source))]
+pub async fn get_prices(
+    date: NaiveDate,
+    db: &Db,
+    tz: Tz,
+    source: &SourceConfig,
+    area: Option<&Area>,
+) -> Result<Vec<PricePoint>, RefinerError> {
+    let (measurement, condition) = price_selection(source, area);
+    let read_query = ReadQuery::new(format!(
+        "SELECT \"{}\" FROM {} WHERE \"{}\" = '{}'{}",
+        source.price_column, measurement, source.date_tag, date, condition
+    ));
+
+    let prices = sanitize(date, read_prices(&read_query, db, tz, source).await?);
+    if prices.is_empty() {
+        return Err(RefinerError::MissingData(format!("No prices for {}", date)));
+    }
+    Ok(prices)
+}
+
+/// The prices of the `days` days before `date` in time order, empty if none are stored.
+///
+/// Selects on time rather than the date tag, so a month of history is a single range
+#[cfg(feature = "influx")]
+#[instrument(skip(db, source))]
+pub async fn get_prices_before(
+    date: NaiveDate,
+    days: u32,
+    db: &Db,
+    tz: Tz,
+    source: &SourceConfig,
+    area: Option<&Area>,
+) -> Result<Vec<PricePoint>, RefinerError> {
+    let first = date - chrono::Duration::days(days.into());
+    let (start, stop) = match (start_of_day(first, tz), start_of_day(date, tz)) {
+        (Some(start), Some(stop)) => (start, stop),
+        _ => {
+            return Err(RefinerError::Timestamp(format!(
+                "{} or {} has no local midnight",
+                first, date
+            )))
+        }
     };
+    let (measurement, condition) = price_selection(source, area);
     let read_query = ReadQuery::new(format!(
-        "SELECT price, hour FROM price_info WHERE date = '{}'",
-        date
+        "SELECT \"{}\" FROM {} WHERE time >= '{}' AND time < '{}'{}",
+        source.price_column,
+        measurement,
+        start.with_timezone(&Utc).format("%Y-%m-%dT%H:%M:%SZ"),
+        stop.with_timezone(&Utc).format("%Y-%m-%dT%H:%M:%SZ"),
+        condition
     ));
 
-    let read_result = client.query(&read_query).await;
-    match read_result {
-        Ok(result) => {
-            let r: QueryResults = serde_json::from_str(&result).map_err(|e| {
-                format!(
-                    "Error parsing result from {:?} into QueryResults: {:?}",
-                    read_query, e
-                )
-            })?;
-            Ok(r.results
-                .get(0)
-                .ok_or("Access index out of bounds on results, likely something wrong happened during parsing")?
-                .series
-                .get(0)
-                .ok_or("Access index out of bounds on series, likely something wrong happened during parsing")?
-                .values
-                .iter()
-                .map(|val| (val.hour as usize, val.value))
-                .collect())
+    read_prices(&read_query, db, tz, source).await
+}
+
+/// The energy used in `date` in time order, each point the kWh used from its start on, empty
+/// if none is stored. Selected by the tag of `area` like its prices
+#[cfg(feature = "influx")]
+#[instrument(skip(db, consumption))]
+pub async fn get_consumption(
+    date: NaiveDate,
+    db: &Db,
+    tz: Tz,
+    consumption: &ConsumptionConfig,
+    area: Option<&Area>,
+) -> Result<Vec<PricePoint>, RefinerError> {
+    let (start, stop) = match (start_of_day(date, tz), start_of_day(date.succ(), tz)) {
+        (Some(start), Some(stop)) => (start, stop),
+        _ => {
+            return Err(RefinerError::Timestamp(format!(
+                "{} has no local midnight",
+                date
+            )))
         }
-        Err(e) => Err(e.to_string()),
+    };
+    let read_query = ReadQuery::new(format!(
+        "SELECT \"{}\" FROM \"{}\" WHERE time >= '{}' AND time < '{}'{}",
+        consumption.column,
+        consumption.measurement,
+        start.with_timezone(&Utc).format("%Y-%m-%dT%H:%M:%SZ"),
+        stop.with_timezone(&Utc).format("%Y-%m-%dT%H:%M:%SZ"),
+        area_tag_condition(area)
+    ));
+
+    read_series(&read_query, db, tz).await
+}
+
+/// Measurement the prices of `area` are in and the condition selecting them, every price of
+/// the source measurement without an area
+#[cfg(feature = "influx")]
+fn price_selection(source: &SourceConfig, area: Option<&Area>) -> (String, String) {
+    let measurement = area
+        .and_then(|area| area.measurement.clone())
+        .unwrap_or_else(|| source.measurement.clone());
+    (format!("\"{}\"", measurement), area_tag_condition(area))
+}
+
+/// Condition selecting the source rows of `area` by its tag, none without a tag
+#[cfg(feature = "influx")]
+fn area_tag_condition(area: Option<&Area>) -> String {
+    match area {
+        Some(Area {
+            name,
+            tag: Some(tag),
+            value,
+            ..
+        }) => format!(" AND \"{}\" = '{}'", tag, value.as_ref().unwrap_or(name)),
+        _ => String::new(),
     }
 }
 
-pub async fn get_hour_price(day: Day, client: &Client) -> Result<Vec<HourPrice>, String> {
-    Ok(get_prices(day, client).await?)
+/// Condition selecting the refined rows of `area`
+#[cfg(feature = "influx")]
+fn area_condition(area: Option<&Area>) -> String {
+    match area {
+        Some(area) => format!(" AND \"area\" = '{}'", area.name),
+        None => String::new(),
+    }
 }
 
-pub fn price_now(now: usize, prices: &Vec<HourPrice>) -> Result<f64, String> {
-    Ok(prices
-        .get(now)
-        .ok_or(format!("Access index out of bounds using hour = {}", now))?
-        .1
-        .to_owned())
+/// Condition selecting either the forecast rows or the rows of published prices
+#[cfg(feature = "influx")]
+fn forecast_condition(forecast: bool) -> &'static str {
+    if forecast {
+        " AND \"forecast\" = 'true'"
+    } else {
+        // Rows of published prices have no forecast tag, which compares as empty
+        " AND \"forecast\" != 'true'"
+    }
 }
 
-pub fn average(prices: &Vec<HourPrice>) -> Result<f64, String> {
-    Ok(prices.iter().map(|hour_price| hour_price.1).sum::<f64>() / 24.0)
+/// Reads prices in time order and converts them to currency per kWh
+#[cfg(feature = "influx")]
+async fn read_prices(
+    read_query: &ReadQuery,
+    db: &Db,
+    tz: Tz,
+    source: &SourceConfig,
+) -> Result<Vec<PricePoint>, RefinerError> {
+    let mut prices = read_series(read_query, db, tz).await?;
+    for price in &mut prices {
+        price.value = source.unit.per_kwh(price.value);
+    }
+    Ok(prices)
 }
 
-pub fn price_ratio(now: usize, prices: &Vec<HourPrice>) -> Result<f64, String> {
-    Ok(price_now(now, prices)? / average(prices)?)
+/// The values of the first series `read_query` returns in time order, empty without a series
+#[cfg(feature = "influx")]
+async fn read_series(
+    read_query: &ReadQuery,
+    db: &Db,
+    tz: Tz,
+) -> Result<Vec<PricePoint>, RefinerError> {
+    let result = db.read(read_query).await?;
+    let r: QueryResults = serde_json::from_str(&result).map_err(|source| RefinerError::Parse {
+        query: format!("{:?}", read_query),
+        source,
+    })?;
+    let values = match r
+        .results
+        .get(0)
+        .and_then(|statement| statement.series.get(0))
+    {
+        Some(serie) => &serie.values,
+        None => return Ok(Vec::new()),
+    };
+    let mut prices = values
+        .iter()
+        .map(|val| {
+            let start = DateTime::parse_from_rfc3339(&val.datetime)
+                .map_err(|e| RefinerError::Timestamp(format!("{}: {}", val.datetime, e)))?;
+            Ok(PricePoint {
+                start: start.with_timezone(&tz),
+                value: val.value,
+            })
+        })
+        .collect::<Result<Vec<_>, RefinerError>>()?;
+    prices.sort_by_key(|price| price.start);
+    Ok(prices)
 }
 
-pub async fn highest(
-    day: Day,
-    count: usize,
-    start: usize,
-    stop: usize,
-    client: &Client,
-) -> Result<Vec<HourPrice>, String> {
-    let mut prices: Vec<HourPrice> = get_prices(day, client)
-        .await?
+/// Number of rows already written to `refined` for `date`, of a forecast or of its published
+/// prices
+#[cfg(feature = "influx")]
+#[instrument(skip(db))]
+pub async fn count_refined(
+    date: NaiveDate,
+    db: &Db,
+    measurement: &str,
+    area: Option<&Area>,
+    forecast: bool,
+) -> Result<u64, RefinerError> {
+    let read_query = ReadQuery::new(format!(
+        "SELECT count(pris_time) FROM {} WHERE \"date\" = '{}'{}{}",
+        db.written_measurement(measurement),
+        date,
+        area_condition(area),
+        forecast_condition(forecast)
+    ));
+
+    let result = db.read_written(&read_query).await?;
+
+    let r: CountResults = serde_json::from_str(&result).map_err(|source| RefinerError::Parse {
+        query: format!("{:?}", read_query),
+        source,
+    })?;
+    Ok(r.results
+        .get(0)
+        .and_then(|statement| statement.series.get(0))
+        .and_then(|serie| serie.values.get(0))
+        .map(|(_, count)| *count)
+        .unwrap_or(0))
+}
+
+/// Whether `measurement` has any points in the database prices are read from
+#[cfg(feature = "influx")]
+#[instrument(skip(db))]
+pub async fn measurement_exists(db: &Db, measurement: &str) -> Result<bool, RefinerError> {
+    let read_query = ReadQuery::new(format!(
+        "SHOW MEASUREMENTS WITH MEASUREMENT = \"{}\"",
+        measurement
+    ));
+
+    let result = db.read(&read_query).await?;
+
+    let r: RowsResults = serde_json::from_str(&result).map_err(|source| RefinerError::Parse {
+        query: format!("{:?}", read_query),
+        source,
+    })?;
+    Ok(r.results
+        .iter()
+        .any(|statement| !statement.series.is_empty()))
+}
+
+/// Start of the latest row of published prices written to `refined`, of any area. Unset if
+/// nothing was written yet
+#[cfg(feature = "influx")]
+#[instrument(skip(db))]
+pub async fn latest_refined(
+    db: &Db,
+    measurement: &str,
+    tz: Tz,
+) -> Result<Option<DateTime<Tz>>, RefinerError> {
+    let read_query = ReadQuery::new(format!(
+        "SELECT last(pris_time) FROM {} WHERE time > 0{}",
+        db.written_measurement(measurement),
+        forecast_condition(false)
+    ));
+
+    let result = db.read_written(&read_query).await?;
+
+    let r: QueryResults = serde_json::from_str(&result).map_err(|source| RefinerError::Parse {
+        query: format!("{:?}", read_query),
+        source,
+    })?;
+    let latest = match r
+        .results
+        .get(0)
+        .and_then(|statement| statement.series.get(0))
+        .and_then(|serie| serie.values.get(0))
+    {
+        Some(latest) => latest,
+        None => return Ok(None),
+    };
+    let start = DateTime::parse_from_rfc3339(&latest.datetime)
+        .map_err(|e| RefinerError::Timestamp(format!("{}: {}", latest.datetime, e)))?;
+    Ok(Some(start.with_timezone(&tz)))
+}
+
+/// Deletes the forecast rows of `date` from every write target, before the rows of its
+/// published prices are written
+#[cfg(feature = "influx")]
+#[instrument(skip(db))]
+pub async fn delete_forecast(
+    date: NaiveDate,
+    db: &Db,
+    measurement: &str,
+    area: Option<&Area>,
+) -> Result<(), RefinerError> {
+    db.delete(&format!(
+        "DELETE FROM \"{}\" WHERE \"date\" = '{}'{}{}",
+        measurement,
+        date,
+        area_condition(area),
+        forecast_condition(true)
+    ))
+    .await
+}
+
+/// Reads back the rows written for `date` and checks that every row in `expected` is present
+/// with the same field values
+#[cfg(feature = "influx")]
+#[instrument(skip_all, fields(date = %date))]
+pub async fn verify_refined(
+    date: NaiveDate,
+    expected: &[Refined],
+    db: &Db,
+    measurement: &str,
+    area: Option<&Area>,
+) -> Result<(), RefinerError> {
+    let forecast = expected.first().map_or(false, |row| row.forecast);
+    let read_query = ReadQuery::new(format!(
+        "SELECT * FROM {} WHERE \"date\" = '{}'{}{}",
+        db.written_measurement(measurement),
+        date,
+        area_condition(area),
+        forecast_condition(forecast)
+    ));
+
+    let result = db.read_written(&read_query).await?;
+
+    let r: RowsResults = serde_json::from_str(&result).map_err(|source| RefinerError::Parse {
+        query: format!("{:?}", read_query),
+        source,
+    })?;
+    let rows: Vec<serde_json::Map<String, serde_json::Value>> = r
+        .results
         .into_iter()
-        .filter(|hour_price| start <= hour_price.0 && hour_price.0 <= stop)
+        .flat_map(|statement| statement.series)
+        .flat_map(|serie| {
+            let columns = serie.columns;
+            serie
+                .values
+                .into_iter()
+                .map(move |values| {
+                    columns
+                        .iter()
+                        .cloned()
+                        .zip(values)
+                        .collect::<serde_json::Map<_, _>>()
+                })
+        })
         .collect();
-    prices.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal));
-    Ok(prices.into_iter().take(count).collect())
+
+    let mut missing = Vec::new();
+    for refined in expected {
+        // The hour tag repeats when daylight saving time ends, the timestamp doesn't
+        let found = rows.iter().find(|row| {
+            row.get("time")
+                .and_then(|time| time.as_str())
+                .and_then(|time| DateTime::parse_from_rfc3339(time).ok())
+                .map_or(false, |time| time == refined.time)
+        });
+        let found = match found {
+            Some(found) => found,
+            None => {
+                missing.push(refined.hour);
+                continue;
+            }
+        };
+
+        let fields = match serde_json::to_value(refined) {
+            Ok(serde_json::Value::Object(fields)) => fields,
+            _ => {
+                return Err(RefinerError::Write(
+                    "Refined did not serialize into an object".to_string(),
+                ))
+            }
+        };
+        for (field, value) in fields
+            .iter()
+            .filter(|(field, _)| !ROW_KEYS.contains(&field.as_str()))
+        {
+            let actual = found.get(field);
+            let matches = match (value, actual) {
+                (serde_json::Value::Number(a), Some(serde_json::Value::Number(b))) => {
+                    match (a.as_f64(), b.as_f64()) {
+                        (Some(a), Some(b)) => (a - b).abs() <= f64::EPSILON * a.abs().max(1.0),
+                        _ => false,
+                    }
+                }
+                (value, Some(actual)) => value == actual,
+                (_, None) => false,
+            };
+            if !matches {
+                return Err(RefinerError::Write(format!(
+                    "Hour {} has {} = {:?} after writing, expected {}",
+                    refined.hour, field, actual, value
+                )));
+            }
+        }
+    }
+
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(RefinerError::MissingData(format!(
+            "Hours {:?} missing after writing {}",
+            missing, date
+        )))
+    }
+}
+
+pub fn price_now(now: usize, prices: &[PricePoint]) -> Result<f64, RefinerError> {
+    Ok(prices
+        .get(now)
+        .ok_or_else(|| RefinerError::MissingData(format!("No price at index {}", now)))?
+        .value)
+}
+
+pub fn average(prices: &[PricePoint]) -> Result<f64, RefinerError> {
+    if prices.is_empty() {
+        return Err(RefinerError::MissingData(
+            "No prices to average".to_string(),
+        ));
+    }
+    Ok(prices.iter().map(|price| price.value).sum::<f64>() / prices.len() as f64)
 }
 
-pub async fn lowest(
-    day: Day,
-    count: usize,
+pub fn median(prices: &[PricePoint]) -> Result<f64, RefinerError> {
+    let mut values: Vec<f64> = prices.iter().map(|price| price.value).collect();
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    let middle = values.len() / 2;
+    match values.len() {
+        0 => Err(RefinerError::MissingData(
+            "No prices to take the median of".to_string(),
+        )),
+        len if len % 2 == 0 => Ok((values[middle - 1] + values[middle]) / 2.0),
+        _ => Ok(values[middle]),
+    }
+}
+
+/// Population standard deviation of the day's prices
+pub fn stddev(prices: &[PricePoint]) -> Result<f64, RefinerError> {
+    let avg = average(prices)?;
+    let variance = prices
+        .iter()
+        .map(|price| (price.value - avg).powi(2))
+        .sum::<f64>()
+        / prices.len() as f64;
+    Ok(variance.sqrt())
+}
+
+/// `value` relative to `reference`, the plain ratio for a reference of at least
+/// `RATIO_FLOOR`. Against a smaller or negative reference it is 1 plus the difference relative
+/// to the reference's magnitude, so above 1 still means pricier than the reference
+pub fn ratio(value: f64, reference: f64) -> f64 {
+    1.0 + (value - reference) / reference.abs().max(RATIO_FLOOR)
+}
+
+pub fn price_ratio(now: usize, prices: &[PricePoint]) -> Result<f64, RefinerError> {
+    Ok(ratio(price_now(now, prices)?, average(prices)?))
+}
+
+/// The prices from hour `start` through hour `stop` since midnight
+fn window(prices: &[PricePoint], start: usize, stop: usize) -> Vec<IndexedPrice> {
+    prices
+        .iter()
+        .enumerate()
+        .filter(|(_, price)| (start..=stop).contains(&hour_of_day(price.start)))
+        .map(|(index, price)| (index, price.value))
+        .collect()
+}
+
+/// `hours` worth of prices from the window, a count of hours covers four quarter hour prices each
+pub fn highest(
+    prices: &[PricePoint],
+    hours: usize,
     start: usize,
     stop: usize,
-    client: &Client,
-) -> Result<Vec<HourPrice>, String> {
-    let mut prices: Vec<HourPrice> = get_prices(day, client)
-        .await?
+) -> Vec<IndexedPrice> {
+    let mut window = window(prices, start, stop);
+    window.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal));
+    window
         .into_iter()
-        .filter(|hour_price| start <= hour_price.0 && hour_price.0 <= stop)
-        .collect();
-    prices.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
-    Ok(prices.into_iter().take(count).collect())
+        .take(hours * points_per_hour(prices))
+        .collect()
 }
 
-pub async fn max(day: Day, client: &Client) -> Result<HourPrice, String> {
-    Ok(highest(day, 1, 0, 24, client)
-        .await?
-        .first()
-        .take()
-        .ok_or("Error taking first from highest")?
-        .to_owned())
-}
-
-pub async fn min(day: Day, client: &Client) -> Result<HourPrice, String> {
-    Ok(lowest(day, 1, 0, 24, client)
-        .await?
-        .first()
-        .take()
-        .ok_or("Error taking first from lowest")?
-        .to_owned())
+pub fn lowest(prices: &[PricePoint], hours: usize, start: usize, stop: usize) -> Vec<IndexedPrice> {
+    let mut window = window(prices, start, stop);
+    window.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+    window
+        .into_iter()
+        .take(hours * points_per_hour(prices))
+        .collect()
 }
 
-pub async fn rel_thresh(
-    day: Day,
+/// The prices whose ratio to the daily average is between the thresholds, as `ratio` defines
+/// it on days whose average is near zero or negative
+pub fn rel_thresh(
     mut low_thresh: f64,
     mut high_thresh: f64,
-    prices: &Vec<HourPrice>,
-    client: &Client,
-) -> Result<Vec<HourPrice>, String> {
+    prices: &[PricePoint],
+) -> Result<Vec<IndexedPrice>, RefinerError> {
     let avg = average(prices)?;
     if low_thresh > 1.0 {
         low_thresh /= 100.0;
     }
-    let low_val = low_thresh * avg;
-
     if high_thresh > 1.0 {
         high_thresh /= 100.0;
     }
-    let high_val = high_thresh * avg;
-    Ok(get_hour_price(day, client)
-        .await?
-        .into_iter()
-        .filter(|(_, price)| high_val > *price && *price > low_val)
+    Ok(prices
+        .iter()
+        .enumerate()
+        .map(|(index, price)| (index, price.value))
+        .filter(|(_, price)| {
+            let relative = ratio(*price, avg);
+            high_thresh > relative && relative > low_thresh
+        })
         .collect())
 }
 
-pub async fn within_thresh(
+pub fn within_thresh(
     now: usize,
     low_thresh: f64,
     high_thresh: f64,
-    prices: &Vec<HourPrice>,
-    client: &Client,
-) -> Result<bool, String> {
-    Ok(
-        rel_thresh(Day::Today, low_thresh, high_thresh, prices, client)
-            .await?
-            .iter()
-            .map(|hour_price| hour_price.0)
-            .any(|hour| hour == now),
-    )
-}
-pub async fn in_6_l_8(day: Day, now: usize, client: &Client) -> Result<bool, String> {
-    Ok(!(highest(day, 2, 0, 8, client)
-        .await?
+    prices: &[PricePoint],
+) -> Result<bool, RefinerError> {
+    Ok(rel_thresh(low_thresh, high_thresh, prices)?
         .iter()
         .map(|hour_price| hour_price.0)
         .any(|hour| hour == now))
-        && highest(day, 8, 0, 8, client)
-            .await?
+}
+
+/// Start index and average price of the cheapest `hours` long run of consecutive prices
+pub fn cheapest_window(prices: &[PricePoint], hours: usize) -> Option<(usize, f64)> {
+    let per_hour = points_per_hour(prices);
+    let length = hours * per_hour;
+    if length == 0 {
+        return None;
+    }
+    let span = chrono::Duration::minutes(((length - 1) * 60 / per_hour) as i64);
+    prices
+        .windows(length)
+        .enumerate()
+        // A run across missing prices isn't consecutive
+        .filter(|(_, window)| window[length - 1].start - window[0].start == span)
+        .map(|(start, window)| {
+            let avg = window.iter().map(|price| price.value).sum::<f64>() / length as f64;
+            (start, avg)
+        })
+        .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal))
+}
+
+/// Percent of the other `len` prices of a day that are cheaper than the price of `rank`
+pub fn percentile(rank: usize, len: usize) -> f64 {
+    if len < 2 {
+        return 0.0;
+    }
+    let cheaper = rank.saturating_sub(1);
+    100.0 * cheaper as f64 / (len - 1) as f64
+}
+
+/// Hours from the price at `now` to the cheapest price of the day, negative once it has passed
+pub fn hours_until_cheapest(now: usize, prices: &[PricePoint]) -> Result<f64, RefinerError> {
+    let cheapest = prices
+        .iter()
+        .min_by(|a, b| {
+            a.value
+                .partial_cmp(&b.value)
+                .unwrap_or(std::cmp::Ordering::Equal)
+        })
+        .ok_or_else(|| RefinerError::MissingData("No prices".to_string()))?;
+    let start = prices
+        .get(now)
+        .ok_or_else(|| RefinerError::MissingData(format!("No price at index {}", now)))?
+        .start;
+    Ok((cheapest.start - start).num_minutes() as f64 / 60.0)
+}
+
+/// Change from the price at `now` to the next one, none for the last price of the day
+pub fn delta_next(now: usize, prices: &[PricePoint]) -> Option<f64> {
+    Some(prices.get(now + 1)?.value - prices.get(now)?.value)
+}
+
+/// Least squares slope of the prices from `now` through `hours` hours ahead, per hour
+pub fn slope(now: usize, hours: usize, prices: &[PricePoint]) -> f64 {
+    let per_hour = points_per_hour(prices);
+    let upcoming: Vec<(f64, f64)> = prices
+        .iter()
+        .enumerate()
+        .skip(now)
+        .take(hours * per_hour + 1)
+        .map(|(index, price)| ((index - now) as f64 / per_hour as f64, price.value))
+        .collect();
+    if upcoming.len() < 2 {
+        return 0.0;
+    }
+    let n = upcoming.len() as f64;
+    let mean_x = upcoming.iter().map(|(x, _)| x).sum::<f64>() / n;
+    let mean_y = upcoming.iter().map(|(_, y)| y).sum::<f64>() / n;
+    let covariance: f64 = upcoming
+        .iter()
+        .map(|(x, y)| (x - mean_x) * (y - mean_y))
+        .sum();
+    let variance: f64 = upcoming.iter().map(|(x, _)| (x - mean_x).powi(2)).sum();
+    covariance / variance
+}
+
+/// 1 if the price rises over the next `hours`, -1 if it falls and 0 if it changes by less
+/// than `flat` percent of the daily average per hour
+pub fn trend(
+    now: usize,
+    hours: usize,
+    flat: f64,
+    prices: &[PricePoint],
+) -> Result<i8, RefinerError> {
+    let slope = slope(now, hours, prices);
+    let threshold = flat / 100.0 * average(prices)?.abs();
+    Ok(if slope > threshold {
+        1
+    } else if slope < -threshold {
+        -1
+    } else {
+        0
+    })
+}
+
+/// A day's prices in time order and what they tell on their own. Every method is pure, the
+/// days around it are in `Context`
+#[derive(Clone, Debug)]
+pub struct PriceDay {
+    prices: Vec<PricePoint>,
+    /// Rank of every price, from sorting the prices once rather than once per hour
+    ranks: Vec<usize>,
+}
+
+impl PriceDay {
+    pub fn new(prices: Vec<PricePoint>) -> PriceDay {
+        let mut sorted: Vec<f64> = prices.iter().map(|price| price.value).collect();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        // Equal prices share the rank of the first of them
+        let ranks = prices
+            .iter()
+            .map(|price| 1 + sorted.partition_point(|value| *value < price.value))
+            .collect();
+        PriceDay { prices, ranks }
+    }
+
+    pub fn prices(&self) -> &[PricePoint] {
+        &self.prices
+    }
+
+    pub fn len(&self) -> usize {
+        self.prices.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.prices.is_empty()
+    }
+
+    pub fn price(&self, index: usize) -> Result<f64, RefinerError> {
+        price_now(index, &self.prices)
+    }
+
+    pub fn average(&self) -> Result<f64, RefinerError> {
+        average(&self.prices)
+    }
+
+    pub fn median(&self) -> Result<f64, RefinerError> {
+        median(&self.prices)
+    }
+
+    pub fn stddev(&self) -> Result<f64, RefinerError> {
+        stddev(&self.prices)
+    }
+
+    /// Standard deviation relative to the magnitude of the average
+    pub fn variation(&self) -> Result<f64, RefinerError> {
+        Ok(self.stddev()? / self.average()?.abs().max(RATIO_FLOOR))
+    }
+
+    /// The price at `index` relative to the daily average
+    pub fn ratio(&self, index: usize) -> Result<f64, RefinerError> {
+        price_ratio(index, &self.prices)
+    }
+
+    /// The `n` cheapest hours from hour `start` through `stop` since midnight
+    pub fn cheapest(&self, n: usize, start: usize, stop: usize) -> Vec<IndexedPrice> {
+        // `highest` sorts ascending, so it takes the cheapest
+        highest(&self.prices, n, start, stop)
+    }
+
+    /// The `n` priciest hours from hour `start` through `stop` since midnight
+    pub fn priciest(&self, n: usize, start: usize, stop: usize) -> Vec<IndexedPrice> {
+        lowest(&self.prices, n, start, stop)
+    }
+
+    /// Whether the price at `index` is within `band`
+    pub fn within_band(&self, index: usize, band: &Band) -> Result<bool, RefinerError> {
+        within_thresh(index, band.low, band.high, &self.prices)
+    }
+
+    pub fn cheapest_window(&self, hours: usize) -> Option<(usize, f64)> {
+        cheapest_window(&self.prices, hours)
+    }
+
+    /// 1 for the cheapest price of the day, prices that are equal share a rank. 0 if out of range
+    pub fn rank(&self, index: usize) -> usize {
+        self.ranks.get(index).copied().unwrap_or(0)
+    }
+
+    /// Percent of the day's other prices that are cheaper than the price at `index`
+    pub fn percentile(&self, index: usize) -> f64 {
+        percentile(self.rank(index), self.prices.len())
+    }
+
+    pub fn hours_until_cheapest(&self, index: usize) -> Result<f64, RefinerError> {
+        hours_until_cheapest(index, &self.prices)
+    }
+
+    pub fn delta_next(&self, index: usize) -> Option<f64> {
+        delta_next(index, &self.prices)
+    }
+
+    pub fn trend(&self, index: usize, hours: usize, flat: f64) -> Result<i8, RefinerError> {
+        trend(index, hours, flat, &self.prices)
+    }
+
+    /// The first of the prices `pick` prefers over every earlier one
+    fn first_by(&self, pick: fn(f64, f64) -> bool) -> Result<&PricePoint, RefinerError> {
+        self.prices
             .iter()
-            .map(|hour_price| hour_price.0)
-            .any(|hour| hour == now))
+            .reduce(|best, price| {
+                if pick(price.value, best.value) {
+                    price
+                } else {
+                    best
+                }
+            })
+            .ok_or_else(|| RefinerError::MissingData("No prices".to_string()))
+    }
+
+    /// Hour on the clock of the priciest price, the first if several are equal
+    pub fn max_hour(&self) -> Result<u32, RefinerError> {
+        Ok(self.first_by(|price, best| price > best)?.start.hour())
+    }
+
+    /// Hour on the clock of the cheapest price, the first if several are equal
+    pub fn min_hour(&self) -> Result<u32, RefinerError> {
+        Ok(self.first_by(|price, best| price < best)?.start.hour())
+    }
+
+    /// The priciest price of the day
+    pub fn max_value(&self) -> Result<f64, RefinerError> {
+        Ok(self.first_by(|price, best| price > best)?.value)
+    }
+
+    /// The cheapest price of the day
+    pub fn min_value(&self) -> Result<f64, RefinerError> {
+        Ok(self.first_by(|price, best| price < best)?.value)
+    }
+
+    /// The priciest price less the cheapest
+    pub fn spread(&self) -> Result<f64, RefinerError> {
+        Ok(self.max_value()? - self.min_value()?)
+    }
+
+    /// The priciest price relative to the cheapest, as `ratio` defines it when the cheapest is
+    /// near zero or negative
+    pub fn spread_ratio(&self) -> Result<f64, RefinerError> {
+        Ok(ratio(self.max_value()?, self.min_value()?))
+    }
+}
+
+/// What the days around the refined day tell about its prices
+#[derive(Clone, Debug, Default)]
+pub struct Context {
+    /// Average price of the three days before, unset if none of them have prices
+    pub avg_3d: Option<f64>,
+    /// Average price of the seven days before, unset if none of them have prices
+    pub avg_7d: Option<f64>,
+    /// Median price of the seven days before, unset if none of them have prices
+    pub median_7d: Option<f64>,
+    /// Prices of the 30 days before by hour on the clock
+    pub by_hour: BTreeMap<u32, Vec<f64>>,
+    /// Prices of the day before at the same resolution, empty if they aren't stored
+    pub yesterday: Vec<PricePoint>,
+    /// Prices of the day after at the same resolution, empty until they are published
+    pub tomorrow: Vec<PricePoint>,
+    /// Energy used in the refined day, empty without consumption or before it is stored
+    pub consumption: Vec<PricePoint>,
+    /// Solar production forecast for the refined day in kWh per hour, empty without solar or if
+    /// the forecast isn't available. Not loaded from the store
+    pub production: Vec<PricePoint>,
 }
 
-pub async fn in_top(
-    day: Day,
+impl Context {
+    #[instrument(skip(store, config))]
+    pub async fn load(
+        date: NaiveDate,
+        store: &dyn PriceStore,
+        tz: Tz,
+        config: &RefinerConfig,
+        area: Option<&Area>,
+    ) -> Result<Context, RefinerError> {
+        let month = store
+            .read_prices_before(date, 30, tz, &config.source, area)
+            .await?;
+        let since = |days: i64| {
+            let first = date - chrono::Duration::days(days);
+            month
+                .iter()
+                .filter(|price| price.start.date().naive_local() >= first)
+                .copied()
+                .collect::<Vec<PricePoint>>()
+        };
+        let mut by_hour: BTreeMap<u32, Vec<f64>> = BTreeMap::new();
+        for price in &month {
+            by_hour
+                .entry(price.start.hour())
+                .or_default()
+                .push(price.value);
+        }
+        let yesterday = get_prices_at(Day::Yesterday.of(date), store, tz, config, area).await?;
+        let tomorrow = get_prices_at(Day::Tomorrow.of(date), store, tz, config, area).await?;
+        let consumption = match &config.consumption {
+            Some(consumption) => store.read_consumption(date, tz, consumption, area).await?,
+            None => Vec::new(),
+        };
+        Ok(Context {
+            avg_3d: average(&since(3)).ok(),
+            avg_7d: average(&since(7)).ok(),
+            median_7d: median(&since(7)).ok(),
+            by_hour,
+            yesterday,
+            tomorrow,
+            consumption,
+            production: Vec::new(),
+        })
+    }
+}
+
+/// Prices of a day around the refined one at the configured resolution, empty if they aren't
+/// stored
+pub async fn get_prices_at(
+    date: NaiveDate,
+    store: &dyn PriceStore,
+    tz: Tz,
+    config: &RefinerConfig,
+    area: Option<&Area>,
+) -> Result<Vec<PricePoint>, RefinerError> {
+    match store.read_prices(date, tz, &config.source, area).await {
+        Ok(prices) => Ok(match config.resolution {
+            Resolution::Hourly => hourly(&prices),
+            Resolution::Native => prices,
+        }),
+        Err(RefinerError::MissingData(_)) => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Yesterday's price at the same time on the clock as the price at `now`
+pub fn price_yesterday(now: usize, prices: &[PricePoint], context: &Context) -> Option<f64> {
+    let start = prices.get(now)?.start;
+    context
+        .yesterday
+        .iter()
+        .find(|price| price.start.hour() == start.hour() && price.start.minute() == start.minute())
+        .map(|price| price.value)
+}
+
+/// Energy used during the price at `now`. Finer consumption is summed, an hour's consumption is
+/// split evenly over its quarter hour prices
+pub fn consumption_at(now: usize, prices: &[PricePoint], context: &Context) -> Option<f64> {
+    let price = prices.get(now)?;
+    let per_hour = points_per_hour(prices);
+    let end = price.start + chrono::Duration::minutes(60 / per_hour as i64);
+    let within: Vec<f64> = context
+        .consumption
+        .iter()
+        .filter(|usage| price.start <= usage.start && usage.start < end)
+        .map(|usage| usage.value)
+        .collect();
+    if !within.is_empty() {
+        return Some(within.iter().sum());
+    }
+    context
+        .consumption
+        .iter()
+        .find(|usage| usage.start == hour_start(price.start))
+        .map(|usage| usage.value / per_hour as f64)
+}
+
+/// Solar production forecast for the price at `now`, a share of its hour's for quarter hour
+/// prices. None for hours the forecast doesn't cover
+pub fn production_at(now: usize, prices: &[PricePoint], context: &Context) -> Option<f64> {
+    let price = prices.get(now)?;
+    let per_hour = points_per_hour(prices);
+    context
+        .production
+        .iter()
+        .find(|produced| produced.start == hour_start(price.start))
+        .map(|produced| produced.value / per_hour as f64)
+}
+
+/// Cost of the energy used from midnight through the price at `now`, at the actual prices and
+/// at the day's average price. None while the energy used at `now` isn't known
+pub fn cost_so_far(
     now: usize,
-    start: usize,
-    stop: usize,
-    client: &Client,
-) -> Result<bool, String> {
-    Ok(highest(day, 3, start, stop, client)
-        .await?
+    prices: &[PricePoint],
+    context: &Context,
+) -> Result<Option<(f64, f64)>, RefinerError> {
+    if consumption_at(now, prices, context).is_none() {
+        return Ok(None);
+    }
+    let avg = average(prices)?;
+    let (mut actual, mut at_average) = (0.0, 0.0);
+    for (index, price) in prices.iter().enumerate().take(now + 1) {
+        if let Some(kwh) = consumption_at(index, prices, context) {
+            actual += kwh * price.value;
+            at_average += kwh * avg;
+        }
+    }
+    Ok(Some((actual, at_average)))
+}
+
+/// Whether the price at `now` is more than `factor` times the median of the seven days before,
+/// up or down. False without history or unless the median is positive
+pub fn anomaly(now: usize, prices: &[PricePoint], context: &Context, factor: f64) -> bool {
+    match (prices.get(now), context.median_7d) {
+        (Some(price), Some(median)) if median > 0.0 => price.value.abs() > factor * median,
+        _ => false,
+    }
+}
+
+/// Percent of the last 30 days' prices for the same hour on the clock that are cheaper than
+/// the price at `now`, none without history for that hour
+pub fn percentile_30d(now: usize, prices: &[PricePoint], context: &Context) -> Option<f64> {
+    let price = prices.get(now)?;
+    let history = context.by_hour.get(&price.start.hour())?;
+    if history.is_empty() {
+        return None;
+    }
+    let cheaper = history.iter().filter(|value| **value < price.value).count();
+    Some(100.0 * cheaper as f64 / history.len() as f64)
+}
+
+/// The prices from `now` through the next 24 hours, as far as they are known
+fn next_24(now: usize, prices: &[PricePoint]) -> &[PricePoint] {
+    let end = (now + 24 * points_per_hour(prices)).min(prices.len());
+    prices.get(now..end).unwrap_or_default()
+}
+
+/// Tibber's classification of the price at `now` relative to `reference`, the trailing average
+pub fn price_level(
+    now: usize,
+    prices: &[PricePoint],
+    reference: f64,
+) -> Result<&'static str, RefinerError> {
+    let percent = 100.0 * ratio(price_now(now, prices)?, reference);
+    Ok(if percent <= 60.0 {
+        "VERY_CHEAP"
+    } else if percent <= 90.0 {
+        "CHEAP"
+    } else if percent < 115.0 {
+        "NORMAL"
+    } else if percent < 140.0 {
+        "EXPENSIVE"
+    } else {
+        "VERY_EXPENSIVE"
+    })
+}
+
+/// The electricity subsidy per kWh for a spot price, before VAT
+pub fn subsidy(spot: f64, costs: &CostConfig) -> f64 {
+    (spot - costs.subsidy_threshold).max(0.0) * costs.subsidy_percent / 100.0
+}
+
+/// What the household pays per kWh for a spot price, with markup and VAT and less the subsidy
+pub fn consumer_price(spot: f64, costs: &CostConfig) -> f64 {
+    (spot + costs.markup - subsidy(spot, costs)) * (1.0 + costs.vat_percent / 100.0)
+}
+
+/// The prices as consumer prices
+pub fn with_costs(prices: &[PricePoint], costs: &CostConfig) -> Vec<PricePoint> {
+    prices
         .iter()
-        .map(|hour_price| hour_price.0)
-        .any(|hour| hour == now))
+        .map(|price| PricePoint {
+            start: price.start,
+            value: consumer_price(price.value, costs),
+        })
+        .collect()
+}
+
+/// Share of the monthly fee for `date`, with VAT
+pub fn daily_fee(date: NaiveDate, costs: &CostConfig) -> f64 {
+    let first = NaiveDate::from_ymd(date.year(), date.month(), 1);
+    let next = match date.month() {
+        12 => NaiveDate::from_ymd(date.year() + 1, 1, 1),
+        month => NaiveDate::from_ymd(date.year(), month + 1, 1),
+    };
+    let days = (next - first).num_days() as f64;
+    costs.monthly_fee * (1.0 + costs.vat_percent / 100.0) / days
 }
 
-pub async fn in_8_low(now: usize, client: &Client) -> Result<bool, String> {
-    Ok(lowest(Day::Today, 8, 0, 8, client)
-        .await?
+/// Grid tariff for a price starting at `start`. With a calendar its peak hours are charged the
+/// day rate and holidays the weekend rate, instead of the tariff's own hours
+pub fn grid_tariff(
+    start: DateTime<Tz>,
+    tariff: &TariffConfig,
+    calendar: Option<&CalendarConfig>,
+) -> f64 {
+    if let Some(calendar) = calendar {
+        return match start.date().naive_local() {
+            date if calendar::is_off_day(date, calendar) => tariff.weekend,
+            _ if calendar::is_peak(start, calendar) => tariff.day,
+            _ => tariff.night,
+        };
+    }
+    match start.weekday() {
+        Weekday::Sat | Weekday::Sun => tariff.weekend,
+        _ if (tariff.day_start..tariff.day_end).contains(&start.hour()) => tariff.day,
+        _ => tariff.night,
+    }
+}
+
+/// The prices with the grid tariff added
+pub fn with_tariff(
+    prices: &[PricePoint],
+    tariff: &TariffConfig,
+    calendar: Option<&CalendarConfig>,
+) -> Vec<PricePoint> {
+    prices
         .iter()
-        .map(|hour_price| hour_price.0)
-        .any(|hour| hour == now))
+        .map(|price| PricePoint {
+            start: price.start,
+            value: price.value + grid_tariff(price.start, tariff, calendar),
+        })
+        .collect()
 }
 
-#[derive(InfluxDbWriteable, Debug)]
-struct Refined {
-    time: chrono::DateTime<chrono_tz::Tz>,
-    #[influxdb(tag)]
-    hour: u32,
-    #[influxdb(tag)]
-    date: String,
-    pris_snitt_24: f64,
-    in_6_l_8: bool,
-    in_0_6_high: bool,
-    in_6_12_high: bool,
-    in_12_18_high: bool,
-    in_18_24_high: bool,
-    t90_115: bool,
-    t60_90: bool,
-    t0_60: bool,
-    t115_140: bool,
-    t140_999: bool,
-    i8h_low: bool,
-    pris_time: f64,
-    pris_forhold_24: f64,
-    pris_max: u32,
-    pris_min: u32,
-}
-
-pub async fn refine(hour: usize, client: &Client) -> Result<(), String> {
-    let prices = get_prices(Day::Today, client).await?;
-
-    let fut_in_6_l_8 = in_6_l_8(Day::Today, hour, client);
-    let fut_in_0_6_high = in_top(Day::Today, hour, 0, 6, client);
-    let fut_in_6_12_high = in_top(Day::Today, hour, 6, 12, client);
-    let fut_in_12_18_high = in_top(Day::Today, hour, 12, 18, client);
-    let fut_in_18_24_high = in_top(Day::Today, hour, 18, 24, client);
-    let fut_t90_115 = within_thresh(hour, 90.0, 115.0, &prices, client);
-    let fut_t60_90 = within_thresh(hour, 60.0, 90.0, &prices, client);
-    let fut_t0_60 = within_thresh(hour, 0.0, 60.0, &prices, client);
-    let fut_t115_140 = within_thresh(hour, 115.0, 140.0, &prices, client);
-    let fut_t140_999 = within_thresh(hour, 140.0, 999.0, &prices, client);
-    let fut_i8h_low = in_8_low(hour, client);
-    let fut_pris_max = max(Day::Today, client);
-    let fut_pris_min = min(Day::Today, client);
-
-    let (
-        in_6_l_8,
-        in_0_6_high,
-        in_6_12_high,
-        in_12_18_high,
-        in_18_24_high,
-        t90_115,
-        t60_90,
-        t0_60,
-        t115_140,
-        t140_999,
-        i8h_low,
-        pris_max,
-        pris_min,
-    ) = tokio::join!(
-        fut_in_6_l_8,
-        fut_in_0_6_high,
-        fut_in_6_12_high,
-        fut_in_12_18_high,
-        fut_in_18_24_high,
-        fut_t90_115,
-        fut_t60_90,
-        fut_t0_60,
-        fut_t115_140,
-        fut_t140_999,
-        fut_i8h_low,
-        fut_pris_max,
-        fut_pris_min
-    );
-
-    let refined = Refined {
-        time: chrono::Utc::now().with_timezone(&Oslo)
-            .date()
-            .and_hms(0, 0, 0)
-            .checked_add_signed(chrono::Duration::hours(hour as i64))
-            .ok_or("Datetime overflow")?,
-        hour: hour as u32,
-        date: chrono::Utc::now().with_timezone(&Oslo)
-            .date()
-            .and_hms(0, 0, 0)
-            .to_rfc3339()
-            .split('T')
-            .into_iter()
-            .next()
-            .unwrap()
-            .to_string(),
-        pris_snitt_24: average(&prices)?,
-        pris_time: price_now(hour, &prices)?,
-        pris_forhold_24: price_ratio(hour, &prices)?,
-        pris_max: pris_max?.0 as u32,
-        pris_min: pris_min?.0 as u32,
-        in_6_l_8: in_6_l_8?,
-        in_0_6_high: in_0_6_high?,
-        in_6_12_high: in_6_12_high?,
-        in_12_18_high: in_12_18_high?,
-        in_18_24_high: in_18_24_high?,
-        t90_115: t90_115?,
-        t60_90: t60_90?,
-        t0_60: t0_60?,
-        t115_140: t115_140?,
-        t140_999: t140_999?,
-        i8h_low: i8h_low?,
+/// The prices less what the forecast solar production saves a load of `load_kw`, the share of
+/// the load it covers at the price less what the energy would have earned fed into the grid
+pub fn with_production(
+    prices: &[PricePoint],
+    production: &[PricePoint],
+    solar: &SolarConfig,
+) -> Vec<PricePoint> {
+    prices
+        .iter()
+        .map(|price| {
+            let produced = production
+                .iter()
+                .find(|produced| produced.start == hour_start(price.start))
+                .map_or(0.0, |produced| produced.value);
+            let covered = produced.min(solar.load_kw) / solar.load_kw;
+            PricePoint {
+                start: price.start,
+                value: price.value * (1.0 - covered * (1.0 - solar.export_ratio)),
+            }
+        })
+        .collect()
+}
+
+/// Fields of `Refined` that identify a row rather than describe it
+pub const ROW_KEYS: [&str; 6] = ["time", "date", "hour", "currency", "area", "forecast"];
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Refined {
+    pub time: chrono::DateTime<chrono_tz::Tz>,
+    pub hour: u32,
+    pub date: String,
+    pub currency: String,
+    /// Name of the configured area, unset with a single area
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub area: Option<String>,
+    /// Whether the prices are forecast rather than published, only set on forecasts
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub forecast: bool,
+    /// Whether the day has a price for every hour
+    pub data_complete: bool,
+    /// Hours of the day without a price, the statistics are of the others
+    pub missing_hours: u32,
+    /// Whether the prices were read from the cache because reading the source failed, only set
+    /// then
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub stale_source: bool,
+    pub pris_snitt_24: f64,
+    pub pris_median: f64,
+    pub pris_stddev: f64,
+    /// Standard deviation relative to the average, low on flat days
+    pub pris_variasjon: f64,
+    pub pris_time: f64,
+    /// Whether the price is below zero, paid to use energy
+    pub negativ_pris: bool,
+    pub pris_forhold_24: f64,
+    /// Hour on the clock of the cheapest price, named before the values were refined
+    pub pris_max: u32,
+    /// Hour on the clock of the priciest price, named before the values were refined
+    pub pris_min: u32,
+    /// The priciest price of the day
+    pub pris_max_verdi: f64,
+    /// The cheapest price of the day
+    pub pris_min_verdi: f64,
+    /// The priciest price less the cheapest
+    pub pris_spredning: f64,
+    /// The priciest price relative to the cheapest
+    pub pris_spredning_forhold: f64,
+    /// Percent of the day's other prices that are cheaper, 0 for the cheapest hour
+    pub pris_persentil: f64,
+    /// Rank of the price within the day, 1 for the cheapest. Equal prices share a rank
+    pub pris_rang: u32,
+    /// Change from the same hour yesterday, unset if yesterday has no prices
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pris_diff_i_gaar: Option<f64>,
+    /// The price relative to the same hour yesterday, unset if yesterday has no prices
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pris_forhold_i_gaar: Option<f64>,
+    /// Percent of the last 30 days' prices for the same hour that are cheaper, unset without
+    /// history
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pris_persentil_30d: Option<f64>,
+    /// Hours until the cheapest price of the day, negative once it has passed
+    pub timer_til_billigst: f64,
+    /// Change to the next price, unset for the last price of the day
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pris_delta_neste: Option<f64>,
+    /// 1 if the price is rising over the next hours, -1 if falling and 0 if flat
+    pub trend: i8,
+    /// Whether the price is far from the median of the seven days before, likely a bad import
+    pub anomaly: bool,
+    /// Average of the seven days before, unset without history
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pris_snitt_7d: Option<f64>,
+    /// The price relative to the average of the seven days before, unset without history
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pris_forhold_7d: Option<f64>,
+    /// Tibber's price level, VERY_CHEAP through VERY_EXPENSIVE against the three days before
+    pub price_level: String,
+    /// Average of today and tomorrow, unset until tomorrow's prices are published
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pris_snitt_48: Option<f64>,
+    /// Whether the price is among the cheapest of the next 24 hours, across midnight
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub in_cheapest_next_24: Option<bool>,
+    /// Hours until the cheapest upcoming run of each configured length starts, across midnight
+    #[serde(flatten)]
+    pub next_window_starts: BTreeMap<String, f64>,
+    /// Average price of the cheapest upcoming run of each configured length, across midnight
+    #[serde(flatten)]
+    pub next_window_averages: BTreeMap<String, f64>,
+    /// Hour on the clock the cheapest run of each configured length starts, by field name
+    #[serde(flatten)]
+    pub window_starts: BTreeMap<String, u32>,
+    /// Average price of the cheapest run of each configured length, by field name
+    #[serde(flatten)]
+    pub window_averages: BTreeMap<String, f64>,
+    /// Whether the price is within each configured band, by band name
+    #[serde(flatten)]
+    pub bands: BTreeMap<String, bool>,
+    /// Subsidy per kWh before VAT, unset without costs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pris_stotte: Option<f64>,
+    /// What the household pays per kWh with markup and VAT and less the subsidy, unset without
+    /// costs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pris_effektiv: Option<f64>,
+    /// The effective price relative to the day's average effective price, unset without costs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pris_forhold_effektiv: Option<f64>,
+    /// The day's share of the monthly fee, unset without costs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fastbelop_dag: Option<f64>,
+    /// Forecast solar production in kWh, unset without solar or outside the forecast
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sol_produksjon: Option<f64>,
+    /// The price less what the forecast solar production saves, unset like `sol_produksjon`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pris_netto: Option<f64>,
+    /// Whether the hour is in the peak hours of a working day, unset without a calendar
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peak: Option<bool>,
+    /// The opposite of `peak`, unset without a calendar
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offpeak: Option<bool>,
+    /// Spot price, or effective price with costs, and grid tariff, unset without a tariff
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pris_total: Option<f64>,
+    /// The total price relative to the day's average total price, unset without a tariff
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pris_forhold_total: Option<f64>,
+    /// Whether the total price is within each configured band, by band name with a `_total`
+    /// suffix
+    #[serde(flatten)]
+    pub total_bands: BTreeMap<String, bool>,
+    /// The result of each configured rule, by rule name
+    #[serde(flatten)]
+    pub rules: BTreeMap<String, bool>,
+    /// Energy used in kWh, unset without consumption or before it is stored
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub forbruk: Option<f64>,
+    /// What the energy used cost at the spot price, unset like `forbruk`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kostnad_time: Option<f64>,
+    /// What the energy used since midnight cost at the spot price, unset like `forbruk`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kostnad_dag: Option<f64>,
+    /// What the energy used since midnight would have cost at the day's average price, unset
+    /// like `forbruk`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kostnad_dag_snittpris: Option<f64>,
+    /// Whether to charge the car in this hour, only set with a charging plan
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub charge_now: Option<bool>,
+    /// Forecast outdoor temperature in °C, only set with weather for the next two days
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temp_ute: Option<f64>,
+    /// Whether the hour is both cold and expensive, set along with `temp_ute`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kaldt_og_dyrt: Option<bool>,
+}
+
+/// Fields of the cheapest run of `hours` of the day, its start and average, and of the next 24
+/// hours, when it starts and its average
+fn window_fields(hours: usize) -> [String; 4] {
+    [
+        format!("cheapest_{}h_start", hours),
+        format!("cheapest_{}h_avg", hours),
+        format!("next_cheapest_{}h_in", hours),
+        format!("next_cheapest_{}h_avg", hours),
+    ]
+}
+
+/// Field of a band on the total price
+pub fn total_field(band: &str) -> String {
+    format!("{}_total", band)
+}
+
+impl Refined {
+    /// A row with every field set that rows refined with `config` can have, named like them.
+    /// Serialized, it lists the fields for outputs that declare them up front
+    pub fn schema(config: &RefinerConfig) -> Refined {
+        let mut window_starts = BTreeMap::new();
+        let mut window_averages = BTreeMap::new();
+        let mut next_window_starts = BTreeMap::new();
+        let mut next_window_averages = BTreeMap::new();
+        for hours in &config.windows {
+            let [start, avg, next_in, next_avg] = window_fields(*hours);
+            window_starts.insert(start, 0);
+            window_averages.insert(avg, 0.0);
+            next_window_starts.insert(next_in, 0.0);
+            next_window_averages.insert(next_avg, 0.0);
+        }
+        let flags = |names: Vec<String>| names.into_iter().map(|name| (name, false)).collect();
+        let bands = config.bands.iter().map(|band| band.name.clone()).collect();
+        let total_bands = config
+            .bands
+            .iter()
+            .map(|band| total_field(&band.name))
+            .collect();
+        let rules = config.rules.iter().map(|rule| rule.name.clone()).collect();
+        Refined {
+            time: chrono_tz::UTC.timestamp(0, 0),
+            hour: 0,
+            date: String::new(),
+            currency: config.source.currency.clone(),
+            area: Some(String::new()),
+            forecast: true,
+            data_complete: true,
+            missing_hours: 0,
+            stale_source: true,
+            pris_snitt_24: 0.0,
+            pris_median: 0.0,
+            pris_stddev: 0.0,
+            pris_variasjon: 0.0,
+            pris_time: 0.0,
+            negativ_pris: false,
+            pris_forhold_24: 0.0,
+            pris_max: 0,
+            pris_min: 0,
+            pris_max_verdi: 0.0,
+            pris_min_verdi: 0.0,
+            pris_spredning: 0.0,
+            pris_spredning_forhold: 0.0,
+            pris_persentil: 0.0,
+            pris_rang: 0,
+            pris_diff_i_gaar: Some(0.0),
+            pris_forhold_i_gaar: Some(0.0),
+            pris_persentil_30d: Some(0.0),
+            timer_til_billigst: 0.0,
+            pris_delta_neste: Some(0.0),
+            trend: 0,
+            anomaly: false,
+            pris_snitt_7d: Some(0.0),
+            pris_forhold_7d: Some(0.0),
+            price_level: String::new(),
+            pris_snitt_48: Some(0.0),
+            in_cheapest_next_24: Some(false),
+            next_window_starts,
+            next_window_averages,
+            window_starts,
+            window_averages,
+            bands: flags(bands),
+            pris_stotte: Some(0.0),
+            pris_effektiv: Some(0.0),
+            pris_forhold_effektiv: Some(0.0),
+            fastbelop_dag: Some(0.0),
+            sol_produksjon: Some(0.0),
+            pris_netto: Some(0.0),
+            peak: Some(false),
+            offpeak: Some(false),
+            pris_total: Some(0.0),
+            pris_forhold_total: Some(0.0),
+            total_bands: flags(total_bands),
+            rules: flags(rules),
+            forbruk: Some(0.0),
+            kostnad_time: Some(0.0),
+            kostnad_dag: Some(0.0),
+            kostnad_dag_snittpris: Some(0.0),
+            charge_now: Some(false),
+            temp_ute: Some(0.0),
+            kaldt_og_dyrt: Some(false),
+        }
+    }
+
+    /// `hour`, `date`, `currency`, `area` and `forecast` are tags, everything else is a field
+    #[cfg(feature = "influx")]
+    pub fn to_query(&self, measurement: &str) -> WriteQuery {
+        let mut query = Timestamp::from(self.time)
+            .into_query(measurement)
+            .add_tag("hour", self.hour)
+            .add_tag("date", self.date.clone())
+            .add_tag("currency", self.currency.clone())
+            .add_field("pris_snitt_24", self.pris_snitt_24)
+            .add_field("pris_median", self.pris_median)
+            .add_field("pris_stddev", self.pris_stddev)
+            .add_field("pris_variasjon", self.pris_variasjon)
+            .add_field("pris_time", self.pris_time)
+            .add_field("negativ_pris", self.negativ_pris)
+            .add_field("pris_forhold_24", self.pris_forhold_24)
+            .add_field("pris_max", self.pris_max)
+            .add_field("pris_min", self.pris_min)
+            .add_field("pris_max_verdi", self.pris_max_verdi)
+            .add_field("pris_min_verdi", self.pris_min_verdi)
+            .add_field("pris_spredning", self.pris_spredning)
+            .add_field("pris_spredning_forhold", self.pris_spredning_forhold)
+            .add_field("pris_persentil", self.pris_persentil)
+            .add_field("pris_rang", self.pris_rang)
+            .add_field("timer_til_billigst", self.timer_til_billigst)
+            .add_field("trend", self.trend)
+            .add_field("anomaly", self.anomaly)
+            .add_field("price_level", self.price_level.clone())
+            .add_field("data_complete", self.data_complete)
+            .add_field("missing_hours", self.missing_hours)
+            // Always written, so a day refined again from the source clears it
+            .add_field("stale_source", self.stale_source);
+        if let Some(area) = &self.area {
+            query = query.add_tag("area", area.clone());
+        }
+        if self.forecast {
+            query = query.add_tag("forecast", true);
+        }
+        if let (Some(avg), Some(ratio)) = (self.pris_snitt_7d, self.pris_forhold_7d) {
+            query = query
+                .add_field("pris_snitt_7d", avg)
+                .add_field("pris_forhold_7d", ratio);
+        }
+        if let (Some(diff), Some(ratio)) = (self.pris_diff_i_gaar, self.pris_forhold_i_gaar) {
+            query = query
+                .add_field("pris_diff_i_gaar", diff)
+                .add_field("pris_forhold_i_gaar", ratio);
+        }
+        if let Some(percentile) = self.pris_persentil_30d {
+            query = query.add_field("pris_persentil_30d", percentile);
+        }
+        if let Some(avg) = self.pris_snitt_48 {
+            query = query.add_field("pris_snitt_48", avg);
+        }
+        if let Some(cheapest) = self.in_cheapest_next_24 {
+            query = query.add_field("in_cheapest_next_24", cheapest);
+        }
+        for (name, value) in self
+            .next_window_starts
+            .iter()
+            .chain(&self.next_window_averages)
+        {
+            query = query.add_field(name.as_str(), *value);
+        }
+        if let Some(delta) = self.pris_delta_neste {
+            query = query.add_field("pris_delta_neste", delta);
+        }
+        for (name, start) in &self.window_starts {
+            query = query.add_field(name.as_str(), *start);
+        }
+        for (name, avg) in &self.window_averages {
+            query = query.add_field(name.as_str(), *avg);
+        }
+        let costs = [
+            ("pris_stotte", self.pris_stotte),
+            ("pris_effektiv", self.pris_effektiv),
+            ("pris_forhold_effektiv", self.pris_forhold_effektiv),
+            ("fastbelop_dag", self.fastbelop_dag),
+        ];
+        for (name, value) in costs {
+            if let Some(value) = value {
+                query = query.add_field(name, value);
+            }
+        }
+        if let (Some(produced), Some(net)) = (self.sol_produksjon, self.pris_netto) {
+            query = query
+                .add_field("sol_produksjon", produced)
+                .add_field("pris_netto", net);
+        }
+        if let (Some(peak), Some(offpeak)) = (self.peak, self.offpeak) {
+            query = query.add_field("peak", peak).add_field("offpeak", offpeak);
+        }
+        if let (Some(total), Some(ratio)) = (self.pris_total, self.pris_forhold_total) {
+            query = query
+                .add_field("pris_total", total)
+                .add_field("pris_forhold_total", ratio);
+        }
+        let bands = self.bands.iter().chain(&self.total_bands);
+        for (name, value) in bands.chain(&self.rules) {
+            query = query.add_field(name.as_str(), *value);
+        }
+        let consumption = [
+            ("forbruk", self.forbruk),
+            ("kostnad_time", self.kostnad_time),
+            ("kostnad_dag", self.kostnad_dag),
+            ("kostnad_dag_snittpris", self.kostnad_dag_snittpris),
+        ];
+        for (name, value) in consumption {
+            if let Some(value) = value {
+                query = query.add_field(name, value);
+            }
+        }
+        if let Some(charge_now) = self.charge_now {
+            query = query.add_field("charge_now", charge_now);
+        }
+        if let (Some(temperature), Some(cold)) = (self.temp_ute, self.kaldt_og_dyrt) {
+            query = query
+                .add_field("temp_ute", temperature)
+                .add_field("kaldt_og_dyrt", cold);
+        }
+        query
+    }
+}
+
+/// The rows produced by the latest successful tick
+pub type SharedRows = Arc<RwLock<Vec<Refined>>>;
+
+/// The latest row of the first area started by `now`, which the clock hour can't tell apart
+/// when daylight saving time ends or with quarter hour rows
+pub fn row_at<T: TimeZone>(rows: &[Refined], now: DateTime<T>) -> Option<&Refined> {
+    let area = &rows.first()?.area;
+    rows.iter()
+        .rev()
+        .filter(|row| row.area == *area)
+        .find(|row| row.time <= now)
+        .filter(|row| now < row.time + chrono::Duration::hours(1))
+}
+
+/// Computes the refined row for the price at `index` from the analysis of the day, its context
+/// and the rules compiled for it
+pub fn refine(
+    date: NaiveDate,
+    index: usize,
+    day: &PriceDay,
+    context: &Context,
+    rules: &DayRules,
+    config: &RefinerConfig,
+) -> Result<Refined, RefinerError> {
+    let prices = day.prices();
+    let time = prices
+        .get(index)
+        .ok_or_else(|| RefinerError::MissingData(format!("No price at index {}", index)))?
+        .start;
+    // Cheapest windows prefer hours of solar production when it is forecast
+    let net = match &config.solar {
+        Some(solar) if !context.production.is_empty() => Some(PriceDay::new(with_production(
+            prices,
+            &context.production,
+            solar,
+        ))),
+        _ => None,
+    };
+    let mut window_starts = BTreeMap::new();
+    let mut window_averages = BTreeMap::new();
+    for hours in &config.windows {
+        let windowed = net.as_ref().unwrap_or(day);
+        let (start, avg) = windowed.cheapest_window(*hours).ok_or_else(|| {
+            RefinerError::MissingData(format!("Fewer than {} hours of prices", hours))
+        })?;
+        let [start_field, avg_field, _, _] = window_fields(*hours);
+        window_starts.insert(start_field, prices[start].start.hour());
+        window_averages.insert(avg_field, avg);
+    }
+
+    let mut pris_snitt_48 = None;
+    let mut in_cheapest_next_24 = None;
+    let mut next_window_starts = BTreeMap::new();
+    let mut next_window_averages = BTreeMap::new();
+    if !context.tomorrow.is_empty() {
+        let both: Vec<PricePoint> = prices.iter().chain(&context.tomorrow).copied().collect();
+        let upcoming = next_24(index, &both);
+        let per_hour = points_per_hour(upcoming);
+        pris_snitt_48 = Some(average(&both)?);
+        // `highest` sorts ascending, so it takes the cheapest. The current price is the first
+        in_cheapest_next_24 = Some(
+            highest(upcoming, config.rolling_cheapest, 0, 24)
+                .iter()
+                .any(|(upcoming, _)| *upcoming == 0),
+        );
+        for hours in &config.windows {
+            if let Some((start, avg)) = cheapest_window(upcoming, *hours) {
+                let [_, _, in_field, avg_field] = window_fields(*hours);
+                next_window_starts.insert(in_field, start as f64 / per_hour as f64);
+                next_window_averages.insert(avg_field, avg);
+            }
+        }
+    }
+
+    let price = day.price(index)?;
+    let yesterday = price_yesterday(index, prices, context);
+    let forbruk = consumption_at(index, prices, context);
+    let cost = cost_so_far(index, prices, context)?;
+
+    let effective = config
+        .costs
+        .as_ref()
+        .map(|costs| PriceDay::new(with_costs(prices, costs)));
+    let pris_effektiv = match &effective {
+        Some(effective) => Some(effective.price(index)?),
+        None => None,
+    };
+    let pris_forhold_effektiv = match &effective {
+        Some(effective) => Some(effective.ratio(index)?),
+        None => None,
     };
 
-    let write_query = refined.into_query("refined");
+    let mut pris_total = None;
+    let mut pris_forhold_total = None;
+    let mut total_bands = BTreeMap::new();
+    if let Some(tariff) = &config.tariff {
+        let before = effective.as_ref().unwrap_or(day).prices();
+        let calendar = config.calendar.as_ref();
+        let totals = PriceDay::new(with_tariff(before, tariff, calendar));
+        pris_total = Some(totals.price(index)?);
+        pris_forhold_total = Some(totals.ratio(index)?);
+        for band in &config.bands {
+            total_bands.insert(total_field(&band.name), totals.within_band(index, band)?);
+        }
+    }
+
+    let mut bands = BTreeMap::new();
+    for band in &config.bands {
+        bands.insert(band.name.clone(), day.within_band(index, band)?);
+    }
+    let sol_produksjon = net.as_ref().and(production_at(index, prices, context));
+    let pris_netto = match (&net, sol_produksjon) {
+        (Some(net), Some(_)) => Some(net.price(index)?),
+        _ => None,
+    };
+    let peak = config
+        .calendar
+        .as_ref()
+        .map(|calendar| calendar::is_peak(time, calendar));
+    Ok(Refined {
+        time,
+        hour: time.hour(),
+        date: date.to_string(),
+        currency: config.source.currency.clone(),
+        area: None,
+        forecast: false,
+        data_complete: true,
+        missing_hours: 0,
+        stale_source: false,
+        pris_snitt_24: day.average()?,
+        pris_median: day.median()?,
+        pris_stddev: day.stddev()?,
+        pris_variasjon: day.variation()?,
+        pris_time: price,
+        negativ_pris: price < 0.0,
+        pris_forhold_24: day.ratio(index)?,
+        // Named the other way around before the values were refined, pris_max is the cheapest hour
+        pris_max: day.min_hour()?,
+        pris_min: day.max_hour()?,
+        pris_max_verdi: day.max_value()?,
+        pris_min_verdi: day.min_value()?,
+        pris_spredning: day.spread()?,
+        pris_spredning_forhold: day.spread_ratio()?,
+        pris_persentil: day.percentile(index),
+        pris_rang: day.rank(index) as u32,
+        pris_diff_i_gaar: yesterday.map(|yesterday| price - yesterday),
+        pris_forhold_i_gaar: yesterday.map(|yesterday| ratio(price, yesterday)),
+        pris_persentil_30d: percentile_30d(index, prices, context),
+        timer_til_billigst: day.hours_until_cheapest(index)?,
+        pris_delta_neste: day.delta_next(index),
+        trend: day.trend(index, config.trend_hours, config.trend_flat)?,
+        anomaly: anomaly(index, prices, context, config.anomaly_factor),
+        pris_snitt_7d: context.avg_7d,
+        pris_forhold_7d: context.avg_7d.map(|avg| ratio(price, avg)),
+        // Without history the day is its own reference, like the ratio fields
+        price_level: price_level(index, prices, context.avg_3d.unwrap_or(day.average()?))?
+            .to_string(),
+        pris_snitt_48,
+        in_cheapest_next_24,
+        next_window_starts,
+        next_window_averages,
+        window_starts,
+        window_averages,
+        bands,
+        pris_stotte: config.costs.as_ref().map(|costs| subsidy(price, costs)),
+        pris_effektiv,
+        pris_forhold_effektiv,
+        fastbelop_dag: config.costs.as_ref().map(|costs| daily_fee(date, costs)),
+        sol_produksjon,
+        pris_netto,
+        peak,
+        offpeak: peak.map(|peak| !peak),
+        pris_total,
+        pris_forhold_total,
+        total_bands,
+        rules: rules.evaluate(index)?,
+        forbruk,
+        kostnad_time: forbruk.map(|kwh| kwh * price),
+        kostnad_dag: cost.map(|(actual, _)| actual),
+        kostnad_dag_snittpris: cost.map(|(_, at_average)| at_average),
+        charge_now: None,
+        temp_ute: None,
+        kaldt_og_dyrt: None,
+    })
+}
+
+/// Renders rows as Influx line protocol, one line per row
+#[cfg(feature = "influx")]
+pub fn line_protocol(rows: &[Refined], measurement: &str) -> Result<String, RefinerError> {
+    let write_queries: Vec<WriteQuery> = rows
+        .iter()
+        .map(|refined| refined.to_query(measurement))
+        .collect();
+    Ok(write_queries
+        .build()
+        .map_err(|e| RefinerError::Write(e.to_string()))?
+        .get())
+}
+
+/// Writes all rows in a single batched query
+#[cfg(feature = "influx")]
+#[instrument(skip_all, fields(rows = rows.len()))]
+pub async fn write_refined(
+    rows: &[Refined],
+    db: &Db,
+    measurement: &str,
+) -> Result<(), RefinerError> {
+    if rows.is_empty() {
+        return Ok(());
+    }
+    let write_queries: Vec<WriteQuery> = rows
+        .iter()
+        .map(|refined| refined.to_query(measurement))
+        .collect();
+
+    db.write(write_queries).await?;
+    metrics::HOURS_REFINED.inc_by(rows.len() as u64);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono_tz::Europe::Oslo;
+
+    use super::*;
+
+    /// A day of hourly prices, `values[i]` at hour `i`
+    fn day(values: &[f64]) -> PriceDay {
+        let midnight = start_of_day(NaiveDate::from_ymd(2023, 1, 10), Oslo).unwrap();
+        PriceDay::new(
+            values
+                .iter()
+                .enumerate()
+                .map(|(hour, value)| PricePoint {
+                    start: midnight + chrono::Duration::hours(hour as i64),
+                    value: *value,
+                })
+                .collect(),
+        )
+    }
 
-    let write_result = client.query(write_query).await;
+    /// 1 at midnight rising by 1 every hour through 24
+    fn rising() -> PriceDay {
+        day(&(1..=24).map(f64::from).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn statistics() {
+        let day = rising();
+        assert_eq!(day.average().unwrap(), 12.5);
+        assert_eq!(day.median().unwrap(), 12.5);
+        assert!((day.ratio(0).unwrap() - 1.0 / 12.5).abs() < 1e-9);
+        assert_eq!(day.max_hour().unwrap(), 23);
+        assert_eq!(day.min_hour().unwrap(), 0);
+        assert_eq!(day.max_value().unwrap(), 24.0);
+        assert_eq!(day.min_value().unwrap(), 1.0);
+        assert_eq!(day.spread().unwrap(), 23.0);
+        assert!(PriceDay::new(Vec::new()).average().is_err());
+    }
+
+    #[test]
+    fn ranks_and_percentiles() {
+        let day = rising();
+        assert_eq!(day.rank(0), 1);
+        assert_eq!(day.rank(23), 24);
+        assert_eq!(day.rank(24), 0);
+        assert_eq!(day.percentile(0), 0.0);
+        assert_eq!(day.percentile(23), 100.0);
+
+        let flat = self::day(&[2.0, 1.0, 2.0, 3.0]);
+        assert_eq!(flat.rank(0), flat.rank(2));
+        assert_eq!(flat.rank(0), 2);
+        assert_eq!(flat.percentile(3), 100.0);
+    }
+
+    #[test]
+    fn cheapest_and_priciest_hours() {
+        let day = rising();
+        let hours = |prices: Vec<IndexedPrice>| -> Vec<usize> {
+            prices.into_iter().map(|(index, _)| index).collect()
+        };
+        assert_eq!(hours(day.cheapest(3, 0, 23)), vec![0, 1, 2]);
+        assert_eq!(hours(day.priciest(3, 0, 23)), vec![23, 22, 21]);
+        assert_eq!(hours(day.cheapest(2, 10, 12)), vec![10, 11]);
+        assert_eq!(day.cheapest_window(3), Some((0, 2.0)));
+        assert_eq!(day.hours_until_cheapest(5).unwrap(), -5.0);
+    }
+
+    #[test]
+    fn changes_ahead() {
+        let day = rising();
+        assert_eq!(day.delta_next(0), Some(1.0));
+        assert_eq!(day.delta_next(23), None);
+        assert_eq!(day.trend(0, 3, 1.0).unwrap(), 1);
+        assert_eq!(self::day(&[5.0; 24]).trend(0, 3, 1.0).unwrap(), 0);
+    }
+
+    #[test]
+    fn bands() {
+        let day = rising();
+        let cheap = Band {
+            name: "billig".to_string(),
+            low: 0.0,
+            high: 0.5,
+        };
+        assert!(day.within_band(0, &cheap).unwrap());
+        assert!(!day.within_band(23, &cheap).unwrap());
+    }
 
-    match write_result {
-        Ok(_) => Ok(()),
-        Err(e) => Err(e.to_string()),
+    #[test]
+    fn ratio_against_small_or_negative_references() {
+        assert_eq!(ratio(2.0, 1.0), 2.0);
+        // Still above 1 for a price above a negative average
+        assert_eq!(ratio(0.5, -1.0), 2.5);
+        assert_eq!(ratio(0.0, 0.0), 1.0);
     }
 }
@@ -0,0 +1,78 @@
+use chrono::NaiveDate;
+use sentry::{protocol::Value, Breadcrumb, ClientInitGuard, Level};
+use serde::{Deserialize, Serialize};
+
+use super::error::RefinerError;
+
+const DEFAULT_SAMPLE_RATE: f32 = 1.0;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReportingSettings {
+    /// DSN of the Sentry project, or of anything speaking its protocol like GlitchTip
+    pub dsn: String,
+    /// Like `production` or `home`, Sentry's default if unset
+    pub environment: Option<String>,
+    /// Share of errors that are sent, from 0 through 1
+    pub sample_rate: f32,
+}
+
+impl Default for ReportingSettings {
+    fn default() -> Self {
+        ReportingSettings {
+            dsn: String::new(),
+            environment: None,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+        }
+    }
+}
+
+/// Starts reporting errors and panics. Reports are sent until the guard is dropped, keep it for
+/// as long as the program runs
+pub fn init(settings: &ReportingSettings) -> ClientInitGuard {
+    sentry::init((
+        settings.dsn.as_str(),
+        sentry::ClientOptions {
+            release: sentry::release_name!(),
+            environment: settings.environment.clone().map(Into::into),
+            sample_rate: settings.sample_rate,
+            ..Default::default()
+        },
+    ))
+}
+
+/// Records a failed attempt, sent along with the report if the pass gives up
+pub fn attempt_failed(pass: &str, date: NaiveDate, attempt: u32, error: &RefinerError) {
+    sentry::add_breadcrumb(Breadcrumb {
+        category: Some(pass.to_string()),
+        message: Some(format!("Attempt {} at {} failed: {}", attempt, date, error)),
+        level: Level::Warning,
+        ..Default::default()
+    });
+}
+
+/// Reports a pass over `date` that gave up, with the hours that failed or the query that
+/// couldn't be parsed. Does nothing unless reporting was started
+pub fn capture(pass: &str, date: NaiveDate, error: &RefinerError) {
+    sentry::with_scope(
+        |scope| {
+            scope.set_tag("pass", pass);
+            scope.set_tag("date", date);
+            scope.set_tag("transient", error.is_transient());
+            match error {
+                RefinerError::Parse { query, .. } => {
+                    scope.set_extra("query", Value::from(query.clone()));
+                }
+                RefinerError::Hours { failed_hours, .. } => {
+                    let hours: Vec<String> = failed_hours
+                        .iter()
+                        .map(|(hour, e)| format!("{}: {}", hour, e))
+                        .collect();
+                    scope.set_extra("failed_hours", Value::from(hours));
+                }
+                _ => {}
+            }
+        },
+        || sentry::capture_error(error),
+    );
+}
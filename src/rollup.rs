@@ -0,0 +1,176 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Datelike, NaiveDate};
+use chrono_tz::Tz;
+use influxdb::{InfluxDbWriteable, Query, Timestamp, WriteQuery};
+use tracing::instrument;
+
+use super::{
+    config::{Area, RefinerConfig},
+    db::Db,
+    error::RefinerError,
+    refiner::{average, get_prices_before, start_of_day, stddev, PricePoint},
+};
+
+/// A span of days prices are rolled up over
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Period {
+    /// Monday through Sunday
+    Week,
+    Month,
+}
+
+impl Period {
+    pub fn measurement(&self) -> &'static str {
+        match self {
+            Period::Week => "refined_weekly",
+            Period::Month => "refined_monthly",
+        }
+    }
+
+    /// The first day of the period `date` is in and the first day of the next
+    pub fn bounds(&self, date: NaiveDate) -> (NaiveDate, NaiveDate) {
+        match self {
+            Period::Week => {
+                let monday = date.weekday().num_days_from_monday();
+                let start = date - chrono::Duration::days(monday.into());
+                (start, start + chrono::Duration::days(7))
+            }
+            Period::Month => {
+                let start = NaiveDate::from_ymd(date.year(), date.month(), 1);
+                let next = match date.month() {
+                    12 => NaiveDate::from_ymd(date.year() + 1, 1, 1),
+                    month => NaiveDate::from_ymd(date.year(), month + 1, 1),
+                };
+                (start, next)
+            }
+        }
+    }
+}
+
+/// The prices of a week or month, or as much of it as has passed
+#[derive(Clone, Debug)]
+pub struct Rollup {
+    pub period: Period,
+    /// Local midnight at the start of the period
+    pub time: DateTime<Tz>,
+    pub start: NaiveDate,
+    pub currency: String,
+    /// Name of the configured area, unset with a single area
+    pub area: Option<String>,
+    /// Days with prices
+    pub dager: u32,
+    pub pris_snitt: f64,
+    /// Standard deviation of every price of the period
+    pub pris_stddev: f64,
+    /// Standard deviation of the daily averages, how much days differ from each other
+    pub pris_stddev_dag: f64,
+    pub billigste_dag: NaiveDate,
+    pub billigste_dag_snitt: f64,
+    pub dyreste_dag: NaiveDate,
+    pub dyreste_dag_snitt: f64,
+}
+
+impl Rollup {
+    /// The start of the period is the timestamp, `date`, `currency` and `area` are tags,
+    /// everything else is a field
+    pub fn to_query(&self) -> WriteQuery {
+        let mut query = Timestamp::from(self.time)
+            .into_query(self.period.measurement())
+            .add_tag("date", self.start.to_string())
+            .add_tag("currency", self.currency.clone())
+            .add_field("dager", self.dager)
+            .add_field("pris_snitt", self.pris_snitt)
+            .add_field("pris_stddev", self.pris_stddev)
+            .add_field("pris_stddev_dag", self.pris_stddev_dag)
+            .add_field("billigste_dag", self.billigste_dag.to_string())
+            .add_field("billigste_dag_snitt", self.billigste_dag_snitt)
+            .add_field("dyreste_dag", self.dyreste_dag.to_string())
+            .add_field("dyreste_dag_snitt", self.dyreste_dag_snitt);
+        if let Some(area) = &self.area {
+            query = query.add_tag("area", area.clone());
+        }
+        query
+    }
+}
+
+/// Rolls up the period `date` is in through `date`, none if it has no prices yet
+#[instrument(skip(db, tz, config, area))]
+pub async fn rollup(
+    date: NaiveDate,
+    period: Period,
+    db: &Db,
+    tz: Tz,
+    config: &RefinerConfig,
+    area: Option<&Area>,
+) -> Result<Option<Rollup>, RefinerError> {
+    let (start, _) = period.bounds(date);
+    let days = u32::try_from((date - start).num_days() + 1).unwrap_or_default();
+    let prices = get_prices_before(date.succ(), days, db, tz, &config.source, area).await?;
+    if prices.is_empty() {
+        return Ok(None);
+    }
+
+    let mut by_day: BTreeMap<NaiveDate, Vec<PricePoint>> = BTreeMap::new();
+    for price in &prices {
+        by_day
+            .entry(price.start.date().naive_local())
+            .or_default()
+            .push(*price);
+    }
+    // One price per day, starting at its first price
+    let mut daily = Vec::new();
+    for day_prices in by_day.values() {
+        let value = average(day_prices)?;
+        let start = day_prices[0].start;
+        daily.push(PricePoint { start, value });
+    }
+    let by_value = |a: &&PricePoint, b: &&PricePoint| {
+        a.value
+            .partial_cmp(&b.value)
+            .unwrap_or(std::cmp::Ordering::Equal)
+    };
+    let cheapest = daily.iter().min_by(by_value);
+    let (cheapest, priciest) = match (cheapest, daily.iter().max_by(by_value)) {
+        (Some(cheapest), Some(priciest)) => (cheapest, priciest),
+        _ => return Ok(None),
+    };
+
+    Ok(Some(Rollup {
+        period,
+        time: start_of_day(start, tz)
+            .ok_or_else(|| RefinerError::Timestamp(format!("{} has no local midnight", start)))?,
+        start,
+        currency: config.source.currency.clone(),
+        area: area.map(|area| area.name.clone()),
+        dager: daily.len() as u32,
+        pris_snitt: average(&prices)?,
+        pris_stddev: stddev(&prices)?,
+        pris_stddev_dag: stddev(&daily)?,
+        billigste_dag: cheapest.start.date().naive_local(),
+        billigste_dag_snitt: cheapest.value,
+        dyreste_dag: priciest.start.date().naive_local(),
+        dyreste_dag_snitt: priciest.value,
+    }))
+}
+
+/// Renders rollups as Influx line protocol, one line per rollup
+pub fn line_protocol(rollups: &[Rollup]) -> Result<String, RefinerError> {
+    let write_queries: Vec<WriteQuery> = rollups.iter().map(Rollup::to_query).collect();
+    Ok(write_queries
+        .build()
+        .map_err(|e| RefinerError::Write(e.to_string()))?
+        .get())
+}
+
+/// Writes every rollup in a single batched query, replacing the rollup of the same period
+/// written the day before
+#[instrument(skip_all, fields(rollups = rollups.len()))]
+pub async fn write_rollups(rollups: &[Rollup], db: &Db) -> Result<(), RefinerError> {
+    if rollups.is_empty() {
+        return Ok(());
+    }
+    db.write(rollups.iter().map(Rollup::to_query).collect())
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,209 @@
+use std::{cell::Cell, collections::BTreeMap, sync::Arc};
+
+use chrono::Timelike;
+use rhai::{Engine, Scope, AST};
+
+use super::{
+    config::Rule,
+    error::RefinerError,
+    refiner::{points_per_hour, ratio, PriceDay},
+};
+
+/// Upper bound on the work a single rule may do, so a bad rule can't stall a tick
+const MAX_OPERATIONS: u64 = 10_000;
+
+thread_local! {
+    /// Index of the price the rules are being evaluated for on this thread. An evaluation runs
+    /// on one thread without yielding, so the engine a day's rules share reads it from here
+    static NOW: Cell<usize> = Cell::new(0);
+}
+
+/// Checks that `expr` parses, without evaluating it
+pub fn check(expr: &str) -> Result<(), String> {
+    Engine::new()
+        .compile_expression(expr)
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+/// An engine with the functions rules can call for the price being evaluated, see `NOW`.
+///
+/// `in_lowest(n, start, stop)` and `in_highest(n, start, stop)` tell whether the price is among
+/// the `n` cheapest or priciest hours from hour `start` through `stop` since midnight,
+/// `in_cheapest_window(n)` whether the price is in the cheapest run of `n` consecutive hours,
+/// `rank(i)` is the rank of the `i`th price of the day with 1 being the cheapest and
+/// `price_at(i)` is its value
+fn engine(day: &Arc<PriceDay>) -> Engine {
+    let mut engine = Engine::new();
+    engine.set_max_operations(MAX_OPERATIONS);
+
+    let of = day.clone();
+    engine.register_fn("in_lowest", move |n: i64, start: i64, stop: i64| {
+        of.cheapest(to_usize(n), to_usize(start), to_usize(stop))
+            .iter()
+            .any(|(index, _)| *index == now())
+    });
+    let of = day.clone();
+    engine.register_fn("in_highest", move |n: i64, start: i64, stop: i64| {
+        of.priciest(to_usize(n), to_usize(start), to_usize(stop))
+            .iter()
+            .any(|(index, _)| *index == now())
+    });
+    let of = day.clone();
+    engine.register_fn("in_cheapest_window", move |n: i64| {
+        let length = to_usize(n) * points_per_hour(of.prices());
+        of.cheapest_window(to_usize(n))
+            .map_or(false, |(start, _)| (start..start + length).contains(&now()))
+    });
+    let of = day.clone();
+    engine.register_fn("rank", move |i: i64| of.rank(to_usize(i)) as i64);
+    let of = day.clone();
+    engine.register_fn("price_at", move |i: i64| {
+        of.prices()
+            .get(to_usize(i))
+            .map_or(f64::NAN, |price| price.value)
+    });
+
+    engine
+}
+
+fn now() -> usize {
+    NOW.with(Cell::get)
+}
+
+fn to_usize(value: i64) -> usize {
+    usize::try_from(value).unwrap_or(0)
+}
+
+/// The rules compiled for a day, with what they read of the day as a whole worked out once
+/// rather than for every price
+pub struct DayRules {
+    rules: Vec<(String, AST)>,
+    engine: Engine,
+    day: Arc<PriceDay>,
+    avg: f64,
+    median: f64,
+    stddev: f64,
+    min: f64,
+    max: f64,
+}
+
+impl DayRules {
+    pub fn new(rules: &[Rule], day: Arc<PriceDay>) -> Result<DayRules, RefinerError> {
+        let engine = engine(&day);
+        let rules = rules
+            .iter()
+            .map(|rule| {
+                engine
+                    .compile_expression(&rule.expr)
+                    .map(|ast| (rule.name.clone(), ast))
+                    .map_err(|e| RefinerError::Rule(format!("{}: {}", rule.name, e)))
+            })
+            .collect::<Result<_, _>>()?;
+        // An empty day has no averages, nothing is evaluated for it either
+        let (avg, median, stddev, min, max) = if day.is_empty() {
+            (f64::NAN, f64::NAN, f64::NAN, f64::NAN, f64::NAN)
+        } else {
+            let (avg, median, stddev) = (day.average()?, day.median()?, day.stddev()?);
+            (avg, median, stddev, day.min_value()?, day.max_value()?)
+        };
+        Ok(DayRules {
+            rules,
+            engine,
+            day,
+            avg,
+            median,
+            stddev,
+            min,
+            max,
+        })
+    }
+
+    /// Evaluates every rule for the price at `now`.
+    ///
+    /// Besides the functions of the engine, rules can read `index`, `hour`, `price`, `avg`,
+    /// `median`, `stddev`, `ratio`, `percentile`, `min` and `max`.
+    pub fn evaluate(&self, now: usize) -> Result<BTreeMap<String, bool>, RefinerError> {
+        let mut results = BTreeMap::new();
+        if self.rules.is_empty() {
+            return Ok(results);
+        }
+
+        let point = self
+            .day
+            .prices()
+            .get(now)
+            .ok_or_else(|| RefinerError::MissingData(format!("No price at index {}", now)))?;
+        let mut scope = Scope::new();
+        scope.push_constant("index", now as i64);
+        scope.push_constant("hour", point.start.hour() as i64);
+        scope.push_constant("price", point.value);
+        scope.push_constant("avg", self.avg);
+        scope.push_constant("median", self.median);
+        scope.push_constant("stddev", self.stddev);
+        scope.push_constant("ratio", ratio(point.value, self.avg));
+        scope.push_constant("percentile", self.day.percentile(now));
+        scope.push_constant("min", self.min);
+        scope.push_constant("max", self.max);
+
+        NOW.with(|index| index.set(now));
+        for (name, ast) in &self.rules {
+            let result = self
+                .engine
+                .eval_ast_with_scope::<bool>(&mut scope, ast)
+                .map_err(|e| RefinerError::Rule(format!("{}: {}", name, e)))?;
+            results.insert(name.clone(), result);
+        }
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+    use chrono_tz::Europe::Oslo;
+
+    use super::*;
+    use crate::refiner::{start_of_day, PricePoint};
+
+    fn rule(name: &str, expr: &str) -> Rule {
+        Rule {
+            name: name.to_string(),
+            expr: expr.to_string(),
+        }
+    }
+
+    #[test]
+    fn evaluates_compiled_rules_for_every_price() {
+        let midnight = start_of_day(NaiveDate::from_ymd(2023, 1, 10), Oslo).unwrap();
+        let prices = (0..24)
+            .map(|hour| PricePoint {
+                start: midnight + chrono::Duration::hours(hour),
+                value: hour as f64,
+            })
+            .collect();
+        let day = Arc::new(PriceDay::new(prices));
+        let rules = [
+            rule("night", "hour < 6 && in_lowest(6, 0, 23)"),
+            rule("above_average", "price > avg && rank(index) > 12"),
+        ];
+        let rules = DayRules::new(&rules, day).unwrap();
+
+        let first = rules.evaluate(0).unwrap();
+        assert!(first["night"]);
+        assert!(!first["above_average"]);
+        let last = rules.evaluate(23).unwrap();
+        assert!(!last["night"]);
+        assert!(last["above_average"]);
+        assert!(rules.evaluate(24).is_err());
+    }
+
+    #[test]
+    fn reports_the_rule_that_does_not_compile() {
+        let day = Arc::new(PriceDay::new(Vec::new()));
+        let error = DayRules::new(&[rule("broken", "price >")], day)
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("broken"));
+    }
+}
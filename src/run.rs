@@ -1,127 +1,1389 @@
-use std::{env, rc, sync::Arc};
+use std::{path::PathBuf, sync::Arc, time::Duration};
 
-use chrono::Utc;
-use influxdb::Client;
-use tokio::time;
-use tracing::{instrument, metadata::LevelFilter, Level};
-use tracing_appender::non_blocking::{NonBlocking, WorkerGuard};
+use chrono::{DateTime, NaiveDate, TimeZone, Timelike, Utc};
+use chrono_tz::Tz;
+use futures::{Stream, StreamExt};
+use opentelemetry::{
+    sdk::{trace, Resource},
+    KeyValue,
+};
+use opentelemetry_otlp::WithExportConfig;
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::{watch, RwLock},
+    task::JoinSet,
+    time,
+};
+use tracing::{instrument, metadata::LevelFilter, Level, Subscriber};
+use tracing_appender::{
+    non_blocking::WorkerGuard,
+    rolling::{RollingFileAppender, Rotation},
+};
+use tracing_opentelemetry::OpenTelemetryLayer;
 use tracing_subscriber::{
-    fmt::format::{DefaultFields, FmtSpan, Format},
-    FmtSubscriber,
+    fmt::format::FmtSpan, layer::SubscriberExt, registry::LookupSpan, reload, Registry,
+};
+
+#[cfg(feature = "http-api")]
+use super::api::{self, AppState};
+#[cfg(feature = "live")]
+use super::live;
+#[cfg(feature = "mqtt")]
+use super::mqtt;
+#[cfg(feature = "sentry")]
+use super::reporting;
+#[cfg(feature = "systemd")]
+use super::systemd;
+use super::{
+    cache::CachedStore,
+    charging,
+    config::{
+        Area, Config, Forecast, LogFormat, LogRotation, LogTarget, LoggingConfig, RefinerConfig,
+        Resolution,
+    },
+    db::Db,
+    error::RefinerError,
+    events, forecast,
+    grafana::{self, GrafanaSettings},
+    health::{Health, SharedHealth},
+    heartbeat::{self, Run},
+    metrics,
+    notify::{Event, Notifier},
+    optimizer,
+    refiner::{
+        day_length, get_prices, hourly, latest_refined, line_protocol, missing_hours,
+        points_per_hour, refine, row_at, Context, Day, Output, PriceDay, Refined, SharedRows,
+    },
+    rollup::{self, Period, Rollup},
+    rules::DayRules,
+    sink, solar,
+    spool::{Failed, Spool},
+    store::PriceStore,
+    summary,
+    weather::{self, WeatherSettings},
 };
 
-const DEFAULT_RETRIES: u32 = 10;
-const DEFAULT_UPDATE_TIME: &str = "0";
+/// How often the age of the latest refined row is checked
+const STALE_CHECK_SECS: u64 = 3600;
+
+/// Changes the log level of a running subscriber
+pub type LevelHandle = reload::Handle<LevelFilter, Registry>;
 
-use super::refiner::refine;
+pub fn get_logger(
+    config: &LoggingConfig,
+    otel_endpoint: Option<&str>,
+) -> (Box<dyn Subscriber + Send + Sync>, WorkerGuard, LevelHandle) {
+    let (non_blocking_appender, guard) = match config.target {
+        LogTarget::Stdout => tracing_appender::non_blocking(std::io::stdout()),
+        LogTarget::File => match get_file_appender(config) {
+            Ok(appender) => tracing_appender::non_blocking(appender),
+            Err(e) => {
+                // The global subscriber is not set yet, so report on stderr
+                eprintln!("Failed to set up log file, logging to stdout: {}", e);
+                tracing_appender::non_blocking(std::io::stdout())
+            }
+        },
+    };
 
-#[instrument]
-pub fn get_db_info() -> (Arc<String>, Arc<String>) {
-    let db_addr = env::var("INFLUXDB_ADDR").expect("INFLUXDB_ADDR not set");
-    tracing::info!("INFLUXDB_ADDR: {}", db_addr);
+    let level: Level = config.level.into();
+    let json = config.format == LogFormat::Json;
 
-    let db_name = env::var("INFLUXDB_DB_NAME").expect("INFLUXDB_DB_NAME not set");
-    tracing::info!("INFLUXDB_DB_NAME: {}", db_name);
+    let text_layer = (!json).then(|| {
+        tracing_subscriber::fmt::layer()
+            .with_span_events(FmtSpan::NONE)
+            .with_ansi(false)
+            .with_writer(non_blocking_appender.clone())
+    });
+    let json_layer = json.then(|| {
+        tracing_subscriber::fmt::layer()
+            .json()
+            .with_span_events(FmtSpan::NONE)
+            .with_writer(non_blocking_appender)
+    });
 
-    (Arc::new(db_addr), Arc::new(db_name))
+    // all spans/events with a level higher than `level` are discarded
+    let (level_filter, level_handle) = reload::Layer::new(LevelFilter::from_level(level));
+    let subscriber = tracing_subscriber::registry()
+        .with(level_filter)
+        .with(text_layer)
+        .with(json_layer)
+        .with(otel_endpoint.and_then(get_otel_layer));
+
+    (Box::new(subscriber), guard, level_handle)
 }
 
-pub fn get_retries() -> u32 {
-    let retries = env::var("RETRIES")
-        .ok()
-        .unwrap_or(DEFAULT_RETRIES.to_string());
-    tracing::info!("RETRIES: {}", retries);
+fn get_file_appender(config: &LoggingConfig) -> Result<RollingFileAppender, String> {
+    let rotation = match config.rotation {
+        LogRotation::Minutely => Rotation::MINUTELY,
+        LogRotation::Hourly => Rotation::HOURLY,
+        LogRotation::Daily => Rotation::DAILY,
+        LogRotation::Never => Rotation::NEVER,
+    };
+
+    std::fs::create_dir_all(&config.dir)
+        .map_err(|e| format!("Failed to create log directory {}: {}", config.dir, e))?;
+
+    let mut builder = RollingFileAppender::builder()
+        .rotation(rotation)
+        .filename_prefix(&config.file_prefix);
+    if let Some(max_files) = config.max_files {
+        builder = builder.max_log_files(max_files);
+    }
+
+    builder
+        .build(&config.dir)
+        .map_err(|e| format!("Failed to open log file in {}: {}", config.dir, e))
+}
+
+/// Builds a layer exporting spans over OTLP to `endpoint`
+fn get_otel_layer<S>(endpoint: &str) -> Option<OpenTelemetryLayer<S, trace::Tracer>>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(trace::config().with_resource(Resource::new(vec![KeyValue::new(
+            "service.name",
+            "tibber_refiner",
+        )])))
+        .install_batch(opentelemetry::runtime::Tokio);
+
+    match tracer {
+        Ok(tracer) => Some(tracing_opentelemetry::layer().with_tracer(tracer)),
+        Err(e) => {
+            // The global subscriber is not set yet, so report on stderr
+            eprintln!("Failed to set up OTLP exporter for {}: {}", endpoint, e);
+            None
+        }
+    }
+}
+
+/// How a tick refines and outputs a date. Besides the store, the services a tick reaches, like
+/// Grafana, MET Norway and Forecast.Solar, are best effort, as are the notifications sent about
+/// it: their failures are logged and never fail the tick
+#[derive(Clone, Debug)]
+pub struct TickOptions {
+    /// Print the refined rows as line protocol instead of writing them
+    pub dry_run: bool,
+    /// Write even if the date has already been refined
+    pub force: bool,
+    /// Only compute the rows, neither writing nor printing them
+    pub discard: bool,
+    /// Where the notable events of written days are annotated
+    pub grafana: Option<GrafanaSettings>,
+    /// Where the outdoor temperature of the rows is forecast for
+    pub weather: Option<WeatherSettings>,
+    /// Give up on the tick after this long
+    pub deadline: Option<Duration>,
+    /// Timezone the date's hours are counted in
+    pub tz: Tz,
+    pub refiner: Arc<RefinerConfig>,
+}
+
+/// Refines `date` for every area, reading from and writing to `store`
+#[instrument(skip_all, level = "trace")]
+pub async fn tick<S: PriceStore>(
+    store: S,
+    date: NaiveDate,
+    options: TickOptions,
+) -> Result<Vec<Refined>, RefinerError> {
+    let deadline = match options.deadline {
+        Some(deadline) => deadline,
+        None => return refine_areas(&store, date, options).await,
+    };
+    match time::timeout(deadline, refine_areas(&store, date, options)).await {
+        Ok(result) => result,
+        Err(_) => {
+            tracing::warn!("Tick for {} timed out after {:?}", date, deadline);
+            Err(RefinerError::Deadline { date, deadline })
+        }
+    }
+}
+
+/// Refines `date` for every configured area in turn, or for the single untagged one
+async fn refine_areas(
+    store: &dyn PriceStore,
+    date: NaiveDate,
+    options: TickOptions,
+) -> Result<Vec<Refined>, RefinerError> {
+    let refiner = options.refiner.clone();
+    if refiner.areas.is_empty() {
+        return refine_date(store, date, options, None).await;
+    }
+    let mut refined = Vec::new();
+    for area in &refiner.areas {
+        refined.extend(refine_date(store, date, options.clone(), Some(area)).await?);
+    }
+    Ok(refined)
+}
+
+/// Rolls up the week and month `date` is in through `date` for every area, printing them instead
+/// of writing them on a dry run
+pub async fn rollup_pass(
+    db: &Db,
+    date: NaiveDate,
+    options: &TickOptions,
+) -> Result<Vec<Rollup>, RefinerError> {
+    let refiner = &options.refiner;
+    let areas: Vec<Option<&Area>> = if refiner.areas.is_empty() {
+        vec![None]
+    } else {
+        refiner.areas.iter().map(Some).collect()
+    };
+    let mut rollups = Vec::new();
+    for area in areas {
+        for period in [Period::Week, Period::Month] {
+            let rollup = rollup::rollup(date, period, db, options.tz, refiner, area).await?;
+            rollups.extend(rollup);
+        }
+    }
+    if options.dry_run {
+        println!("{}", rollup::line_protocol(&rollups)?);
+    } else {
+        rollup::write_rollups(&rollups, db).await?;
+    }
+    Ok(rollups)
+}
+
+async fn refine_date(
+    store: &dyn PriceStore,
+    date: NaiveDate,
+    options: TickOptions,
+    area: Option<&Area>,
+) -> Result<Vec<Refined>, RefinerError> {
+    tracing::debug!("tick");
+    let _timer = metrics::TICK_DURATION.start_timer();
+    let cached;
+    let store: &dyn PriceStore = match &options.refiner.price_cache {
+        Some(path) => {
+            cached = CachedStore::new(store, path);
+            &cached
+        }
+        None => store,
+    };
+    match area {
+        Some(area) => tracing::info!("Writing price info for {} in {}", date, area.name),
+        None => tracing::info!("Writing price info for {}", date),
+    }
+
+    let source = &options.refiner.source;
+    let method = options.refiner.forecast;
+    let (prices, forecast) = match store.read_prices(date, options.tz, source, area).await {
+        Ok(prices) => (prices, false),
+        // Only days still to come are forecast, the daily pass fails on a missing today
+        Err(RefinerError::MissingData(e))
+            if method != Forecast::Off && date > Day::Today.date(options.tz) =>
+        {
+            tracing::info!("{}, refining a forecast from the days before", e);
+            let prices = forecast::forecast(date, method, store, options.tz, source, area).await?;
+            (prices, true)
+        }
+        Err(e) => return Err(e),
+    };
+    let hours = day_length(date, options.tz)
+        .ok_or_else(|| RefinerError::Timestamp(format!("{} has no local midnight", date)))?;
+    let expected = hours * points_per_hour(&prices);
+    if prices.len() > expected {
+        return Err(RefinerError::MissingData(format!(
+            "{} should have at most {} prices, found {}",
+            date,
+            expected,
+            prices.len()
+        )));
+    }
+    let missing = missing_hours(date, options.tz, &prices).unwrap_or_default();
+    if missing > 0 {
+        tracing::warn!(
+            "{} has no prices for {} of {} hours, refining the others",
+            date,
+            missing,
+            hours
+        );
+    }
+    let prices = match options.refiner.resolution {
+        Resolution::Hourly => hourly(&prices),
+        Resolution::Native => prices,
+    };
+
+    let output = if options.discard {
+        Output::Discard
+    } else if options.dry_run {
+        Output::Print
+    } else if options.force {
+        Output::Write
+    } else {
+        let measurement = &options.refiner.measurement;
+        let existing = store
+            .count_refined(date, measurement, area, forecast)
+            .await?;
+        if existing >= prices.len() as u64 {
+            tracing::info!(
+                "{} already has {} refined rows, skipping write. Force to overwrite",
+                date,
+                existing
+            );
+            Output::Discard
+        } else {
+            if existing > 0 {
+                // Written while some hours were missing
+                tracing::info!(
+                    "{} has {} refined rows of {}, writing again",
+                    date,
+                    existing,
+                    prices.len()
+                );
+            }
+            Output::Write
+        }
+    };
+
+    // Only today and tomorrow are forecast
+    let production = match &options.refiner.solar {
+        Some(solar) if date >= Day::Today.date(options.tz) => {
+            solar::production(solar, options.tz).await
+        }
+        _ => Vec::new(),
+    };
+
+    // Plans and schedules wait for the published prices
+    let plans = match &options.refiner.ev {
+        Some(ev) if !forecast => {
+            let (refiner, tz) = (&options.refiner, options.tz);
+            let plans = charging::plans(date, &prices, store, tz, refiner, ev, area, &production);
+            Some(plans.await?)
+        }
+        _ => None,
+    };
+
+    let mut schedules = if forecast {
+        Vec::new()
+    } else {
+        optimizer::schedules(&prices, &options.refiner.appliances)
+    };
+    for schedule in &mut schedules {
+        schedule.area = area.map(|area| area.name.clone());
+    }
 
-    retries.parse().unwrap_or_else(|e| {
+    let mut context = Context::load(date, store, options.tz, &options.refiner, area).await?;
+    context.production = production;
+    let context = Arc::new(context);
+    if context.avg_3d.is_none() {
         tracing::warn!(
-            "Failed to parse {}, using default: {}",
-            retries,
-            DEFAULT_RETRIES
+            "No prices before {}, using its own average for price levels",
+            date
         );
-        tracing::debug!("{}", e);
-        10
-    })
+    }
+
+    let day = Arc::new(PriceDay::new(prices));
+    let rules = Arc::new(DayRules::new(&options.refiner.rules, day.clone())?);
+    let prices = day.prices();
+    let points = prices.len();
+    let mut tasks = JoinSet::new();
+    for index in 0..points {
+        let day = day.clone();
+        let context = context.clone();
+        let rules = rules.clone();
+        let config = options.refiner.clone();
+        tasks.spawn(async move {
+            let row = refine(date, index, &day, &context, &rules, &config);
+            (index, row)
+        });
+    }
+
+    let mut refined = Vec::new();
+    let mut failed_hours = Vec::new();
+    while let Some(joined) = tasks.join_next().await {
+        match joined {
+            Ok((hour, Ok(row))) => refined.push((hour, row)),
+            Ok((hour, Err(e))) => {
+                tracing::error!("Error in refining {}: {}", hour, e);
+                failed_hours.push((hour, e));
+            }
+            Err(e) => tracing::error!("Refine task failed: {}", e),
+        }
+    }
+
+    // Hours whose task panicked are neither refined nor failed
+    for hour in 0..points {
+        let done = refined.iter().any(|(refined, _)| *refined == hour)
+            || failed_hours.iter().any(|(failed, _)| *failed == hour);
+        if !done {
+            failed_hours.push((hour, RefinerError::Task("panicked".to_string())));
+        }
+    }
+    if !failed_hours.is_empty() {
+        // Nothing is written, so the retry doesn't find a partial day and skip it
+        failed_hours.sort_by_key(|(hour, _)| *hour);
+        return Err(RefinerError::Hours { date, failed_hours });
+    }
+    refined.sort_by_key(|(hour, _)| *hour);
+    let mut refined: Vec<Refined> = refined.into_iter().map(|(_, row)| row).collect();
+    for row in &mut refined {
+        row.area = area.map(|area| area.name.clone());
+        row.forecast = forecast;
+        row.data_complete = missing == 0;
+        row.missing_hours = missing as u32;
+        row.stale_source = store.stale();
+    }
+    let anomalies: Vec<u32> = refined
+        .iter()
+        .filter(|row| row.anomaly)
+        .map(|row| row.hour)
+        .collect();
+    if !anomalies.is_empty() {
+        tracing::warn!(
+            "{} has prices far from the week before's median in hours {:?}, check the source",
+            date,
+            anomalies
+        );
+    }
+    if let Some(plans) = &plans {
+        for row in &mut refined {
+            row.charge_now = Some(plans.iter().any(|plan| plan.contains(row.time)));
+        }
+    }
+    if let Some(weather) = &options.weather {
+        weather::enrich(weather, &mut refined).await;
+    }
+
+    let summary = summary::summarize(date, options.tz, &day, &refined, &options.refiner)?;
+
+    match output {
+        Output::Write => {
+            let measurement = &options.refiner.measurement;
+            let json_sink = &options.refiner.json_sink;
+            if !matches!(json_sink, Some(json_sink) if json_sink.replace) {
+                if !forecast && method != Forecast::Off {
+                    store.delete_forecast(date, measurement, area).await?;
+                }
+                store
+                    .write_refined(date, &refined, measurement, area)
+                    .await?;
+                tracing::debug!("Wrote and verified {} rows for {}", refined.len(), date);
+            }
+            if !forecast {
+                summary::write_summary(&summary, store).await?;
+            }
+            if let Some(plans) = &plans {
+                charging::write_plans(plans, store).await?;
+            }
+            optimizer::write_schedules(date, prices, &schedules, store).await?;
+            // Only once every write succeeded, a retried pass would append its rows again
+            if let Some(json_sink) = json_sink {
+                sink::emit(json_sink, &refined)?;
+            }
+            if let (Some(grafana), false) = (&options.grafana, forecast) {
+                let currency = &options.refiner.source.currency;
+                let annotations =
+                    grafana::annotations(prices, &refined, currency, grafana.window_hours);
+                let area = area.map(|area| area.name.as_str());
+                grafana::post(grafana, date, area, &annotations).await;
+            }
+        }
+        Output::Print => {
+            println!("{}", line_protocol(&refined, &options.refiner.measurement)?);
+            if !forecast {
+                println!("{}", summary::line_protocol(&summary)?);
+            }
+            if let Some(plans) = &plans {
+                println!("{}", charging::line_protocol(plans)?);
+            }
+            if !schedules.is_empty() {
+                println!("{}", optimizer::line_protocol(date, prices, &schedules)?);
+            }
+        }
+        Output::Discard => {}
+    }
+
+    Ok(refined)
+}
+
+/// Refines every date from `from` through `to`, with at most `concurrency` dates in flight.
+/// Results are yielded in completion order.
+pub fn backfill(
+    db: Db,
+    from: NaiveDate,
+    to: NaiveDate,
+    concurrency: usize,
+    options: TickOptions,
+) -> impl Stream<Item = (NaiveDate, Result<Vec<Refined>, RefinerError>)> {
+    let dates = std::iter::successors(Some(from), |date| date.succ_opt())
+        .take_while(move |date| *date <= to);
+    futures::stream::iter(dates)
+        .map(move |date| {
+            let db = db.clone();
+            let options = options.clone();
+            async move {
+                let result = tokio::spawn(tick(db, date, options))
+                    .await
+                    .unwrap_or_else(|e| Err(RefinerError::Task(e.to_string())));
+                (date, result)
+            }
+        })
+        .buffer_unordered(concurrency)
+}
+
+/// `time` o'clock on `date` in `tz`. A time skipped when daylight saving time starts moves to the
+/// first that exists after it, one that occurs twice when it ends is its first
+fn local_time(date: NaiveDate, time: u32, tz: Tz) -> DateTime<Tz> {
+    let mut local = date
+        .and_hms_opt(time, 0, 0)
+        .unwrap_or_else(|| date.succ().and_hms(0, 0, 0));
+    loop {
+        match tz.from_local_datetime(&local).earliest() {
+            Some(when) => return when,
+            None => local += chrono::Duration::minutes(1),
+        }
+    }
+}
+
+/// `time` o'clock tomorrow in `tz`, logged as the next update time
+pub fn get_instant(time: u32, tz: Tz) -> time::Instant {
+    let tomorrow = Utc::now().with_timezone(&tz).date().naive_local().succ();
+    let when = local_time(tomorrow, time, tz);
+    tracing::info!("Next update time: {}", when);
+    let until = when
+        .signed_duration_since(Utc::now())
+        .to_std()
+        .unwrap_or_default();
+    time::Instant::now() + until
+}
+
+/// The first time after `now` the clock in its timezone reads `time` o'clock, today or tomorrow
+fn next_local_time(now: DateTime<Tz>, time: u32) -> DateTime<Tz> {
+    let (today, tz) = (now.date().naive_local(), now.timezone());
+    let when = local_time(today, time, tz);
+    if when > now {
+        return when;
+    }
+    local_time(today.succ(), time, tz)
+}
+
+/// The next time the clock in `tz` reads `time` o'clock, today or tomorrow, logged as the next
+/// `purpose`
+fn get_next_instant(time: u32, tz: Tz, purpose: &str) -> time::Instant {
+    let now = Utc::now().with_timezone(&tz);
+    let when = next_local_time(now, time);
+    tracing::info!("Next {}: {}", purpose, when);
+    let until = when.signed_duration_since(now).to_std().unwrap_or_default();
+    time::Instant::now() + until
 }
 
-pub fn get_logger() -> (
-    FmtSubscriber<DefaultFields, Format, LevelFilter, NonBlocking>,
-    WorkerGuard,
+/// Writes a `refiner_runs` point for a pass over `date` that ran since `started`, not on a dry
+/// run, and shows how it went in systemd's status
+#[allow(clippy::too_many_arguments)]
+async fn record_run(
+    db: &Db,
+    pass: &'static str,
+    date: NaiveDate,
+    options: &TickOptions,
+    started: time::Instant,
+    attempts: u32,
+    rows: &[Refined],
+    error: Option<&RefinerError>,
 ) {
-    let appender = tracing_appender::rolling::daily("./var/log", "tibber-status-server");
-    let (non_blocking_appender, guard) = tracing_appender::non_blocking(appender);
-
-    let level = match env::var("LOG_LEVEL") {
-        Ok(l) => match l.as_str() {
-            "trace" => Level::TRACE,
-            "debug" => Level::DEBUG,
-            "info" => Level::INFO,
-            "warn" => Level::WARN,
-            "error" => Level::ERROR,
-            _ => Level::INFO,
-        },
-        Err(_) => Level::INFO,
+    #[cfg(feature = "systemd")]
+    systemd::status(&match error {
+        Some(e) => format!("The {} pass of {} failed: {}", pass, date, e),
+        None => format!("The {} pass refined {} hours of {}", pass, rows.len(), date),
+    });
+    #[cfg(feature = "sentry")]
+    if let Some(e) = error {
+        reporting::capture(pass, date, e);
+    }
+    if options.dry_run {
+        return;
+    }
+    let source = &options.refiner.source.measurement;
+    let run = Run::new(pass, date, source, started.elapsed(), attempts, rows, error);
+    heartbeat::write_run(&run, db).await;
+}
+
+/// Refines tomorrow every day from the configured time, polling until its prices are published.
+/// The rows, of a forecast until then, are kept in `rows`
+async fn tomorrow_pass(db: Db, tz: Tz, mut config_rx: watch::Receiver<Config>, rows: SharedRows) {
+    loop {
+        let config = config_rx.borrow_and_update().clone();
+        let tomorrow_time = match config.schedule.tomorrow_time {
+            Some(tomorrow_time) => tomorrow_time,
+            None => {
+                // Disabled until a reload sets it
+                if config_rx.changed().await.is_err() {
+                    return;
+                }
+                continue;
+            }
+        };
+
+        let next = get_next_instant(tomorrow_time, tz, "update time for tomorrow's prices");
+        tokio::select! {
+            _ = time::sleep_until(next) => {}
+            Ok(()) = config_rx.changed() => continue,
+        }
+
+        let options = TickOptions {
+            dry_run: config.dry_run,
+            force: false,
+            discard: false,
+            grafana: config.grafana.clone(),
+            weather: config.weather.clone(),
+            deadline: Some(Duration::from_secs(config.schedule.tick_timeout_secs)),
+            tz,
+            refiner: Arc::new(config.refiner.clone()),
+        };
+        let poll = Duration::from_secs(config.schedule.tomorrow_poll_secs);
+        let date = Day::Tomorrow.date(tz);
+        let started = time::Instant::now();
+        let mut attempts = 0;
+        let outcome = loop {
+            attempts += 1;
+            match tick(db.clone(), date, options.clone()).await {
+                Ok(refined) if refined.iter().any(|row| row.forecast) => {
+                    *rows.write().await = refined;
+                    tracing::info!(
+                        "Refined a forecast of {}, polling for its prices again in {:?}",
+                        date,
+                        poll
+                    );
+                    time::sleep(poll).await;
+                }
+                Ok(refined) => {
+                    tracing::info!("Refined {} hours of {} ahead of time", refined.len(), date);
+                    *rows.write().await = refined.clone();
+                    break Ok(refined);
+                }
+                Err(e) if !e.is_transient() => {
+                    tracing::error!("Unable to refine {} ahead of time: {}. Giving up", date, e);
+                    break Err(e);
+                }
+                // The daily pass takes over once the date has arrived
+                Err(e) if Day::Today.date(tz) >= date => {
+                    tracing::warn!("No prices for {} before it arrived: {}", date, e);
+                    break Err(e);
+                }
+                Err(e) => {
+                    tracing::info!(
+                        "{} not refined yet, polling again in {:?}: {}",
+                        date,
+                        poll,
+                        e
+                    );
+                    time::sleep(poll).await;
+                }
+            }
+        };
+        let (written, error) = match &outcome {
+            Ok(refined) => (refined.as_slice(), None),
+            Err(e) => (&[][..], Some(e)),
+        };
+        let pass = "tomorrow";
+        record_run(&db, pass, date, &options, started, attempts, written, error).await;
+    }
+}
+
+/// Refines today again at the start of every hour and writes only the row of the hour that just
+/// started, so fields looking ahead, like `in_cheapest_next_24`, catch up once tomorrow's prices
+/// are published. The rows in `rows` are replaced with the fresh ones
+async fn hourly_pass(db: Db, tz: Tz, mut config_rx: watch::Receiver<Config>, rows: SharedRows) {
+    loop {
+        let config = config_rx.borrow_and_update().clone();
+        if !config.schedule.hourly {
+            // Disabled until a reload sets it
+            if config_rx.changed().await.is_err() {
+                return;
+            }
+            continue;
+        }
+
+        // The local hour, which is offset from UTC's by a half hour in some zones
+        let now = Utc::now().with_timezone(&tz);
+        let seconds_into_hour = (now.minute() * 60 + now.second()) as u64;
+        tokio::select! {
+            _ = time::sleep(Duration::from_secs(3600 - seconds_into_hour)) => {}
+            Ok(()) = config_rx.changed() => continue,
+        }
+
+        let options = TickOptions {
+            dry_run: config.dry_run,
+            force: false,
+            // Only the current row is written, below
+            discard: true,
+            grafana: None,
+            weather: config.weather.clone(),
+            deadline: Some(Duration::from_secs(config.schedule.tick_timeout_secs)),
+            tz,
+            refiner: Arc::new(config.refiner.clone()),
+        };
+        let date = Day::Today.date(tz);
+        if let Err(e) = refine_current(&db, date, &options, &rows).await {
+            tracing::warn!("Unable to refine the current hour of {}: {}", date, e);
+        }
+    }
+}
+
+/// Refines `date` and writes the row of the current hour of every area, printing it on a dry run
+async fn refine_current(
+    db: &Db,
+    date: NaiveDate,
+    options: &TickOptions,
+    rows: &SharedRows,
+) -> Result<(), RefinerError> {
+    let refined = tick(db.clone(), date, options.clone()).await?;
+    let now = Utc::now();
+    let refiner = &options.refiner;
+    let areas: Vec<Option<&Area>> = if refiner.areas.is_empty() {
+        vec![None]
+    } else {
+        refiner.areas.iter().map(Some).collect()
     };
+    for area in areas {
+        let name = area.map(|area| area.name.clone());
+        let of_area: Vec<Refined> = refined
+            .iter()
+            .filter(|row| row.area == name)
+            .cloned()
+            .collect();
+        let current = match row_at(&of_area, now) {
+            Some(current) => std::slice::from_ref(current),
+            None => continue,
+        };
+        if options.dry_run {
+            println!("{}", line_protocol(current, &refiner.measurement)?);
+        } else {
+            if !matches!(&refiner.json_sink, Some(json_sink) if json_sink.replace) {
+                db.write_refined(date, current, &refiner.measurement, area)
+                    .await?;
+            }
+            if let Some(json_sink) = &refiner.json_sink {
+                sink::emit(json_sink, current)?;
+            }
+        }
+        tracing::debug!("Refined the row of {} again", current[0].time);
+    }
+    *rows.write().await = refined;
+    Ok(())
+}
 
-    let subscriber = FmtSubscriber::builder()
-        // all spans/events with a level higher than TRACE (e.g, debug, info, warn, etc.)
-        // will be written to stdout.
-        .with_span_events(FmtSpan::NONE)
-        .with_ansi(false)
-        .with_max_level(level)
-        .with_writer(non_blocking_appender)
-        // completes the builder.
-        .finish();
+/// Checks every day at the configured deadline that tomorrow's prices are stored for every area,
+/// so a failed import is noticed before the daily pass fails on it
+async fn tomorrow_watchdog(
+    db: Db,
+    tz: Tz,
+    mut config_rx: watch::Receiver<Config>,
+    notifier: Notifier,
+) {
+    loop {
+        let config = config_rx.borrow_and_update().clone();
+        let deadline = match config.schedule.tomorrow_deadline {
+            Some(deadline) => deadline,
+            None => {
+                // Disabled until a reload sets it
+                if config_rx.changed().await.is_err() {
+                    return;
+                }
+                continue;
+            }
+        };
+
+        let next = get_next_instant(deadline, tz, "check for tomorrow's prices");
+        tokio::select! {
+            _ = time::sleep_until(next) => {}
+            Ok(()) = config_rx.changed() => continue,
+        }
 
-    (subscriber, guard)
+        let date = Day::Tomorrow.date(tz);
+        let refiner = &config.refiner;
+        let areas: Vec<Option<&Area>> = if refiner.areas.is_empty() {
+            vec![None]
+        } else {
+            refiner.areas.iter().map(Some).collect()
+        };
+        let mut missing = Vec::new();
+        for area in areas {
+            let name = area.map_or("prices", |area| area.name.as_str());
+            match get_prices(date, &db, tz, &refiner.source, area).await {
+                Ok(prices) => {
+                    let hours = missing_hours(date, tz, &prices).unwrap_or_default();
+                    if hours > 0 {
+                        tracing::warn!("{} of {} is missing {} hours", name, date, hours);
+                    }
+                }
+                Err(e @ RefinerError::MissingData(_)) => {
+                    tracing::error!("{} of {} not stored by {}:00: {}", name, date, deadline, e);
+                    missing.push(name.to_string());
+                }
+                Err(e) => {
+                    tracing::error!("Unable to check {} of {}: {}", name, date, e);
+                    missing.push(name.to_string());
+                }
+            }
+        }
+        metrics::TOMORROW_PRICES_MISSING.set(i64::from(!missing.is_empty()));
+        if !missing.is_empty() {
+            let message = format!(
+                "{} of {} not stored by {}:00",
+                missing.join(", "),
+                date,
+                deadline
+            );
+            notifier.notify(Event::MissingData, &message).await;
+        }
+    }
 }
 
-#[instrument(skip_all, level = "trace")]
-pub async fn tick(db_addr: Arc<String>, db_name: Arc<String>) -> Result<(), String> {
-    tracing::debug!("tick");
-    let date = chrono::offset::Local::now().date().and_hms(0, 0, 0).to_rfc3339();
-    let t_pos = date.find('T').unwrap();
-    let date = &date[..t_pos];
-    tracing::info!("Writing price info for {}", date);
-    let client = Client::new(db_addr.as_str(), db_name.as_str());
-
-    let mut handles = Vec::new();
-    let client_ref = rc::Rc::new(client);
-    for hour in 0..24 {
-        let clone = client_ref.clone();
-        handles.push(async move {
-            refine(hour, clone.as_ref()).await.map_err(|e| {
-                tracing::error!("Error in refining {}: {}", hour, e);
-                e
-            })
+/// Checks every hour that the latest refined row is younger than the configured threshold, so a
+/// scheduler that silently stopped firing is noticed. Notifies once until rows are written again
+async fn stale_watchdog(
+    db: Db,
+    tz: Tz,
+    mut config_rx: watch::Receiver<Config>,
+    notifier: Notifier,
+) {
+    let mut stale = false;
+    loop {
+        let config = config_rx.borrow_and_update().clone();
+        let threshold = match config.schedule.stale_after_hours {
+            Some(threshold) => chrono::Duration::hours(i64::from(threshold)),
+            None => {
+                // Disabled until a reload sets it
+                if config_rx.changed().await.is_err() {
+                    return;
+                }
+                continue;
+            }
+        };
+
+        let measurement = &config.refiner.measurement;
+        match latest_refined(&db, measurement, tz).await {
+            Ok(Some(latest)) => {
+                let age = Utc::now() - latest.with_timezone(&Utc);
+                metrics::REFINED_AGE.set(age.num_seconds() as f64);
+                if age > threshold {
+                    let message = format!(
+                        "Latest row of {} starts at {}, {} hours ago",
+                        measurement,
+                        latest.format("%Y-%m-%d %H:%M"),
+                        age.num_hours()
+                    );
+                    tracing::error!("{}", message);
+                    if !stale {
+                        notifier.notify(Event::Stale, &message).await;
+                    }
+                }
+                stale = age > threshold;
+            }
+            Ok(None) => tracing::debug!("Nothing written to {} yet", measurement),
+            Err(e) => tracing::warn!("Unable to check the latest row of {}: {}", measurement, e),
+        }
+
+        tokio::select! {
+            _ = time::sleep(Duration::from_secs(STALE_CHECK_SECS)) => {}
+            Ok(()) = config_rx.changed() => {}
+        }
+    }
+}
+
+/// What the daemon needs to reload its config on SIGHUP
+pub struct Reload {
+    /// The config file to read again, only the environment is reapplied if unset
+    pub path: Option<PathBuf>,
+    pub level: LevelHandle,
+    /// Set by `--dry-run`, which the config file can't turn off
+    pub dry_run: bool,
+}
+
+/// Reloads the config on every SIGHUP, keeping the current config if the new one is invalid.
+/// Only the schedule, refiner settings, dry run and log level take effect without a restart.
+async fn reload_on_hangup(reload: Reload, config: watch::Sender<Config>) {
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            tracing::error!("Failed to listen for SIGHUP, config reload disabled: {}", e);
+            return;
+        }
+    };
+
+    while hangups.recv().await.is_some() {
+        tracing::info!("Received SIGHUP, reloading config");
+        #[cfg(feature = "systemd")]
+        systemd::reloading();
+        let mut new_config = match Config::load(reload.path.clone()) {
+            Ok(new_config) => new_config,
+            Err(e) => {
+                tracing::error!("Invalid config, keeping the current one: {}", e);
+                #[cfg(feature = "systemd")]
+                systemd::ready();
+                continue;
+            }
+        };
+        new_config.dry_run |= reload.dry_run;
+
+        let level: Level = new_config.logging.level.into();
+        if let Err(e) = reload.level.reload(LevelFilter::from_level(level)) {
+            tracing::error!("Failed to change log level: {}", e);
+        }
+        tracing::info!("LOG_LEVEL: {}", level);
+        tracing::info!("UPDATE_TIME: {}", new_config.schedule.update_time);
+        tracing::info!("RETRIES: {}", new_config.schedule.retries);
+        tracing::info!("TOMORROW_TIME: {:?}", new_config.schedule.tomorrow_time);
+        tracing::info!(
+            "TOMORROW_DEADLINE: {:?}",
+            new_config.schedule.tomorrow_deadline
+        );
+        tracing::info!("DRY_RUN: {}", new_config.dry_run);
+        if config.send(new_config).is_err() {
+            return;
+        }
+        #[cfg(feature = "systemd")]
+        systemd::ready();
+    }
+}
+
+/// Refines today at the configured update time every day, serving MQTT and HTTP outputs in between
+pub async fn daemon(config: Config, db: Db, reload: Reload) {
+    // Not reloaded, everything keeps counting hours in the same timezone
+    let tz = config.timezone;
+
+    metrics::register();
+
+    let rows: SharedRows = Arc::new(RwLock::new(Vec::new()));
+    let tomorrow: SharedRows = Arc::new(RwLock::new(Vec::new()));
+    let health: SharedHealth = Arc::new(RwLock::new(Health::new()));
+    if let Some(settings) = config.events.clone() {
+        tracing::info!("Posting flag changes to {} webhooks", settings.urls.len());
+        tokio::spawn(events::run(settings, rows.clone(), tz));
+    }
+    #[cfg(feature = "live")]
+    match config.live.clone() {
+        Some(_) if config.dry_run => tracing::info!("Not writing live measurements on a dry run"),
+        Some(settings) => {
+            tracing::info!("Writing live measurements of {}", settings.home_id);
+            tokio::spawn(live::run(settings, db.clone(), rows.clone()));
+        }
+        None => {}
+    }
+    #[cfg(feature = "http-api")]
+    if let Some(addr) = config.http.addr {
+        let state = AppState {
+            rows: rows.clone(),
+            tomorrow: tomorrow.clone(),
+            tz,
+            health: health.clone(),
+            db: db.clone(),
+        };
+        tokio::spawn(async move {
+            if let Err(e) = api::serve(addr, state).await {
+                tracing::error!("HTTP API stopped: {}", e);
+            }
         });
     }
-    tokio::join!(futures::future::join_all(handles));
 
-    Ok(())
+    let notifier = Notifier::new(config.notify.clone());
+    let (config_tx, config_rx) = watch::channel(config);
+    tokio::spawn(reload_on_hangup(reload, config_tx));
+    #[cfg(feature = "mqtt")]
+    let mqtt_settings = config_rx.borrow().mqtt.clone();
+    #[cfg(feature = "mqtt")]
+    if let Some(settings) = mqtt_settings {
+        tracing::info!("Publishing to MQTT broker {}:{}", settings.host, settings.port);
+        let publisher = mqtt::Publisher::connect(settings);
+        tokio::spawn(mqtt::run(publisher, rows.clone(), tz, config_rx.clone()));
+    }
+    tokio::spawn(tomorrow_pass(db.clone(), tz, config_rx.clone(), tomorrow));
+    tokio::spawn(hourly_pass(db.clone(), tz, config_rx.clone(), rows.clone()));
+    tokio::spawn(tomorrow_watchdog(
+        db.clone(),
+        tz,
+        config_rx.clone(),
+        notifier.clone(),
+    ));
+    tokio::spawn(stale_watchdog(
+        db.clone(),
+        tz,
+        config_rx.clone(),
+        notifier.clone(),
+    ));
+
+    #[cfg(feature = "systemd")]
+    {
+        tokio::spawn(systemd::watchdog());
+        systemd::ready();
+    }
+
+    let version = env!("CARGO_PKG_VERSION");
+    let started = format!("Started version {}", version);
+    notifier.notify(Event::Started, &started).await;
+    let catch_up_config = config_rx.borrow().clone();
+    tokio::select! {
+        _ = async {
+            catch_up(&db, tz, &catch_up_config, &rows).await;
+            daily_pass(db.clone(), tz, config_rx, rows, health, notifier.clone()).await
+        } => {}
+        _ = shutdown() => {}
+    }
+    #[cfg(feature = "systemd")]
+    systemd::stopping();
+    notifier.notify(Event::Stopped, "Stopped").await;
 }
 
-pub fn get_instant() -> time::Instant {
-    let time = env::var("UPDATE_TIME")
-        .ok()
-        .unwrap_or(DEFAULT_UPDATE_TIME.to_string());
-    let time = time.parse().unwrap();
-    let when = chrono::offset::Local::now().date().succ().and_hms(time, 0, 0);
-    tracing::info!("Next update time: {}", when);
-    let next_day = when.signed_duration_since(chrono::offset::Local::now());
-    let std_next_day = match next_day.to_std() {
-        Ok(d) => d,
+/// Resolves on SIGTERM or ctrl-c
+async fn shutdown() {
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
         Err(e) => {
-            tracing::error!("Failed to convert signed duration to std: {}", e);
-            panic!("Failed to convert signed duration to std");
+            tracing::error!("Failed to listen for SIGTERM: {}", e);
+            let _ = tokio::signal::ctrl_c().await;
+            return;
         }
     };
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+    tracing::info!("Shutting down");
+}
+
+/// Keeps `date` in the configured spool to be refined again later, not on a dry run
+fn spool_failure(config: &Config, pass: &str, date: NaiveDate, error: &RefinerError) {
+    let dir = match &config.schedule.spool_dir {
+        Some(dir) if !config.dry_run => dir,
+        _ => return,
+    };
+    let failed = Failed {
+        date,
+        pass: pass.to_string(),
+        error: error.to_string(),
+        failed_at: Utc::now(),
+    };
+    match Spool::new(dir).push(&failed) {
+        Ok(()) => tracing::info!("Spooled {} to refine again later", date),
+        Err(e) => tracing::error!("Failed to spool {}: {}", date, e),
+    }
+}
 
-    let instant = match time::Instant::now().checked_add(std_next_day) {
-        Some(i) => i,
-        None => {
-            tracing::error!("Failed to add signed duration to instant");
-            panic!("Failed to add signed duration to instant");
+/// Refines every spooled day again, oldest first, and forgets the ones that succeed. Days that
+/// have been written since are skipped by the tick
+async fn replay_spool(db: &Db, config: &Config, options: &TickOptions) {
+    let spool = match &config.schedule.spool_dir {
+        Some(dir) if !config.dry_run => Spool::new(dir),
+        _ => return,
+    };
+    for failed in spool.pending() {
+        let date = failed.date;
+        match tick(db.clone(), date, options.clone()).await {
+            Ok(refined) => {
+                tracing::info!(
+                    "Replayed {} from the spool, {} rows, it failed at {}: {}",
+                    date,
+                    refined.len(),
+                    failed.failed_at,
+                    failed.error
+                );
+                if let Err(e) = spool.remove(date) {
+                    tracing::error!("Failed to remove {} from the spool: {}", date, e);
+                }
+            }
+            Err(e) => tracing::warn!("Unable to replay {} from the spool: {}", date, e),
         }
+    }
+}
+
+/// Polls the source until `date` has prices for every area, for ingestion that is running late.
+/// Gives up after the configured deadline, leaving the tick to fail on the missing prices. Returns
+/// at once unless polling is configured
+async fn wait_for_prices(db: &Db, date: NaiveDate, config: &Config, tz: Tz) {
+    let poll = match config.schedule.source_poll_secs {
+        Some(poll_secs) => Duration::from_secs(poll_secs),
+        None => return,
+    };
+    let deadline = time::Instant::now() + Duration::from_secs(config.schedule.source_wait_secs);
+    let refiner = &config.refiner;
+    let areas: Vec<Option<&Area>> = if refiner.areas.is_empty() {
+        vec![None]
+    } else {
+        refiner.areas.iter().map(Some).collect()
     };
-    instant
+    loop {
+        let mut missing = Vec::new();
+        for area in &areas {
+            let name = area.map_or("prices", |area| area.name.as_str());
+            match db.read_prices(date, tz, &refiner.source, *area).await {
+                Ok(_) => {}
+                Err(RefinerError::MissingData(_)) => missing.push(name),
+                // Left to the tick's retries
+                Err(e) => tracing::warn!("Unable to check {} of {}: {}", name, date, e),
+            }
+        }
+        if missing.is_empty() {
+            return;
+        }
+        if time::Instant::now() + poll > deadline {
+            tracing::warn!(
+                "{} of {} still missing after waiting {}s, refining anyway",
+                missing.join(", "),
+                date,
+                config.schedule.source_wait_secs
+            );
+            return;
+        }
+        tracing::info!(
+            "{} of {} not stored yet, polling again in {:?}",
+            missing.join(", "),
+            date,
+            poll
+        );
+        time::sleep(poll).await;
+    }
+}
+
+/// Refines yesterday and today on startup when their rows are missing or incomplete, so a restart
+/// after the update time doesn't leave a gap until the next one. Days already written are skipped
+/// by the tick, and today's rows are kept in `rows`
+async fn catch_up(db: &Db, tz: Tz, config: &Config, rows: &SharedRows) {
+    let options = TickOptions {
+        dry_run: config.dry_run,
+        force: false,
+        discard: false,
+        grafana: config.grafana.clone(),
+        weather: config.weather.clone(),
+        deadline: Some(Duration::from_secs(config.schedule.tick_timeout_secs)),
+        tz,
+        refiner: Arc::new(config.refiner.clone()),
+    };
+    let today = Day::Today.date(tz);
+    for date in [Day::Yesterday.of(today), today] {
+        if date == today {
+            wait_for_prices(db, date, config, tz).await;
+        }
+        let started = time::Instant::now();
+        let outcome = tick(db.clone(), date, options.clone()).await;
+        match &outcome {
+            Ok(refined) if date == today => *rows.write().await = refined.clone(),
+            Ok(_) => {}
+            Err(e) => {
+                tracing::warn!("Unable to catch up on {}: {}", date, e);
+                spool_failure(config, "catch_up", date, e);
+            }
+        }
+        let (written, error) = match &outcome {
+            Ok(refined) => (refined.as_slice(), None),
+            Err(e) => (&[][..], Some(e)),
+        };
+        record_run(db, "catch_up", date, &options, started, 1, written, error).await;
+    }
+    replay_spool(db, config, &options).await;
+}
+
+/// Refines today at the configured update time every day, retrying failed ticks
+async fn daily_pass(
+    db: Db,
+    tz: Tz,
+    mut config_rx: watch::Receiver<Config>,
+    rows: SharedRows,
+    health: SharedHealth,
+    notifier: Notifier,
+) {
+    loop {
+        let config = config_rx.borrow_and_update().clone();
+        let retries = config.schedule.retries;
+        let options = TickOptions {
+            dry_run: config.dry_run,
+            force: false,
+            discard: false,
+            grafana: config.grafana.clone(),
+            weather: config.weather.clone(),
+            deadline: Some(Duration::from_secs(config.schedule.tick_timeout_secs)),
+            tz,
+            refiner: Arc::new(config.refiner.clone()),
+        };
+
+        let instant = get_instant(config.schedule.update_time, tz);
+        tokio::select! {
+            _ = time::sleep_until(instant) => {}
+            // Reschedule with the reloaded config
+            Ok(()) = config_rx.changed() => continue,
+        }
+        wait_for_prices(&db, Day::Today.date(tz), &config, tz).await;
+        let started = time::Instant::now();
+        let mut date = Day::Today.date(tz);
+        let mut attempts = 0;
+        let mut outcome = Ok(Vec::new());
+        for i in 0..retries {
+            date = Day::Today.date(tz);
+            attempts = i + 1;
+            match tick(db.clone(), date, options.clone()).await {
+                Ok(refined) => {
+                    *rows.write().await = refined.clone();
+                    if options.refiner.consumption.is_some() {
+                        // Yesterday's consumption is complete by now, write its cost
+                        let yesterday = Day::Yesterday.of(date);
+                        let force = TickOptions {
+                            force: true,
+                            ..options.clone()
+                        };
+                        if let Err(e) = tick(db.clone(), yesterday, force).await {
+                            tracing::warn!("Failed to refine {} again: {}", yesterday, e);
+                        }
+                    }
+                    if let Err(e) = rollup_pass(&db, date, &options).await {
+                        tracing::warn!("Failed to roll up the week and month of {}: {}", date, e);
+                    }
+                    outcome = Ok(refined);
+                    break;
+                }
+                Err(e) if !e.is_transient() => {
+                    outcome = Err(e);
+                    break;
+                }
+                Err(e) => {
+                    tracing::warn!("Failed attempt {} to tick: {}", i, e);
+                    #[cfg(feature = "sentry")]
+                    reporting::attempt_failed("daily", date, attempts, &e);
+                    metrics::TICK_RETRIES.inc();
+                    let backoff = 2_u64.pow(i);
+                    tracing::debug!("Exponential backoff: {} seconds", backoff);
+                    time::sleep(time::Duration::from_secs(backoff)).await;
+                    outcome = Err(e);
+                }
+            }
+        }
+        match &outcome {
+            Ok(_) => replay_spool(&db, &config, &options).await,
+            Err(e) => {
+                tracing::error!("Unable to refine values: {}. Giving up", e);
+                let message = format!("Unable to refine values: {}", e);
+                notifier.notify(Event::TickFailed, &message).await;
+                spool_failure(&config, "daily", date, e);
+            }
+        }
+        let (written, error) = match &outcome {
+            Ok(refined) => (refined.as_slice(), None),
+            Err(e) => (&[][..], Some(e)),
+        };
+        let pass = "daily";
+        record_run(&db, pass, date, &options, started, attempts, written, error).await;
+        health.write().await.record(&outcome);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono_tz::Europe::Oslo;
+
+    use super::*;
+    use crate::{refiner::start_of_day, store::MemoryStore, PricePoint};
+
+    fn options() -> TickOptions {
+        TickOptions {
+            dry_run: false,
+            force: false,
+            discard: false,
+            grafana: None,
+            weather: None,
+            deadline: None,
+            tz: Oslo,
+            refiner: Arc::new(RefinerConfig::default()),
+        }
+    }
+
+    /// Hourly prices of `date` for the given hours, cheapest at 3 o'clock
+    fn prices(date: NaiveDate, hours: impl Iterator<Item = i64>) -> Vec<PricePoint> {
+        let midnight = start_of_day(date, Oslo).unwrap();
+        hours
+            .map(|hour| PricePoint {
+                start: midnight + chrono::Duration::hours(hour),
+                value: 0.5 + (hour - 3).abs() as f64 / 10.0,
+            })
+            .collect()
+    }
+
+    fn utc(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.ymd(year, month, day).and_hms(hour, minute, 0)
+    }
+
+    #[test]
+    fn local_times_across_daylight_saving_time() {
+        // 02:00 is skipped when the clocks go forward, 03:00 CEST follows 01:59 CET
+        let spring = NaiveDate::from_ymd(2023, 3, 26);
+        assert_eq!(local_time(spring, 2, Oslo), utc(2023, 3, 26, 1, 0));
+        assert_eq!(local_time(spring, 3, Oslo), utc(2023, 3, 26, 1, 0));
+        // 02:00 occurs twice when they go back, first in CEST
+        let autumn = NaiveDate::from_ymd(2023, 10, 29);
+        assert_eq!(local_time(autumn, 2, Oslo), utc(2023, 10, 29, 0, 0));
+        assert_eq!(local_time(autumn, 3, Oslo), utc(2023, 10, 29, 2, 0));
+    }
+
+    #[test]
+    fn next_local_times_across_daylight_saving_time() {
+        // 01:30 CET, before the skipped hour
+        let now = utc(2023, 3, 26, 0, 30).with_timezone(&Oslo);
+        assert_eq!(next_local_time(now, 2), utc(2023, 3, 26, 1, 0));
+        // The first 02:30, in CEST. 02:00 is taken to be its first, which has passed
+        let now = utc(2023, 10, 29, 0, 30).with_timezone(&Oslo);
+        assert_eq!(next_local_time(now, 2), utc(2023, 10, 30, 1, 0));
+        // The second 02:30, in CET
+        let now = utc(2023, 10, 29, 1, 30).with_timezone(&Oslo);
+        assert_eq!(next_local_time(now, 3), utc(2023, 10, 29, 2, 0));
+    }
+
+    #[tokio::test]
+    async fn writes_a_row_per_hour() {
+        let date = NaiveDate::from_ymd(2023, 1, 10);
+        let store = MemoryStore::new();
+        store.insert_prices(None, &prices(date, 0..24));
+        let options = options();
+        let measurement = options.refiner.measurement.clone();
+
+        let refined = tick(store.clone(), date, options).await.unwrap();
+
+        let written = store.refined(&measurement);
+        assert_eq!(written.len(), 24);
+        assert_eq!(refined.len(), written.len());
+        for (hour, row) in written.iter().enumerate() {
+            assert_eq!(row.hour, hour as u32);
+            assert_eq!(row.date, "2023-01-10");
+            assert!(row.data_complete);
+            assert!(!row.forecast);
+        }
+        assert_eq!(written[3].pris_rang, 1);
+        assert_eq!(written[3].pris_persentil, 0.0);
+        // The summary is written along with the rows
+        assert!(!store.lines().is_empty());
+    }
+
+    #[tokio::test]
+    async fn counts_missing_hours() {
+        let date = NaiveDate::from_ymd(2023, 1, 10);
+        let store = MemoryStore::new();
+        store.insert_prices(None, &prices(date, 0..20));
+        let options = options();
+        let measurement = options.refiner.measurement.clone();
+
+        tick(store.clone(), date, options).await.unwrap();
+
+        let written = store.refined(&measurement);
+        assert_eq!(written.len(), 20);
+        assert!(written
+            .iter()
+            .all(|row| !row.data_complete && row.missing_hours == 4));
+    }
+
+    #[tokio::test]
+    async fn writes_nothing_without_prices() {
+        let date = NaiveDate::from_ymd(2023, 1, 10);
+        let store = MemoryStore::new();
+        store.insert_prices(None, &prices(date.pred(), 0..24));
+        let options = options();
+        let measurement = options.refiner.measurement.clone();
+
+        let result = tick(store.clone(), date, options).await;
+
+        assert!(matches!(result, Err(RefinerError::MissingData(_))));
+        assert!(store.refined(&measurement).is_empty());
+    }
 }
@@ -0,0 +1,101 @@
+use chrono::{DateTime, TimeZone};
+
+use super::refiner::{row_at, Refined};
+
+const BOLD: &str = "\x1b[1m";
+const GREEN: &str = "\x1b[32m";
+const RED: &str = "\x1b[31m";
+const RESET: &str = "\x1b[0m";
+
+/// Names of the flags of `row` that are set, bands apart
+fn flags(row: &Refined) -> Vec<String> {
+    let mut flags = Vec::new();
+    if row.negativ_pris {
+        flags.push("negativ_pris".to_string());
+    }
+    if row.anomaly {
+        flags.push("anomaly".to_string());
+    }
+    if row.in_cheapest_next_24 == Some(true) {
+        flags.push("in_cheapest_next_24".to_string());
+    }
+    if row.charge_now == Some(true) {
+        flags.push("charge_now".to_string());
+    }
+    let rules = row.rules.iter().filter(|(_, set)| **set);
+    flags.extend(rules.map(|(name, _)| name.clone()));
+    flags
+}
+
+/// Names of the bands `row`'s price is within
+fn bands(row: &Refined) -> String {
+    let bands: Vec<&str> = row
+        .bands
+        .iter()
+        .filter(|(_, within)| **within)
+        .map(|(name, _)| name.as_str())
+        .collect();
+    bands.join(", ")
+}
+
+/// `text` in `style` when colored
+fn paint(text: &str, style: &str, color: bool) -> String {
+    if color && !text.is_empty() {
+        format!("{}{}{}", style, text, RESET)
+    } else {
+        text.to_string()
+    }
+}
+
+/// Renders rows as a table for a terminal, one line per row with its price, ratio, level, bands
+/// and the flags that are set. The row `now` is in is marked, and with `color` flags are green,
+/// anomalies red and the current row bold
+pub fn table<T: TimeZone>(rows: &[Refined], now: DateTime<T>, color: bool) -> String {
+    let current = row_at(rows, now).map(|row| row.time);
+    let bands: Vec<String> = rows.iter().map(bands).collect();
+    let width = bands
+        .iter()
+        .map(String::len)
+        .chain(["bands".len()])
+        .max()
+        .unwrap_or_default();
+
+    let mut table = format!(
+        "  {:<5} {:>9} {:>6}  {:<14}  {:<width$}  flags\n",
+        "start",
+        "price",
+        "ratio",
+        "level",
+        "bands",
+        width = width
+    );
+    for (row, bands) in rows.iter().zip(&bands) {
+        let flags: Vec<String> = flags(row)
+            .iter()
+            .map(|flag| match flag.as_str() {
+                "anomaly" => paint(flag, RED, color),
+                _ => paint(flag, GREEN, color),
+            })
+            .collect();
+        let is_current = current == Some(row.time);
+        let line = format!(
+            "{} {:<5} {:>9.4} {:>6.2}  {:<14}  {:<width$}  {}",
+            if is_current { ">" } else { " " },
+            row.time.format("%H:%M"),
+            row.pris_time,
+            row.pris_forhold_24,
+            row.price_level,
+            bands,
+            flags.join(", "),
+            width = width
+        );
+        let line = line.trim_end();
+        if is_current {
+            table.push_str(&paint(line, BOLD, color));
+        } else {
+            table.push_str(line);
+        }
+        table.push('\n');
+    }
+    table
+}
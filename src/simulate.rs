@@ -0,0 +1,164 @@
+use chrono::NaiveDate;
+use chrono_tz::Tz;
+use tracing::instrument;
+
+use super::{
+    config::RefinerConfig,
+    error::RefinerError,
+    optimizer,
+    refiner::{average, with_costs, with_tariff, PricePoint},
+    store::PriceStore,
+};
+
+/// What running one appliance every day of the range costs with and without shifting it
+#[derive(Clone, Debug, Default)]
+pub struct Saving {
+    pub appliance: String,
+    /// Energy used over the range
+    pub kwh: f64,
+    /// Cost when it runs like the rest of the household uses energy
+    pub baseline: f64,
+    /// Cost when it runs in the cheapest hours of its window
+    pub shifted: f64,
+}
+
+impl Saving {
+    pub fn saved(&self) -> f64 {
+        self.baseline - self.shifted
+    }
+
+    /// Saved share of the baseline cost
+    pub fn percent(&self) -> f64 {
+        if self.baseline == 0.0 {
+            return 0.0;
+        }
+        self.saved() / self.baseline * 100.0
+    }
+}
+
+/// The result of simulating a range of days
+#[derive(Clone, Debug, Default)]
+pub struct Simulation {
+    /// Days with prices
+    pub days: u32,
+    /// Days without prices, which are left out
+    pub skipped: Vec<NaiveDate>,
+    /// Energy used by the household over the range
+    pub kwh: f64,
+    /// Cost of the household's energy over the range, without shifting anything
+    pub cost: f64,
+    pub savings: Vec<Saving>,
+}
+
+/// The price `point` starts within, none before the first price
+fn price_at(prices: &[PricePoint], point: &PricePoint) -> Option<f64> {
+    prices
+        .iter()
+        .rev()
+        .find(|price| price.start <= point.start)
+        .map(|price| price.value)
+}
+
+/// Average price paid per kWh when energy is used like `profile`, the daily average price with
+/// a flat profile
+fn weighted_price(prices: &[PricePoint], profile: &[PricePoint]) -> Result<f64, RefinerError> {
+    let mut kwh = 0.0;
+    let mut cost = 0.0;
+    for point in profile {
+        if let Some(price) = price_at(prices, point) {
+            kwh += point.value;
+            cost += point.value * price;
+        }
+    }
+    if kwh > 0.0 {
+        Ok(cost / kwh)
+    } else {
+        average(prices)
+    }
+}
+
+/// Simulates running every configured appliance every day from `from` through `to`, both in the
+/// cheapest hours the optimizer finds and spread like the household's stored consumption. Without
+/// stored consumption the household is assumed to use `kwh_per_day` evenly through the day.
+///
+/// Prices are what the household pays, with costs and the grid tariff when they are configured,
+/// of the first area like the HTTP API
+#[instrument(skip(store, config))]
+pub async fn simulate(
+    from: NaiveDate,
+    to: NaiveDate,
+    kwh_per_day: Option<f64>,
+    store: &dyn PriceStore,
+    tz: Tz,
+    config: &RefinerConfig,
+) -> Result<Simulation, RefinerError> {
+    let area = config.areas.first();
+    let mut simulation = Simulation {
+        savings: config
+            .appliances
+            .iter()
+            .map(|appliance| Saving {
+                appliance: appliance.name.clone(),
+                ..Saving::default()
+            })
+            .collect(),
+        ..Simulation::default()
+    };
+
+    let mut date = from;
+    while date <= to {
+        let prices = match store.read_prices(date, tz, &config.source, area).await {
+            Ok(prices) => prices,
+            Err(RefinerError::MissingData(_)) => {
+                simulation.skipped.push(date);
+                date = date.succ();
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        let mut paid = match &config.costs {
+            Some(costs) => with_costs(&prices, costs),
+            None => prices.clone(),
+        };
+        if let Some(tariff) = &config.tariff {
+            paid = with_tariff(&paid, tariff, config.calendar.as_ref());
+        }
+
+        let profile = match &config.consumption {
+            Some(consumption) => store.read_consumption(date, tz, consumption, area).await?,
+            None => Vec::new(),
+        };
+        let profile_price = weighted_price(&paid, &profile)?;
+        match (profile.is_empty(), kwh_per_day) {
+            (false, _) => {
+                simulation.kwh += profile.iter().map(|point| point.value).sum::<f64>();
+                simulation.cost += profile
+                    .iter()
+                    .filter_map(|point| price_at(&paid, point).map(|price| point.value * price))
+                    .sum::<f64>();
+            }
+            (true, Some(kwh)) => {
+                simulation.kwh += kwh;
+                simulation.cost += kwh * profile_price;
+            }
+            (true, None) => {}
+        }
+
+        for (saving, appliance) in simulation.savings.iter_mut().zip(&config.appliances) {
+            let schedule = optimizer::schedule(&paid, appliance);
+            let scheduled: Vec<PricePoint> =
+                schedule.indices.iter().map(|index| paid[*index]).collect();
+            if scheduled.is_empty() {
+                continue;
+            }
+            let kwh = appliance.power_kw * appliance.hours as f64;
+            saving.kwh += kwh;
+            saving.baseline += kwh * profile_price;
+            saving.shifted += kwh * average(&scheduled)?;
+        }
+
+        simulation.days += 1;
+        date = date.succ();
+    }
+    Ok(simulation)
+}
@@ -0,0 +1,48 @@
+use std::{
+    fs::OpenOptions,
+    io::{self, Write},
+    sync::Mutex,
+};
+
+use once_cell::sync::Lazy;
+
+use super::{config::JsonSinkConfig, error::RefinerError, refiner::Refined};
+
+/// Path refined rows are written to standard output with
+pub const STDOUT: &str = "-";
+
+/// Keeps the rows of areas refined at once from interleaving in the file
+static FILE_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+/// Renders rows as newline delimited JSON, one object per row with the fields they are
+/// serialized with everywhere else
+pub fn ndjson(rows: &[Refined]) -> Result<String, RefinerError> {
+    let mut lines = String::new();
+    for row in rows {
+        let line = serde_json::to_string(row).map_err(|e| RefinerError::Write(e.to_string()))?;
+        lines.push_str(&line);
+        lines.push('\n');
+    }
+    Ok(lines)
+}
+
+/// Writes `rows` to standard output, or appends them to the file of the sink
+pub fn emit(sink: &JsonSinkConfig, rows: &[Refined]) -> Result<(), RefinerError> {
+    let lines = ndjson(rows)?;
+    let written = if sink.path == STDOUT {
+        let mut stdout = io::stdout().lock();
+        stdout
+            .write_all(lines.as_bytes())
+            .and_then(|_| stdout.flush())
+    } else {
+        let _lock = FILE_LOCK
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&sink.path)
+            .and_then(|mut file| file.write_all(lines.as_bytes()))
+    };
+    written.map_err(|e| RefinerError::Write(format!("{}: {}", sink.path, e)))
+}
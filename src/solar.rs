@@ -0,0 +1,69 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use chrono::TimeZone;
+use chrono_tz::Tz;
+use serde::Deserialize;
+
+use super::{config::SolarConfig, refiner::PricePoint};
+
+const FETCH_TIMEOUT_SECS: u64 = 10;
+
+#[derive(Deserialize)]
+struct Estimate {
+    /// Watt hours produced in the period up to each timestamp, in seconds since the epoch
+    result: BTreeMap<String, f64>,
+}
+
+/// Energy produced in kWh by the start of the hour it is produced in, for today and tomorrow
+async fn fetch(solar: &SolarConfig, tz: Tz) -> Result<Vec<PricePoint>, String> {
+    let http = reqwest::Client::builder()
+        .timeout(Duration::from_secs(FETCH_TIMEOUT_SECS))
+        .build()
+        .map_err(|e| e.to_string())?;
+    let base = match &solar.api_key {
+        Some(api_key) => format!("{}/{}", solar.url.trim_end_matches('/'), api_key),
+        None => solar.url.trim_end_matches('/').to_string(),
+    };
+    let url = format!(
+        "{}/estimate/watthours/period/{}/{}/{}/{}/{}",
+        base, solar.latitude, solar.longitude, solar.declination, solar.azimuth, solar.kwp
+    );
+    let estimate: Estimate = http
+        .get(&url)
+        .query(&[("time", "seconds")])
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| e.to_string())?
+        .json()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    // Periods around sunrise and sunset are shorter than an hour, they are summed by the hour
+    let mut hours: BTreeMap<i64, f64> = BTreeMap::new();
+    for (end, watt_hours) in estimate.result {
+        let end: i64 = end
+            .parse()
+            .map_err(|_| format!("Invalid timestamp {} in the estimate", end))?;
+        let start = (end - 1).div_euclid(3600) * 3600;
+        *hours.entry(start).or_default() += watt_hours / 1000.0;
+    }
+    Ok(hours
+        .into_iter()
+        .map(|(start, kwh)| PricePoint {
+            start: tz.timestamp(start, 0),
+            value: kwh,
+        })
+        .collect())
+}
+
+/// Production forecast for today and tomorrow in kWh per hour, empty if it can't be fetched
+pub async fn production(solar: &SolarConfig, tz: Tz) -> Vec<PricePoint> {
+    match fetch(solar, tz).await {
+        Ok(production) => production,
+        Err(e) => {
+            tracing::warn!("Failed to fetch the solar production forecast: {}", e);
+            Vec::new()
+        }
+    }
+}
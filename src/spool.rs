@@ -0,0 +1,79 @@
+use std::{fs, io, path::PathBuf};
+
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+
+/// A day that failed to refine on every retry, kept on disk until it is refined. Only the date is
+/// kept, the rows are computed again from the stored prices when it is replayed
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Failed {
+    pub date: NaiveDate,
+    /// The pass that gave up, like `daily`
+    pub pass: String,
+    pub error: String,
+    pub failed_at: DateTime<Utc>,
+}
+
+/// A directory of failed days, one JSON file per date so a day failing again replaces itself
+#[derive(Clone, Debug)]
+pub struct Spool {
+    dir: PathBuf,
+}
+
+impl Spool {
+    pub fn new(dir: impl Into<PathBuf>) -> Spool {
+        Spool { dir: dir.into() }
+    }
+
+    fn path(&self, date: NaiveDate) -> PathBuf {
+        self.dir.join(format!("{}.json", date))
+    }
+
+    /// Keeps `failed` until it is removed, creating the directory if missing
+    pub fn push(&self, failed: &Failed) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let json = serde_json::to_string_pretty(failed)?;
+        fs::write(self.path(failed.date), json)
+    }
+
+    /// The failed days in date order. Files that can't be read are logged and left alone
+    pub fn pending(&self) -> Vec<Failed> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Vec::new(),
+            Err(e) => {
+                tracing::warn!("Failed to read spool {}: {}", self.dir.display(), e);
+                return Vec::new();
+            }
+        };
+        let mut pending: Vec<Failed> = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| {
+                path.extension()
+                    .map_or(false, |extension| extension == "json")
+            })
+            .filter_map(|path| {
+                let read = fs::read_to_string(&path)
+                    .map_err(|e| e.to_string())
+                    .and_then(|json| serde_json::from_str(&json).map_err(|e| e.to_string()));
+                match read {
+                    Ok(failed) => Some(failed),
+                    Err(e) => {
+                        tracing::warn!("Skipping spooled {}: {}", path.display(), e);
+                        None
+                    }
+                }
+            })
+            .collect();
+        pending.sort_by_key(|failed: &Failed| failed.date);
+        pending
+    }
+
+    /// Forgets `date` once it is refined
+    pub fn remove(&self, date: NaiveDate) -> io::Result<()> {
+        match fs::remove_file(self.path(date)) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            removed => removed,
+        }
+    }
+}
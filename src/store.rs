@@ -0,0 +1,367 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+use chrono::NaiveDate;
+use chrono_tz::Tz;
+#[cfg(feature = "influx")]
+use influxdb::{Query, WriteQuery};
+
+use super::{
+    config::{Area, ConsumptionConfig, SourceConfig},
+    error::RefinerError,
+    refiner::{start_of_day, PricePoint, Refined},
+};
+#[cfg(feature = "influx")]
+use super::{db::Db, refiner};
+
+/// Where a tick reads prices from and writes what it refines to. InfluxDB in production, kept
+/// in memory by `MemoryStore` so a tick runs without a database. Outputs other than the refined
+/// rows are Influx points, so without the `influx` feature only the rows are stored
+#[async_trait]
+pub trait PriceStore: Send + Sync {
+    /// The prices of `date` in time order, missing data if none are stored
+    async fn read_prices(
+        &self,
+        date: NaiveDate,
+        tz: Tz,
+        source: &SourceConfig,
+        area: Option<&Area>,
+    ) -> Result<Vec<PricePoint>, RefinerError>;
+
+    /// The prices of the `days` days before `date` in time order, empty if none are stored
+    async fn read_prices_before(
+        &self,
+        date: NaiveDate,
+        days: u32,
+        tz: Tz,
+        source: &SourceConfig,
+        area: Option<&Area>,
+    ) -> Result<Vec<PricePoint>, RefinerError>;
+
+    /// The energy used in `date` in time order, empty if none is stored
+    async fn read_consumption(
+        &self,
+        date: NaiveDate,
+        tz: Tz,
+        consumption: &ConsumptionConfig,
+        area: Option<&Area>,
+    ) -> Result<Vec<PricePoint>, RefinerError>;
+
+    /// Number of rows already written for `date`, of a forecast or of its published prices
+    async fn count_refined(
+        &self,
+        date: NaiveDate,
+        measurement: &str,
+        area: Option<&Area>,
+        forecast: bool,
+    ) -> Result<u64, RefinerError>;
+
+    /// Writes the refined rows of `date` and checks that they were stored
+    async fn write_refined(
+        &self,
+        date: NaiveDate,
+        rows: &[Refined],
+        measurement: &str,
+        area: Option<&Area>,
+    ) -> Result<(), RefinerError>;
+
+    /// Deletes the forecast rows of `date`
+    async fn delete_forecast(
+        &self,
+        date: NaiveDate,
+        measurement: &str,
+        area: Option<&Area>,
+    ) -> Result<(), RefinerError>;
+
+    /// Writes points of the other outputs, like summaries, charging plans and schedules
+    #[cfg(feature = "influx")]
+    async fn write(&self, queries: Vec<WriteQuery>) -> Result<(), RefinerError>;
+
+    /// Whether a read fell back to older data than the source has, like cached prices
+    fn stale(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(feature = "influx")]
+#[async_trait]
+impl PriceStore for Db {
+    async fn read_prices(
+        &self,
+        date: NaiveDate,
+        tz: Tz,
+        source: &SourceConfig,
+        area: Option<&Area>,
+    ) -> Result<Vec<PricePoint>, RefinerError> {
+        refiner::get_prices(date, self, tz, source, area).await
+    }
+
+    async fn read_prices_before(
+        &self,
+        date: NaiveDate,
+        days: u32,
+        tz: Tz,
+        source: &SourceConfig,
+        area: Option<&Area>,
+    ) -> Result<Vec<PricePoint>, RefinerError> {
+        refiner::get_prices_before(date, days, self, tz, source, area).await
+    }
+
+    async fn read_consumption(
+        &self,
+        date: NaiveDate,
+        tz: Tz,
+        consumption: &ConsumptionConfig,
+        area: Option<&Area>,
+    ) -> Result<Vec<PricePoint>, RefinerError> {
+        refiner::get_consumption(date, self, tz, consumption, area).await
+    }
+
+    async fn count_refined(
+        &self,
+        date: NaiveDate,
+        measurement: &str,
+        area: Option<&Area>,
+        forecast: bool,
+    ) -> Result<u64, RefinerError> {
+        refiner::count_refined(date, self, measurement, area, forecast).await
+    }
+
+    async fn write_refined(
+        &self,
+        date: NaiveDate,
+        rows: &[Refined],
+        measurement: &str,
+        area: Option<&Area>,
+    ) -> Result<(), RefinerError> {
+        refiner::write_refined(rows, self, measurement).await?;
+        refiner::verify_refined(date, rows, self, measurement, area).await
+    }
+
+    async fn delete_forecast(
+        &self,
+        date: NaiveDate,
+        measurement: &str,
+        area: Option<&Area>,
+    ) -> Result<(), RefinerError> {
+        refiner::delete_forecast(date, self, measurement, area).await
+    }
+
+    async fn write(&self, queries: Vec<WriteQuery>) -> Result<(), RefinerError> {
+        Db::write(self, queries).await?;
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct Stored {
+    /// Prices by area name, none for the single untagged area
+    prices: BTreeMap<Option<String>, Vec<PricePoint>>,
+    consumption: BTreeMap<Option<String>, Vec<PricePoint>>,
+    /// Refined rows by measurement
+    refined: BTreeMap<String, Vec<Refined>>,
+    /// Line protocol of everything else written
+    lines: Vec<String>,
+}
+
+/// Keeps prices and what is refined from them in memory, for driving ticks in tests and
+/// tools without InfluxDB. Clones share the same contents
+#[derive(Clone, Default)]
+pub struct MemoryStore {
+    stored: Arc<Mutex<Stored>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+
+    fn stored(&self) -> Result<std::sync::MutexGuard<'_, Stored>, RefinerError> {
+        self.stored
+            .lock()
+            .map_err(|e| RefinerError::Query(e.to_string()))
+    }
+
+    /// Adds prices of `area`, per kWh like the refiner reads them
+    pub fn insert_prices(&self, area: Option<&str>, prices: &[PricePoint]) {
+        if let Ok(mut stored) = self.stored() {
+            let stored = stored.prices.entry(area.map(str::to_string)).or_default();
+            stored.extend_from_slice(prices);
+            stored.sort_by_key(|price| price.start);
+        }
+    }
+
+    /// Adds the kWh used from the start of each point on
+    pub fn insert_consumption(&self, area: Option<&str>, consumption: &[PricePoint]) {
+        if let Ok(mut stored) = self.stored() {
+            let stored = stored
+                .consumption
+                .entry(area.map(str::to_string))
+                .or_default();
+            stored.extend_from_slice(consumption);
+            stored.sort_by_key(|usage| usage.start);
+        }
+    }
+
+    /// The rows written to `measurement` in time order
+    pub fn refined(&self, measurement: &str) -> Vec<Refined> {
+        self.stored()
+            .map(|stored| stored.refined.get(measurement).cloned().unwrap_or_default())
+            .unwrap_or_default()
+    }
+
+    /// Line protocol of every other point written, in the order they were
+    pub fn lines(&self) -> Vec<String> {
+        self.stored()
+            .map(|stored| stored.lines.clone())
+            .unwrap_or_default()
+    }
+
+    /// The points of `area` from local midnight of `first` until local midnight of `stop`
+    fn between(
+        &self,
+        series: fn(&Stored) -> &BTreeMap<Option<String>, Vec<PricePoint>>,
+        first: NaiveDate,
+        stop: NaiveDate,
+        tz: Tz,
+        area: Option<&Area>,
+    ) -> Result<Vec<PricePoint>, RefinerError> {
+        let (start, stop) = match (start_of_day(first, tz), start_of_day(stop, tz)) {
+            (Some(start), Some(stop)) => (start, stop),
+            _ => {
+                return Err(RefinerError::Timestamp(format!(
+                    "{} or {} has no local midnight",
+                    first, stop
+                )))
+            }
+        };
+        let stored = self.stored()?;
+        let name = area.map(|area| area.name.clone());
+        Ok(series(&stored)
+            .get(&name)
+            .map(|points| {
+                points
+                    .iter()
+                    .filter(|point| start <= point.start && point.start < stop)
+                    .map(|point| PricePoint {
+                        start: point.start.with_timezone(&tz),
+                        value: point.value,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+}
+
+/// Whether `row` is a row of `date` and `area`, of a forecast or of published prices
+fn matches(row: &Refined, date: NaiveDate, area: Option<&Area>, forecast: bool) -> bool {
+    row.date == date.to_string()
+        && row.area.as_deref() == area.map(|area| area.name.as_str())
+        && row.forecast == forecast
+}
+
+#[async_trait]
+impl PriceStore for MemoryStore {
+    async fn read_prices(
+        &self,
+        date: NaiveDate,
+        tz: Tz,
+        _source: &SourceConfig,
+        area: Option<&Area>,
+    ) -> Result<Vec<PricePoint>, RefinerError> {
+        let prices = self.between(|stored| &stored.prices, date, date.succ(), tz, area)?;
+        if prices.is_empty() {
+            return Err(RefinerError::MissingData(format!("No prices for {}", date)));
+        }
+        Ok(prices)
+    }
+
+    async fn read_prices_before(
+        &self,
+        date: NaiveDate,
+        days: u32,
+        tz: Tz,
+        _source: &SourceConfig,
+        area: Option<&Area>,
+    ) -> Result<Vec<PricePoint>, RefinerError> {
+        let first = date - chrono::Duration::days(days.into());
+        self.between(|stored| &stored.prices, first, date, tz, area)
+    }
+
+    async fn read_consumption(
+        &self,
+        date: NaiveDate,
+        tz: Tz,
+        _consumption: &ConsumptionConfig,
+        area: Option<&Area>,
+    ) -> Result<Vec<PricePoint>, RefinerError> {
+        self.between(|stored| &stored.consumption, date, date.succ(), tz, area)
+    }
+
+    async fn count_refined(
+        &self,
+        date: NaiveDate,
+        measurement: &str,
+        area: Option<&Area>,
+        forecast: bool,
+    ) -> Result<u64, RefinerError> {
+        let stored = self.stored()?;
+        let rows = stored
+            .refined
+            .get(measurement)
+            .map_or(&[][..], Vec::as_slice);
+        let count = rows
+            .iter()
+            .filter(|row| matches(row, date, area, forecast))
+            .count();
+        Ok(count as u64)
+    }
+
+    async fn write_refined(
+        &self,
+        _date: NaiveDate,
+        rows: &[Refined],
+        measurement: &str,
+        _area: Option<&Area>,
+    ) -> Result<(), RefinerError> {
+        let mut stored = self.stored()?;
+        let written = stored.refined.entry(measurement.to_string()).or_default();
+        // A row replaces the row of the same time and tags, like a point in InfluxDB
+        written.retain(|old| {
+            !rows.iter().any(|row| {
+                row.time == old.time && row.area == old.area && row.forecast == old.forecast
+            })
+        });
+        written.extend_from_slice(rows);
+        written.sort_by_key(|row| row.time);
+        Ok(())
+    }
+
+    async fn delete_forecast(
+        &self,
+        date: NaiveDate,
+        measurement: &str,
+        area: Option<&Area>,
+    ) -> Result<(), RefinerError> {
+        let mut stored = self.stored()?;
+        if let Some(rows) = stored.refined.get_mut(measurement) {
+            rows.retain(|row| !matches(row, date, area, true));
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "influx")]
+    async fn write(&self, queries: Vec<WriteQuery>) -> Result<(), RefinerError> {
+        let lines = queries
+            .build()
+            .map_err(|e| RefinerError::Write(e.to_string()))?
+            .get();
+        self.stored()?
+            .lines
+            .extend(lines.lines().map(str::to_string));
+        Ok(())
+    }
+}
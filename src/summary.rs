@@ -0,0 +1,143 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, NaiveDate, Timelike};
+use chrono_tz::Tz;
+#[cfg(feature = "influx")]
+use influxdb::{InfluxDbWriteable, Query, Timestamp, WriteQuery};
+#[cfg(feature = "influx")]
+use tracing::instrument;
+
+#[cfg(feature = "influx")]
+use super::store::PriceStore;
+use super::{
+    config::RefinerConfig,
+    error::RefinerError,
+    refiner::{cheapest_window, points_per_hour, start_of_day, PriceDay, Refined},
+};
+
+/// One point per day and area, so long term dashboards don't have to group the hourly rows
+#[derive(Clone, Debug)]
+pub struct Summary {
+    /// Local midnight at the start of the day
+    pub time: DateTime<Tz>,
+    pub date: NaiveDate,
+    pub currency: String,
+    /// Name of the configured area, unset with a single area
+    pub area: Option<String>,
+    pub pris_snitt: f64,
+    pub pris_median: f64,
+    pub pris_stddev: f64,
+    pub pris_min_verdi: f64,
+    pub pris_max_verdi: f64,
+    /// The most expensive price less the cheapest
+    pub pris_spredning: f64,
+    /// Hours with a price below zero
+    pub negativ_pris_timer: f64,
+    /// Hour on the clock the cheapest run of each configured length starts, by field name
+    pub window_starts: BTreeMap<String, u32>,
+    /// Hours spent within each configured band, by band name with a `_timer` suffix
+    pub band_hours: BTreeMap<String, f64>,
+    pub data_complete: bool,
+    pub missing_hours: u32,
+}
+
+impl Summary {
+    /// `date`, `currency` and `area` are tags, everything else is a field
+    #[cfg(feature = "influx")]
+    pub fn to_query(&self, measurement: &str) -> WriteQuery {
+        let mut query = Timestamp::from(self.time)
+            .into_query(measurement)
+            .add_tag("date", self.date.to_string())
+            .add_tag("currency", self.currency.clone())
+            .add_field("pris_snitt", self.pris_snitt)
+            .add_field("pris_median", self.pris_median)
+            .add_field("pris_stddev", self.pris_stddev)
+            .add_field("pris_min_verdi", self.pris_min_verdi)
+            .add_field("pris_max_verdi", self.pris_max_verdi)
+            .add_field("pris_spredning", self.pris_spredning)
+            .add_field("negativ_pris_timer", self.negativ_pris_timer)
+            .add_field("data_complete", self.data_complete)
+            .add_field("missing_hours", self.missing_hours);
+        if let Some(area) = &self.area {
+            query = query.add_tag("area", area.clone());
+        }
+        for (name, start) in &self.window_starts {
+            query = query.add_field(name.as_str(), *start);
+        }
+        for (name, hours) in &self.band_hours {
+            query = query.add_field(name.as_str(), *hours);
+        }
+        query
+    }
+}
+
+/// Summarizes the day's prices and its refined rows, which carry the area and band flags
+pub fn summarize(
+    date: NaiveDate,
+    tz: Tz,
+    day: &PriceDay,
+    rows: &[Refined],
+    config: &RefinerConfig,
+) -> Result<Summary, RefinerError> {
+    let prices = day.prices();
+    let time = start_of_day(date, tz)
+        .ok_or_else(|| RefinerError::Timestamp(format!("{} has no local midnight", date)))?;
+
+    let mut window_starts = BTreeMap::new();
+    for hours in &config.windows {
+        if let Some((start, _)) = cheapest_window(prices, *hours) {
+            window_starts.insert(
+                format!("cheapest_{}h_start", hours),
+                prices[start].start.hour(),
+            );
+        }
+    }
+
+    let per_hour = points_per_hour(prices) as f64;
+    let mut band_hours = BTreeMap::new();
+    for band in &config.bands {
+        let within = rows
+            .iter()
+            .filter(|row| row.bands.get(&band.name) == Some(&true))
+            .count();
+        band_hours.insert(format!("{}_timer", band.name), within as f64 / per_hour);
+    }
+
+    let negative = prices.iter().filter(|price| price.value < 0.0).count();
+
+    let first = rows.first();
+    Ok(Summary {
+        time,
+        date,
+        currency: config.source.currency.clone(),
+        area: first.and_then(|row| row.area.clone()),
+        pris_snitt: day.average()?,
+        pris_median: day.median()?,
+        pris_stddev: day.stddev()?,
+        pris_min_verdi: day.min_value()?,
+        pris_max_verdi: day.max_value()?,
+        pris_spredning: day.spread()?,
+        negativ_pris_timer: negative as f64 / per_hour,
+        window_starts,
+        band_hours,
+        data_complete: first.map_or(true, |row| row.data_complete),
+        missing_hours: first.map_or(0, |row| row.missing_hours),
+    })
+}
+
+/// Renders the summary as Influx line protocol
+#[cfg(feature = "influx")]
+pub fn line_protocol(summary: &Summary) -> Result<String, RefinerError> {
+    Ok(summary
+        .to_query("refined_daily")
+        .build()
+        .map_err(|e| RefinerError::Write(e.to_string()))?
+        .get())
+}
+
+/// Writes the summary to `refined_daily`, a later summary of the same day and area replaces it
+#[cfg(feature = "influx")]
+#[instrument(skip_all, fields(date = %summary.date))]
+pub async fn write_summary(summary: &Summary, store: &dyn PriceStore) -> Result<(), RefinerError> {
+    store.write(vec![summary.to_query("refined_daily")]).await
+}
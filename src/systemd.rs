@@ -0,0 +1,46 @@
+use std::time::Duration;
+
+use sd_notify::NotifyState;
+use tokio::time;
+
+/// Sends `states` to systemd, does nothing unless it started the refiner with a notify socket
+fn notify(states: &[NotifyState]) {
+    if let Err(e) = sd_notify::notify(false, states) {
+        tracing::debug!("Failed to notify systemd: {}", e);
+    }
+}
+
+/// Tells systemd setup is done, for units of `Type=notify`
+pub fn ready() {
+    notify(&[NotifyState::Ready]);
+}
+
+/// Tells systemd the config is being reloaded, until `ready` is sent again
+pub fn reloading() {
+    notify(&[NotifyState::Reloading]);
+}
+
+pub fn stopping() {
+    notify(&[NotifyState::Stopping]);
+}
+
+/// Shows `status` in `systemctl status`
+pub fn status(status: &str) {
+    notify(&[NotifyState::Status(status)]);
+}
+
+/// Pings systemd's watchdog at half the unit's `WatchdogSec=`, so a refiner whose runtime hangs is
+/// restarted. Returns at once when the watchdog is off
+pub async fn watchdog() {
+    let mut usec = 0;
+    if !sd_notify::watchdog_enabled(false, &mut usec) || usec == 0 {
+        return;
+    }
+    let period = Duration::from_micros(usec) / 2;
+    tracing::info!("Pinging the systemd watchdog every {:?}", period);
+    let mut interval = time::interval(period);
+    loop {
+        interval.tick().await;
+        notify(&[NotifyState::Watchdog]);
+    }
+}
@@ -0,0 +1,148 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use super::refiner::Refined;
+
+const DEFAULT_COLD_BELOW: f64 = 0.0;
+const DEFAULT_EXPENSIVE_RATIO: f64 = 1.2;
+const DEFAULT_URL: &str = "https://api.met.no/weatherapi/locationforecast/2.0/compact";
+/// MET Norway turns away requests that don't identify the application
+const DEFAULT_USER_AGENT: &str = concat!(
+    "tibber_refiner/",
+    env!("CARGO_PKG_VERSION"),
+    " github.com/CasaMack/tibber_refiner"
+);
+const FETCH_TIMEOUT_SECS: u64 = 10;
+/// Forecast steps are an hour apart the first days, longer steps after are not used
+const STEP_SECS: i64 = 3600;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WeatherSettings {
+    pub latitude: f64,
+    pub longitude: f64,
+    /// Meters above sea level, looked up by MET Norway if unset
+    pub altitude: Option<i32>,
+    /// Location forecast endpoint of the MET Norway API
+    pub url: String,
+    /// Identifies the refiner to MET Norway, which asks for a way to contact its user
+    pub user_agent: String,
+    /// Temperature in °C below which an hour is cold
+    pub cold_below: f64,
+    /// Price relative to the day's average above which an hour is expensive
+    pub expensive_ratio: f64,
+}
+
+impl Default for WeatherSettings {
+    fn default() -> Self {
+        WeatherSettings {
+            latitude: 0.0,
+            longitude: 0.0,
+            altitude: None,
+            url: DEFAULT_URL.to_string(),
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            cold_below: DEFAULT_COLD_BELOW,
+            expensive_ratio: DEFAULT_EXPENSIVE_RATIO,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Forecast {
+    properties: Properties,
+}
+
+#[derive(Deserialize)]
+struct Properties {
+    timeseries: Vec<Step>,
+}
+
+#[derive(Deserialize)]
+struct Step {
+    time: DateTime<Utc>,
+    data: StepData,
+}
+
+#[derive(Deserialize)]
+struct StepData {
+    instant: Instant,
+}
+
+#[derive(Deserialize)]
+struct Instant {
+    details: Details,
+}
+
+#[derive(Deserialize)]
+struct Details {
+    air_temperature: Option<f64>,
+}
+
+/// The forecast air temperature at the location, by the time it is forecast for
+async fn fetch(settings: &WeatherSettings) -> Result<BTreeMap<DateTime<Utc>, f64>, String> {
+    let http = reqwest::Client::builder()
+        .timeout(Duration::from_secs(FETCH_TIMEOUT_SECS))
+        .user_agent(settings.user_agent.as_str())
+        .build()
+        .map_err(|e| e.to_string())?;
+    let mut query = vec![
+        ("lat", format!("{:.4}", settings.latitude)),
+        ("lon", format!("{:.4}", settings.longitude)),
+    ];
+    if let Some(altitude) = settings.altitude {
+        query.push(("altitude", altitude.to_string()));
+    }
+    let forecast: Forecast = http
+        .get(&settings.url)
+        .query(&query)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| e.to_string())?
+        .json()
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(forecast
+        .properties
+        .timeseries
+        .into_iter()
+        .filter_map(|step| Some((step.time, step.data.instant.details.air_temperature?)))
+        .collect())
+}
+
+/// Sets `temp_ute` and `kaldt_og_dyrt` on the rows MET Norway has an hourly forecast for, which
+/// are those from about now through the next two days. Left unset if the forecast can't be fetched
+pub async fn enrich(settings: &WeatherSettings, rows: &mut [Refined]) {
+    let now = Utc::now().timestamp();
+    if rows
+        .iter()
+        .all(|row| row.time.timestamp() + STEP_SECS <= now)
+    {
+        // Past days have no forecast
+        return;
+    }
+    let temperatures = match fetch(settings).await {
+        Ok(temperatures) => temperatures,
+        Err(e) => {
+            tracing::warn!("Failed to fetch the weather forecast: {}", e);
+            return;
+        }
+    };
+    for row in rows.iter_mut() {
+        let time = row.time.timestamp();
+        // The step of the hour a quarter hour price is in
+        let temperature = temperatures
+            .range(..=row.time.with_timezone(&Utc))
+            .next_back()
+            .filter(|(step, _)| time - step.timestamp() < STEP_SECS);
+        if let Some((_, temperature)) = temperature {
+            row.temp_ute = Some(*temperature);
+            row.kaldt_og_dyrt = Some(
+                *temperature < settings.cold_below
+                    && row.pris_forhold_24 > settings.expensive_ratio,
+            );
+        }
+    }
+}